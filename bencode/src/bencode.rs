@@ -22,6 +22,7 @@ impl BenObject {
 			BenObject::List(ref list) => wlen += Self::write_list(w, list)?,
 			BenObject::Dict(ref dict) => wlen += Self::write_dict(w, dict)?,
 			BenObject::Bytes(ref bytes) => wlen += Self::write_bytes(w, bytes)?,
+			BenObject::BytesDict(ref dict) => wlen += Self::write_bytes_dict(w, dict)?,
 		}
 
 		Ok(wlen)
@@ -78,6 +79,23 @@ impl BenObject {
 		Ok(wlen)
 	}
 
+	// BTreeMap 本身就是按 key 的字节序排好的
+	fn write_bytes_dict<W>(w: &mut W, dict: &BytesDict) -> Result<usize, BencodeError>
+	where
+		W: Write,
+	{
+		let mut wlen = 0;
+		w.write_all(&[DICT_PREFIX])?;
+		wlen += 1;
+		for (key, val) in dict {
+			wlen += Self::write_bytes(w, key)?;
+			wlen += val.write_into(w)?;
+		}
+		w.write_all(&[DICT_POSTFIX])?;
+		wlen += 1;
+		Ok(wlen)
+	}
+
 	// 字节串的格式为 字节串长度:内容，其中 字节串长度 是 ASCII 编码格式的整数字符串，单位为字节
 	// 4:abcd 表示4个字节的串 "abcd"
 	// 0:     表示0个字节的串 ""
@@ -155,12 +173,9 @@ impl BenObject {
 			val *= -1;
 		}
 
+		// 先除再比较, 接近 i64::MAX 时 dividend 不会溢出
 		let mut dividend = 1;
-		loop {
-			if dividend > val {
-				dividend /= 10;
-				break;
-			}
+		while dividend <= val / 10 {
 			dividend *= 10;
 		}
 
@@ -183,7 +198,7 @@ mod tests {
 
 	#[test]
 	fn test_bencode_int() {
-		let cases = [(999, 5, "i999e"), (0, 3, "i0e"), (-99, 5, "i-99e")];
+		let cases = [(999, 5, "i999e"), (0, 3, "i0e"), (-99, 5, "i-99e"), (1000, 6, "i1000e"), (i64::MAX, 21, "i9223372036854775807e")];
 		for cc in cases {
 			let vec = BenObject::Int(cc.0).bencode().unwrap();
 			assert_eq!(vec.len(), cc.1);
@@ -192,6 +207,7 @@ mod tests {
 	}
 
 	#[test]
+	#[allow(clippy::almost_complete_range)]
 	fn test_bencode_string() {
 		struct Case {
 			source: String,
@@ -1,5 +1,5 @@
 use itertools::Itertools;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::From;
 use std::fmt;
 
@@ -21,6 +21,8 @@ const ZERO: u8 = b'0';
 const MINUS: u8 = b'-';
 
 pub type Dict = HashMap<String, BenObject>;
+/// A dict with keys that are not valid UTF8, such as the `files` dict of a scrape response keyed by info hash.
+pub type BytesDict = BTreeMap<Vec<u8>, BenObject>;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum BenObject {
@@ -29,6 +31,22 @@ pub enum BenObject {
     List(Vec<BenObject>),
     Dict(Dict),
     Bytes(Vec<u8>),
+    BytesDict(BytesDict),
+}
+
+impl BenObject {
+    /// Returns the dict with its keys as bytes, whichever way it was parsed.
+    pub fn into_bytes_dict(self) -> Option<BytesDict> {
+        match self {
+            BenObject::Dict(dict) => Some(
+                dict.into_iter()
+                    .map(|(key, val)| (key.into_bytes(), val))
+                    .collect(),
+            ),
+            BenObject::BytesDict(dict) => Some(dict),
+            _ => None,
+        }
+    }
 }
 
 impl From<u8> for BenObject {
@@ -116,6 +134,15 @@ impl fmt::Display for BenObject {
                         v
                     )))
             ),
+            BenObject::BytesDict(ref dict) => write!(
+                f,
+                "{{ {} }}",
+                dict.iter().format_with(", ", |(k, v), f| f(&format_args!(
+                    r#"([{:#02x}], {})"#,
+                    k.iter().format(", "),
+                    v
+                )))
+            ),
         }
     }
 }
@@ -3,6 +3,7 @@ use shared::bytes::ByteBuffer;
 use super::*;
 
 impl BenObject {
+	#[allow(clippy::needless_question_mark)]
 	pub fn from_bytes<T>(bytes: T) -> Result<BenObject, BencodeError>
 	where
 		T: AsRef<[u8]>,
@@ -16,6 +17,8 @@ impl BenObject {
 				r.advance(1);
 				// TODO: dict 必须是有序的？？？
 				let mut dict = HashMap::new();
+				// 出现不是 UTF8 的 key 时(比如 scrape 响应里以 info hash 为 key), 改用 BytesDict
+				let mut bytes_dict: Option<BytesDict> = None;
 				loop {
 					let b = r.peek().ok_or(BencodeError::EOF)?;
 					if *b == DICT_POSTFIX {
@@ -23,14 +26,32 @@ impl BenObject {
 						break;
 					}
 					let key = match read_string(r)? {
-						BenObject::String(k) => k,
+						BenObject::String(k) => k.into_bytes(),
+						BenObject::Bytes(k) => k,
 						_ => return Err(BencodeError::ExpectStringError),
 					};
 
 					let val = Self::parse(r)?;
-					dict.insert(key, val);
+					match (String::from_utf8(key), bytes_dict.as_mut()) {
+						(Ok(key), None) => {
+							dict.insert(key, val);
+						}
+						(Ok(key), Some(bytes_dict)) => {
+							bytes_dict.insert(key.into_bytes(), val);
+						}
+						(Err(key), _) => {
+							bytes_dict
+								.get_or_insert_with(|| {
+									dict.drain().map(|(k, v)| (k.into_bytes(), v)).collect()
+								})
+								.insert(key.into_bytes(), val);
+						}
+					}
+				}
+				match bytes_dict {
+					Some(bytes_dict) => Ok(BenObject::BytesDict(bytes_dict)),
+					None => Ok(BenObject::Dict(dict)),
 				}
-				Ok(BenObject::Dict(dict))
 			}
 			LIST_PREFIX => {
 				r.advance(1);
@@ -121,6 +142,7 @@ fn read_decimal(r: &mut ByteBuffer) -> Result<(i64, i64), BencodeError> {
 
 // ANCHOR_END: decoder
 
+#[allow(clippy::manual_range_contains)]
 fn check_num(num: u8) -> bool {
 	num >= b'0' && num <= b'9'
 }
@@ -212,6 +234,22 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn test_parse_bytes_dict() {
+		let mut source = b"d3:agei29e2:".to_vec();
+		source.extend_from_slice(&[0xff, 0xfe]);
+		source.extend_from_slice(b"i1ee");
+		let obj = BenObject::from_bytes(&source).unwrap();
+		assert_eq!(
+			obj,
+			BenObject::BytesDict(BytesDict::from([
+				(b"age".to_vec(), BenObject::Int(29)),
+				(vec![0xff, 0xfe], BenObject::Int(1)),
+			]))
+		);
+		assert_eq!(obj.bencode().unwrap(), source);
+	}
+
 	#[test]
 	fn test_parse_complex_dict() {
 		let source = "d4:userd4:name3:ben3:agei29ee5:valueli80ei85ei90eee";
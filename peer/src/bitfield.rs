@@ -0,0 +1,265 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+use torrent::Info;
+
+use crate::error::PeerError;
+
+/// One bit per piece: which pieces a peer (or we) have.
+///
+/// Bits are stored as in the `bitfield` message, the first piece in the high bit
+/// of the first byte, and the spare bits of the last byte are always zero.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "RawBitfield", try_from = "RawBitfield")]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: usize,
+}
+
+// resume 文件里保存的格式
+#[derive(Serialize, Deserialize)]
+struct RawBitfield {
+    len: usize,
+    #[serde(with = "serde_bytes")]
+    bits: Vec<u8>,
+}
+
+impl Bitfield {
+    /// A bitfield of `len` pieces, none of which are set.
+    pub fn new(len: usize) -> Self {
+        Self {
+            bits: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// A bitfield of `len` pieces, all of which are set.
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self {
+            bits: vec![0xff; len.div_ceil(8)],
+            len,
+        };
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    /// An empty bitfield with one bit per piece of the torrent.
+    pub fn for_info(info: &Info) -> Self {
+        Self::new(info.piece_count())
+    }
+
+    /// Parses the payload of a `bitfield` message for a torrent of `len` pieces.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self, PeerError> {
+        if bytes.len() != len.div_ceil(8) {
+            return Err(PeerError::InvalidMessage(Cow::Owned(format!(
+                "bitfield of {} pieces must be {} bytes, got {}",
+                len,
+                len.div_ceil(8),
+                bytes.len()
+            ))));
+        }
+        let bitfield = Self {
+            bits: bytes.to_vec(),
+            len,
+        };
+        if bitfield.spare_bits() != 0 {
+            return Err(PeerError::InvalidMessage(Cow::Borrowed(
+                "bitfield has spare bits set.",
+            )));
+        }
+        Ok(bitfield)
+    }
+
+    /// The payload of the `bitfield` message.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the piece is set. Pieces out of range are not.
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bits[index / 8] & Self::mask(index) != 0
+    }
+
+    /// Sets the piece, panicking if it is out of range.
+    pub fn set(&mut self, index: usize) {
+        assert!(index < self.len, "piece {} out of range", index);
+        self.bits[index / 8] |= Self::mask(index);
+    }
+
+    /// Clears the piece, panicking if it is out of range.
+    pub fn clear(&mut self, index: usize) {
+        assert!(index < self.len, "piece {} out of range", index);
+        self.bits[index / 8] &= !Self::mask(index);
+    }
+
+    /// The number of pieces set.
+    pub fn count(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// The indices of the pieces set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&index| self.get(index))
+    }
+
+    /// The pieces set here but not in `other`. For a peer's bitfield and ours,
+    /// these are the pieces we could download from the peer.
+    ///
+    /// Panics if the bitfields are not of the same length.
+    pub fn difference(&self, other: &Bitfield) -> Bitfield {
+        assert_eq!(self.len, other.len, "bitfields of different lengths");
+        Self {
+            bits: self
+                .bits
+                .iter()
+                .zip(&other.bits)
+                .map(|(ours, theirs)| ours & !theirs)
+                .collect(),
+            len: self.len,
+        }
+    }
+
+    /// Whether any piece is set here but not in `other`, i.e. whether we are
+    /// interested in a peer with this bitfield when we have `other`.
+    ///
+    /// Panics if the bitfields are not of the same length.
+    pub fn has_any_missing_from(&self, other: &Bitfield) -> bool {
+        assert_eq!(self.len, other.len, "bitfields of different lengths");
+        self.bits
+            .iter()
+            .zip(&other.bits)
+            .any(|(ours, theirs)| ours & !theirs != 0)
+    }
+
+    fn mask(index: usize) -> u8 {
+        0x80 >> (index % 8)
+    }
+
+    // 最后一个字节中多出来的位
+    fn spare_bits(&self) -> u8 {
+        match (self.bits.last(), self.len % 8) {
+            (Some(last), used) if used != 0 => last & (0xff >> used),
+            _ => 0,
+        }
+    }
+
+    fn clear_spare_bits(&mut self) {
+        let spare = self.spare_bits();
+        if let Some(last) = self.bits.last_mut() {
+            *last &= !spare;
+        }
+    }
+}
+
+impl From<Bitfield> for RawBitfield {
+    fn from(bitfield: Bitfield) -> Self {
+        Self {
+            len: bitfield.len,
+            bits: bitfield.bits,
+        }
+    }
+}
+
+impl TryFrom<RawBitfield> for Bitfield {
+    type Error = PeerError;
+
+    fn try_from(raw: RawBitfield) -> Result<Self, Self::Error> {
+        Self::from_bytes(&raw.bits, raw.len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use torrent::TorrentFile;
+
+    use super::*;
+
+    #[test]
+    fn test_set_and_clear() {
+        let mut bitfield = Bitfield::new(10);
+        assert_eq!(bitfield.as_bytes(), &[0, 0]);
+        bitfield.set(0);
+        bitfield.set(9);
+        assert_eq!(bitfield.as_bytes(), &[0x80, 0x40]);
+        assert!(bitfield.get(0) && bitfield.get(9));
+        assert!(!bitfield.get(1) && !bitfield.get(10));
+        assert_eq!(bitfield.count(), 2);
+        assert_eq!(bitfield.iter().collect::<Vec<_>>(), vec![0, 9]);
+        bitfield.clear(0);
+        assert_eq!(bitfield.iter().collect::<Vec<_>>(), vec![9]);
+
+        let full = Bitfield::full(10);
+        assert_eq!(full.as_bytes(), &[0xff, 0xc0]);
+        assert!(full.is_full());
+        assert!(Bitfield::full(16).is_full());
+        assert!(Bitfield::new(0).is_full());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_set_out_of_range() {
+        Bitfield::new(10).set(10);
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let bitfield = Bitfield::from_bytes(&[0b1010_0000, 0b1000_0000], 9).unwrap();
+        assert_eq!(bitfield.iter().collect::<Vec<_>>(), vec![0, 2, 8]);
+        // 长度不对
+        assert!(Bitfield::from_bytes(&[0, 0, 0], 9).is_err());
+        assert!(Bitfield::from_bytes(&[0], 9).is_err());
+        // 多余的位不是 0
+        assert!(Bitfield::from_bytes(&[0, 0b0100_0000], 9).is_err());
+        assert!(Bitfield::from_bytes(&[0xff, 0xff], 16).is_ok());
+    }
+
+    #[test]
+    fn test_difference() {
+        let theirs = Bitfield::from_bytes(&[0b1110_0000], 3).unwrap();
+        let ours = Bitfield::from_bytes(&[0b0100_0000], 3).unwrap();
+        assert_eq!(
+            theirs.difference(&ours).iter().collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+        assert!(theirs.has_any_missing_from(&ours));
+        assert!(!ours.has_any_missing_from(&theirs));
+        assert!(!Bitfield::full(3).has_any_missing_from(&Bitfield::full(3)));
+    }
+
+    #[test]
+    fn test_for_info() {
+        let torrent = TorrentFile::parse(include_bytes!(
+            "../../tests/tests/files/debian-11.3.0-amd64-netinst.iso.torrent"
+        ))
+        .unwrap();
+        let bitfield = Bitfield::for_info(&torrent.info);
+        assert_eq!(bitfield.len(), 1512);
+        assert_eq!(bitfield.as_bytes().len(), 189);
+        assert_eq!(bitfield.count(), 0);
+    }
+
+    #[test]
+    fn test_serde() {
+        let mut bitfield = Bitfield::new(12);
+        bitfield.set(3);
+        let json = serde_json::to_string(&bitfield).unwrap();
+        assert_eq!(json, r#"{"len":12,"bits":[16,0]}"#);
+        assert_eq!(serde_json::from_str::<Bitfield>(&json).unwrap(), bitfield);
+        assert!(serde_json::from_str::<Bitfield>(r#"{"len":12,"bits":[16,1]}"#).is_err());
+    }
+}
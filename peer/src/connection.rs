@@ -0,0 +1,864 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+use tokio_util::codec::Framed;
+
+use crate::bitfield::Bitfield;
+use crate::error::PeerError;
+use crate::handshake::{self, Handshake};
+use crate::message::{Message, MessageCodec, DEFAULT_MAX_MESSAGE_LEN};
+
+/// Settings of a [`PeerConnection`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionConfig {
+    queue_depth: usize,
+    keep_alive: Duration,
+    idle_timeout: Duration,
+    handshake_timeout: Duration,
+    max_message_len: usize,
+    max_incoming_requests: usize,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            queue_depth: 10,
+            keep_alive: Duration::from_secs(60),
+            idle_timeout: Duration::from_secs(180),
+            handshake_timeout: Duration::from_secs(10),
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+            max_incoming_requests: 250,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    /// How many block requests may be outstanding at once, `10` by default.
+    pub fn with_queue_depth(mut self, queue_depth: usize) -> Self {
+        self.queue_depth = queue_depth;
+        self
+    }
+
+    /// A keep-alive is sent when nothing else was sent for this long, `60` seconds by default.
+    pub fn with_keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// The connection is closed when nothing was received for this long, `3` minutes by default.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// How long the peer may take to answer the handshake, `10` seconds by default.
+    pub fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    /// See [`MessageCodec::with_max_len`].
+    pub fn with_max_message_len(mut self, max_message_len: usize) -> Self {
+        self.max_message_len = max_message_len;
+        self
+    }
+
+    /// How many requests of the peer may wait for an answer at once, `250` by default.
+    /// Requests beyond that are dropped.
+    pub fn with_max_incoming_requests(mut self, max_incoming_requests: usize) -> Self {
+        self.max_incoming_requests = max_incoming_requests;
+        self
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth
+    }
+
+    pub fn keep_alive(&self) -> Duration {
+        self.keep_alive
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+
+    pub fn max_message_len(&self) -> usize {
+        self.max_message_len
+    }
+
+    pub fn max_incoming_requests(&self) -> usize {
+        self.max_incoming_requests
+    }
+}
+
+/// A block of a piece, as in `request` and `cancel` messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+/// What a [`PeerConnection`] reports to the coordinator of its torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// The handshakes were exchanged.
+    Connected(Handshake),
+    /// The pieces the peer has, sent right after the handshake.
+    Bitfield(Bitfield),
+    /// The peer has a new piece.
+    Have(u32),
+    /// The peer choked us. Its queued and outstanding requests will not be served
+    /// and are handed back to be requested elsewhere.
+    Choked(Vec<BlockRequest>),
+    Unchoked,
+    Interested,
+    NotInterested,
+    /// The peer wants a block, answer with [`PeerHandle::send_block`].
+    /// Only reported while we are not choking the peer and it has fewer than
+    /// [`ConnectionConfig::max_incoming_requests`] requests waiting.
+    Request(BlockRequest),
+    Cancel(BlockRequest),
+    /// A block we requested.
+    Block {
+        index: u32,
+        begin: u32,
+        data: Bytes,
+    },
+    /// The peer's DHT port.
+    Port(u16),
+    /// The connection has ended. The requests that were not served are handed back.
+    Closed(Vec<BlockRequest>),
+}
+
+/// The channel every connection of a torrent reports to, keyed by peer address.
+pub type EventSender = mpsc::UnboundedSender<(SocketAddr, PeerEvent)>;
+
+#[derive(Debug)]
+enum Command {
+    Request(BlockRequest),
+    Cancel(BlockRequest),
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Block { index: u32, begin: u32, data: Bytes },
+    Close,
+}
+
+/// Controls a running [`PeerConnection`].
+///
+/// Dropping the handle closes the connection as if [`PeerHandle::close`] was called.
+#[derive(Debug)]
+pub struct PeerHandle {
+    addr: SocketAddr,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl PeerHandle {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Queues a block to download. Requests are sent while the peer does not
+    /// choke us, no more than the queue depth at once. Sends `interested` if needed.
+    pub fn request(&self, block: BlockRequest) {
+        self.send(Command::Request(block));
+    }
+
+    /// Withdraws a queued or outstanding request.
+    pub fn cancel(&self, block: BlockRequest) {
+        self.send(Command::Cancel(block));
+    }
+
+    pub fn choke(&self) {
+        self.send(Command::Choke);
+    }
+
+    pub fn unchoke(&self) {
+        self.send(Command::Unchoke);
+    }
+
+    pub fn interested(&self) {
+        self.send(Command::Interested);
+    }
+
+    pub fn not_interested(&self) {
+        self.send(Command::NotInterested);
+    }
+
+    /// Tells the peer we have a new piece.
+    pub fn have(&self, index: u32) {
+        self.send(Command::Have(index));
+    }
+
+    /// Answers a [`PeerEvent::Request`]. Dropped if we have choked the peer since.
+    pub fn send_block(&self, index: u32, begin: u32, data: Bytes) {
+        self.send(Command::Block { index, begin, data });
+    }
+
+    pub fn close(&self) {
+        self.send(Command::Close);
+    }
+
+    fn send(&self, command: Command) {
+        // 连接已经关闭时忽略命令
+        let _ = self.commands.send(command);
+    }
+}
+
+/// One peer of a torrent, from the handshake until the connection closes.
+///
+/// Both sides start choked and not interested. The connection keeps track of
+/// the choke and interest flags and the peer's pieces, pipelines block requests
+/// and sends keep-alives; the decisions (what to request, whom to unchoke) are
+/// left to the coordinator listening to the [`PeerEvent`]s.
+pub struct PeerConnection<S> {
+    stream: S,
+    ours: Handshake,
+    state: State,
+    commands: mpsc::UnboundedReceiver<Command>,
+}
+
+// 除了连接本身以外的状态, 收到的消息和命令都在这里处理
+// 要发送的消息先放进 outgoing, 由 run 统一发送
+struct State {
+    addr: SocketAddr,
+    config: ConnectionConfig,
+    events: EventSender,
+    have: Bitfield,
+    peer_has: Bitfield,
+    am_choking: bool,
+    am_interested: bool,
+    peer_choking: bool,
+    peer_interested: bool,
+    pending: VecDeque<BlockRequest>,
+    in_flight: Vec<BlockRequest>,
+    incoming: Vec<BlockRequest>,
+    received_any: bool,
+    outgoing: Vec<Message>,
+}
+
+impl<S> PeerConnection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// `stream` is connected to the peer at `addr`, either way. `have` are the
+    /// pieces we have, sent after the handshake and sizing the peer's bitfield.
+    pub fn new(
+        addr: SocketAddr,
+        stream: S,
+        ours: Handshake,
+        have: Bitfield,
+        config: ConnectionConfig,
+        events: EventSender,
+    ) -> (Self, PeerHandle) {
+        let (commands_tx, commands) = mpsc::unbounded_channel();
+        let connection = Self {
+            stream,
+            ours,
+            state: State {
+                addr,
+                config,
+                events,
+                peer_has: Bitfield::new(have.len()),
+                have,
+                am_choking: true,
+                am_interested: false,
+                peer_choking: true,
+                peer_interested: false,
+                pending: VecDeque::new(),
+                in_flight: Vec::new(),
+                incoming: Vec::new(),
+                received_any: false,
+                outgoing: Vec::new(),
+            },
+            commands,
+        };
+        let handle = PeerHandle {
+            addr,
+            commands: commands_tx,
+        };
+        (connection, handle)
+    }
+
+    /// Runs until either side closes the connection, the peer times out or
+    /// breaks the protocol. [`PeerEvent::Closed`] is always the last event.
+    pub async fn run(self) -> Result<(), PeerError> {
+        let Self {
+            mut stream,
+            ours,
+            mut state,
+            mut commands,
+        } = self;
+        let result = state.run(&mut stream, &ours, &mut commands).await;
+        let unserved = state.in_flight.drain(..).chain(state.pending.drain(..));
+        let unserved = unserved.collect();
+        state.emit(PeerEvent::Closed(unserved));
+        result
+    }
+}
+
+impl State {
+    async fn run<S>(
+        &mut self,
+        stream: &mut S,
+        ours: &Handshake,
+        commands: &mut mpsc::UnboundedReceiver<Command>,
+    ) -> Result<(), PeerError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let theirs = time::timeout(
+            self.config.handshake_timeout,
+            handshake::handshake(stream, ours),
+        )
+        .await
+        .map_err(|_| PeerError::Timeout)??;
+        self.emit(PeerEvent::Connected(theirs));
+        // 什么都没有时可以不发 bitfield
+        if self.have.count() > 0 {
+            let bitfield = Bytes::copy_from_slice(self.have.as_bytes());
+            self.outgoing.push(Message::Bitfield(bitfield));
+        }
+
+        let codec = MessageCodec::new().with_max_len(self.config.max_message_len);
+        let mut framed = Framed::new(stream, codec);
+        let mut last_sent = Instant::now();
+        let mut last_received = Instant::now();
+        loop {
+            if !self.outgoing.is_empty() {
+                for msg in self.outgoing.drain(..) {
+                    framed.feed(msg).await?;
+                }
+                framed.flush().await?;
+                last_sent = Instant::now();
+            }
+            let keep_alive = last_sent + self.config.keep_alive;
+            let idle = last_received + self.config.idle_timeout;
+            tokio::select! {
+                msg = framed.next() => match msg {
+                    Some(msg) => {
+                        last_received = Instant::now();
+                        self.on_message(msg?)?;
+                    }
+                    None => return Ok(()),
+                },
+                command = commands.recv() => match command {
+                    Some(Command::Close) | None => return Ok(()),
+                    Some(command) => self.on_command(command),
+                },
+                _ = time::sleep_until(keep_alive.min(idle)) => {
+                    if Instant::now() >= idle {
+                        return Err(PeerError::Timeout);
+                    }
+                    self.outgoing.push(Message::KeepAlive);
+                }
+            }
+        }
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<(), PeerError> {
+        let first = !self.received_any;
+        if msg != Message::KeepAlive {
+            self.received_any = true;
+        }
+        match msg {
+            Message::KeepAlive => {}
+            Message::Choke => {
+                self.peer_choking = true;
+                // 被 choke 后对方会丢弃所有请求
+                let dropped = self.in_flight.drain(..).chain(self.pending.drain(..));
+                let dropped = dropped.collect();
+                self.emit(PeerEvent::Choked(dropped));
+            }
+            Message::Unchoke => {
+                self.peer_choking = false;
+                self.emit(PeerEvent::Unchoked);
+                self.fill_pipeline();
+            }
+            Message::Interested => {
+                self.peer_interested = true;
+                self.emit(PeerEvent::Interested);
+            }
+            Message::NotInterested => {
+                self.peer_interested = false;
+                self.emit(PeerEvent::NotInterested);
+            }
+            Message::Have(index) => {
+                if index as usize >= self.peer_has.len() {
+                    return Err(PeerError::InvalidMessage(Cow::Owned(format!(
+                        "have of piece {} out of range",
+                        index
+                    ))));
+                }
+                if !self.peer_has.get(index as usize) {
+                    self.peer_has.set(index as usize);
+                    self.emit(PeerEvent::Have(index));
+                }
+            }
+            Message::Bitfield(bytes) => {
+                if !first {
+                    return Err(PeerError::InvalidMessage(Cow::Borrowed(
+                        "`bitfield` is only allowed right after the handshake.",
+                    )));
+                }
+                self.peer_has = Bitfield::from_bytes(&bytes, self.have.len())?;
+                self.emit(PeerEvent::Bitfield(self.peer_has.clone()));
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                let block = BlockRequest {
+                    index,
+                    begin,
+                    length,
+                };
+                // choke 对方时忽略它的请求, 未回答的请求太多或重复时也丢弃
+                if !self.am_choking
+                    && self.incoming.len() < self.config.max_incoming_requests
+                    && !self.incoming.contains(&block)
+                {
+                    self.incoming.push(block);
+                    self.emit(PeerEvent::Request(block));
+                }
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let requested = self.in_flight.iter().position(|req| {
+                    req.index == index && req.begin == begin && req.length as usize == block.len()
+                });
+                // 没有请求过的 (比如已经取消的) 块直接丢弃
+                if let Some(pos) = requested {
+                    self.in_flight.swap_remove(pos);
+                    self.emit(PeerEvent::Block {
+                        index,
+                        begin,
+                        data: block,
+                    });
+                    self.fill_pipeline();
+                }
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                let block = BlockRequest {
+                    index,
+                    begin,
+                    length,
+                };
+                self.incoming.retain(|req| *req != block);
+                self.emit(PeerEvent::Cancel(block));
+            }
+            Message::Port(port) => self.emit(PeerEvent::Port(port)),
+        }
+        Ok(())
+    }
+
+    fn on_command(&mut self, command: Command) {
+        match command {
+            Command::Request(block) => {
+                self.pending.push_back(block);
+                if !self.am_interested {
+                    self.am_interested = true;
+                    self.outgoing.push(Message::Interested);
+                }
+                self.fill_pipeline();
+            }
+            Command::Cancel(block) => {
+                self.pending.retain(|req| *req != block);
+                if let Some(pos) = self.in_flight.iter().position(|req| *req == block) {
+                    self.in_flight.swap_remove(pos);
+                    self.outgoing.push(Message::Cancel {
+                        index: block.index,
+                        begin: block.begin,
+                        length: block.length,
+                    });
+                    self.fill_pipeline();
+                }
+            }
+            Command::Choke if !self.am_choking => {
+                self.am_choking = true;
+                // choke 之后不再回答之前的请求
+                self.incoming.clear();
+                self.outgoing.push(Message::Choke);
+            }
+            Command::Unchoke if self.am_choking => {
+                self.am_choking = false;
+                self.outgoing.push(Message::Unchoke);
+            }
+            Command::Interested if !self.am_interested => {
+                self.am_interested = true;
+                self.outgoing.push(Message::Interested);
+            }
+            Command::NotInterested if self.am_interested => {
+                self.am_interested = false;
+                self.outgoing.push(Message::NotInterested);
+            }
+            Command::Have(index) => {
+                if (index as usize) < self.have.len() {
+                    self.have.set(index as usize);
+                }
+                self.outgoing.push(Message::Have(index));
+            }
+            Command::Block { index, begin, data } if !self.am_choking => {
+                self.incoming.retain(|req| {
+                    req.index != index || req.begin != begin || req.length as usize != data.len()
+                });
+                self.outgoing.push(Message::Piece {
+                    index,
+                    begin,
+                    block: data,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    // 对方没有 choke 我们时, 把排队的请求发出去, 直到达到队列深度
+    fn fill_pipeline(&mut self) {
+        while !self.peer_choking && self.in_flight.len() < self.config.queue_depth {
+            let Some(block) = self.pending.pop_front() else {
+                break;
+            };
+            self.outgoing.push(Message::Request {
+                index: block.index,
+                begin: block.begin,
+                length: block.length,
+            });
+            self.in_flight.push(block);
+        }
+    }
+
+    fn emit(&self, event: PeerEvent) {
+        // 没有人接收事件时, 连接照常运行
+        let _ = self.events.send((self.addr, event));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+    use torrent::InfoHash;
+
+    use super::*;
+
+    type Remote = Framed<DuplexStream, MessageCodec>;
+    type Events = mpsc::UnboundedReceiver<(SocketAddr, PeerEvent)>;
+
+    fn addr() -> SocketAddr {
+        "10.0.0.2:6881".parse().unwrap()
+    }
+
+    fn block(index: u32) -> BlockRequest {
+        BlockRequest {
+            index,
+            begin: 0,
+            length: 4,
+        }
+    }
+
+    // 启动一个 10 个分片的连接, 返回对方的连接
+    async fn connect(
+        have: Bitfield,
+        config: ConnectionConfig,
+    ) -> (
+        PeerHandle,
+        Events,
+        Remote,
+        tokio::task::JoinHandle<Result<(), PeerError>>,
+    ) {
+        let (local, mut remote) = tokio::io::duplex(1 << 16);
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let ours = Handshake::new(InfoHash::V1([1; 20]), [1; 20]);
+        let (connection, handle) =
+            PeerConnection::new(addr(), local, ours, have, config, events_tx);
+        let task = tokio::spawn(connection.run());
+        let theirs = Handshake::new(InfoHash::V1([1; 20]), [2; 20]);
+        handshake::handshake(&mut remote, &theirs).await.unwrap();
+        assert_eq!(
+            events.recv().await.unwrap(),
+            (addr(), PeerEvent::Connected(theirs))
+        );
+        (
+            handle,
+            events,
+            Framed::new(remote, MessageCodec::new()),
+            task,
+        )
+    }
+
+    async fn next_event(events: &mut Events) -> PeerEvent {
+        events.recv().await.unwrap().1
+    }
+
+    async fn next_message(remote: &mut Remote) -> Message {
+        remote.next().await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bitfield_and_have() {
+        let mut have = Bitfield::new(10);
+        have.set(0);
+        let (_handle, mut events, mut remote, _) = connect(have, ConnectionConfig::default()).await;
+        assert_eq!(
+            next_message(&mut remote).await,
+            Message::Bitfield(Bytes::from_static(&[0x80, 0]))
+        );
+
+        remote
+            .send(Message::Bitfield(Bytes::from_static(&[0x40, 0])))
+            .await
+            .unwrap();
+        remote.send(Message::Have(3)).await.unwrap();
+        // 已经有的分片不再报告
+        remote.send(Message::Have(1)).await.unwrap();
+        remote.send(Message::Port(6882)).await.unwrap();
+        let bitfield = Bitfield::from_bytes(&[0x40, 0], 10).unwrap();
+        assert_eq!(next_event(&mut events).await, PeerEvent::Bitfield(bitfield));
+        assert_eq!(next_event(&mut events).await, PeerEvent::Have(3));
+        assert_eq!(next_event(&mut events).await, PeerEvent::Port(6882));
+    }
+
+    #[tokio::test]
+    async fn test_protocol_errors() {
+        let (_handle, mut events, mut remote, task) =
+            connect(Bitfield::new(10), ConnectionConfig::default()).await;
+        remote.send(Message::Have(10)).await.unwrap();
+        assert!(matches!(
+            task.await.unwrap(),
+            Err(PeerError::InvalidMessage(_))
+        ));
+        assert_eq!(next_event(&mut events).await, PeerEvent::Closed(vec![]));
+
+        let (_handle, _, mut remote, task) =
+            connect(Bitfield::new(10), ConnectionConfig::default()).await;
+        remote.send(Message::Unchoke).await.unwrap();
+        remote
+            .send(Message::Bitfield(Bytes::from_static(&[0, 0])))
+            .await
+            .unwrap();
+        assert!(matches!(
+            task.await.unwrap(),
+            Err(PeerError::InvalidMessage(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_pipelining() {
+        let config = ConnectionConfig::default().with_queue_depth(2);
+        let (handle, mut events, mut remote, _) = connect(Bitfield::new(10), config).await;
+        for index in 0..3 {
+            handle.request(block(index));
+        }
+        assert_eq!(next_message(&mut remote).await, Message::Interested);
+
+        // unchoke 之后才发送请求, 最多同时 2 个
+        remote.send(Message::Unchoke).await.unwrap();
+        assert_eq!(next_event(&mut events).await, PeerEvent::Unchoked);
+        for index in 0..2 {
+            assert_eq!(
+                next_message(&mut remote).await,
+                Message::Request {
+                    index,
+                    begin: 0,
+                    length: 4
+                }
+            );
+        }
+        remote
+            .send(Message::Piece {
+                index: 1,
+                begin: 0,
+                block: Bytes::from_static(b"abcd"),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            next_event(&mut events).await,
+            PeerEvent::Block {
+                index: 1,
+                begin: 0,
+                data: Bytes::from_static(b"abcd"),
+            }
+        );
+        assert_eq!(
+            next_message(&mut remote).await,
+            Message::Request {
+                index: 2,
+                begin: 0,
+                length: 4
+            }
+        );
+
+        handle.cancel(block(2));
+        assert_eq!(
+            next_message(&mut remote).await,
+            Message::Cancel {
+                index: 2,
+                begin: 0,
+                length: 4
+            }
+        );
+
+        // 被 choke 时交还没有完成的请求
+        handle.request(block(3));
+        assert_eq!(
+            next_message(&mut remote).await,
+            Message::Request {
+                index: 3,
+                begin: 0,
+                length: 4
+            }
+        );
+        remote.send(Message::Choke).await.unwrap();
+        assert_eq!(
+            next_event(&mut events).await,
+            PeerEvent::Choked(vec![block(0), block(3)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serving_requests() {
+        let (handle, mut events, mut remote, _) =
+            connect(Bitfield::full(10), ConnectionConfig::default()).await;
+        // 先收到我们的 bitfield
+        next_message(&mut remote).await;
+        // 还在 choke 对方, 请求被忽略
+        remote
+            .send(Message::Request {
+                index: 0,
+                begin: 0,
+                length: 4,
+            })
+            .await
+            .unwrap();
+        remote.send(Message::Interested).await.unwrap();
+        assert_eq!(next_event(&mut events).await, PeerEvent::Interested);
+
+        handle.unchoke();
+        assert_eq!(next_message(&mut remote).await, Message::Unchoke);
+        remote
+            .send(Message::Request {
+                index: 5,
+                begin: 0,
+                length: 4,
+            })
+            .await
+            .unwrap();
+        assert_eq!(next_event(&mut events).await, PeerEvent::Request(block(5)));
+        handle.send_block(5, 0, Bytes::from_static(b"wxyz"));
+        handle.have(3);
+        assert_eq!(
+            next_message(&mut remote).await,
+            Message::Piece {
+                index: 5,
+                begin: 0,
+                block: Bytes::from_static(b"wxyz"),
+            }
+        );
+        assert_eq!(next_message(&mut remote).await, Message::Have(3));
+
+        handle.close();
+        assert!(remote.next().await.is_none());
+        assert_eq!(next_event(&mut events).await, PeerEvent::Closed(vec![]));
+    }
+
+    #[tokio::test]
+    async fn test_incoming_request_limit() {
+        let config = ConnectionConfig::default().with_max_incoming_requests(2);
+        let (handle, mut events, mut remote, _) = connect(Bitfield::full(10), config).await;
+        next_message(&mut remote).await;
+        handle.unchoke();
+        assert_eq!(next_message(&mut remote).await, Message::Unchoke);
+
+        let request = |index| Message::Request {
+            index,
+            begin: 0,
+            length: 4,
+        };
+        // 超过上限的请求被丢弃
+        for index in 0..3 {
+            remote.send(request(index)).await.unwrap();
+        }
+        remote.send(Message::Interested).await.unwrap();
+        assert_eq!(next_event(&mut events).await, PeerEvent::Request(block(0)));
+        assert_eq!(next_event(&mut events).await, PeerEvent::Request(block(1)));
+        assert_eq!(next_event(&mut events).await, PeerEvent::Interested);
+
+        // 回答或取消请求后又可以接受新的请求
+        handle.send_block(0, 0, Bytes::from_static(b"abcd"));
+        assert!(matches!(
+            next_message(&mut remote).await,
+            Message::Piece { index: 0, .. }
+        ));
+        remote.send(request(3)).await.unwrap();
+        assert_eq!(next_event(&mut events).await, PeerEvent::Request(block(3)));
+        remote
+            .send(Message::Cancel {
+                index: 1,
+                begin: 0,
+                length: 4,
+            })
+            .await
+            .unwrap();
+        assert_eq!(next_event(&mut events).await, PeerEvent::Cancel(block(1)));
+        remote.send(request(4)).await.unwrap();
+        remote.send(request(5)).await.unwrap();
+        remote.send(Message::NotInterested).await.unwrap();
+        assert_eq!(next_event(&mut events).await, PeerEvent::Request(block(4)));
+        assert_eq!(next_event(&mut events).await, PeerEvent::NotInterested);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keep_alive_and_timeout() {
+        let config = ConnectionConfig::default()
+            .with_keep_alive(Duration::from_secs(60))
+            .with_idle_timeout(Duration::from_secs(150));
+        let (handle, _events, mut remote, task) = connect(Bitfield::new(10), config).await;
+        handle.request(block(0));
+        assert_eq!(next_message(&mut remote).await, Message::Interested);
+
+        let start = Instant::now();
+        assert_eq!(next_message(&mut remote).await, Message::KeepAlive);
+        assert_eq!(start.elapsed().as_secs(), 60);
+        assert_eq!(next_message(&mut remote).await, Message::KeepAlive);
+        assert!(matches!(task.await.unwrap(), Err(PeerError::Timeout)));
+        assert_eq!(start.elapsed().as_secs(), 150);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_timeout() {
+        let (local, _remote) = tokio::io::duplex(1024);
+        let (events, _) = mpsc::unbounded_channel();
+        let ours = Handshake::new(InfoHash::V1([1; 20]), [1; 20]);
+        let (connection, _handle) = PeerConnection::new(
+            addr(),
+            local,
+            ours,
+            Bitfield::new(10),
+            ConnectionConfig::default(),
+            events,
+        );
+        assert!(matches!(connection.run().await, Err(PeerError::Timeout)));
+    }
+}
@@ -10,6 +10,18 @@ pub enum PeerError {
     EOF,
     #[error(transparent)]
     IOError(#[from] ::std::io::Error),
+    #[error("invalid handshake: {0}")]
+    InvalidHandshake(::std::borrow::Cow<'static, str>),
+    #[error("peer sent the handshake of another torrent")]
+    InfoHashMismatch,
+    #[error("invalid message: {0}")]
+    InvalidMessage(::std::borrow::Cow<'static, str>),
+    #[error("unknown message id {0}")]
+    UnknownMessage(u8),
+    #[error("message of {0} bytes is too long")]
+    MessageTooLong(usize),
+    #[error("peer timed out")]
+    Timeout,
     #[error("unknown bencode error")]
     Unknown,
 
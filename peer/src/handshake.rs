@@ -1,72 +1,270 @@
-use std::io::Write;
+use std::borrow::Cow;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::error::PeerError;
-use shared::bytes::ByteBuffer;
-use tokio::runtime::Handle;
+use torrent::InfoHash;
+
+/// The 8 reserved handshake bytes, where clients announce the extensions they support.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Reserved(pub [u8; 8]);
+
+impl Reserved {
+    // (字节下标, 位) 参考 https://wiki.theory.org/BitTorrentSpecification#Reserved_Bytes
+    const DHT: (usize, u8) = (7, 0x01);
+    const FAST: (usize, u8) = (7, 0x04);
+    const EXTENSION_PROTOCOL: (usize, u8) = (5, 0x10);
+
+    /// The DHT (BEP 5), peers may send `port` messages.
+    pub fn dht(&self) -> bool {
+        self.get(Self::DHT)
+    }
+
+    /// The fast extension (BEP 6).
+    pub fn fast(&self) -> bool {
+        self.get(Self::FAST)
+    }
+
+    /// The extension protocol (BEP 10).
+    pub fn extension_protocol(&self) -> bool {
+        self.get(Self::EXTENSION_PROTOCOL)
+    }
+
+    pub fn with_dht(self, enabled: bool) -> Self {
+        self.set(Self::DHT, enabled)
+    }
+
+    pub fn with_fast(self, enabled: bool) -> Self {
+        self.set(Self::FAST, enabled)
+    }
 
-#[derive(Default)]
+    pub fn with_extension_protocol(self, enabled: bool) -> Self {
+        self.set(Self::EXTENSION_PROTOCOL, enabled)
+    }
+
+    fn get(&self, (byte, bit): (usize, u8)) -> bool {
+        self.0[byte] & bit != 0
+    }
+
+    fn set(mut self, (byte, bit): (usize, u8), enabled: bool) -> Self {
+        if enabled {
+            self.0[byte] |= bit;
+        } else {
+            self.0[byte] &= !bit;
+        }
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Handshake {
     pub pstrlen: u8,
     pub pstr: &'static str, // 写死的值, 所以直接用static
-    pub reserved: [u8; 8],
-    pub info_hash: [u8; 20],
+    pub reserved: Reserved,
+    pub info_hash: InfoHash,
     pub peer_id: [u8; 20],
 }
 
+#[allow(clippy::redundant_static_lifetimes)]
 pub const PROTOCOL_STRING: &'static str = "BitTorrent protocol";
 
 impl Handshake {
-    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+    pub fn new(info_hash: InfoHash, peer_id: [u8; 20]) -> Self {
         Self {
             pstrlen: PROTOCOL_STRING.len() as u8,
             pstr: PROTOCOL_STRING,
-            reserved: [0; 8],
+            reserved: Reserved::default(),
             info_hash,
             peer_id,
         }
     }
 
+    pub fn with_reserved(mut self, reserved: Reserved) -> Self {
+        self.reserved = reserved;
+        self
+    }
+
     pub fn len() -> usize {
         1 + 19 + 8 + 20 + 20
     }
 
     // <pstrlen><pstr><reserved><info_hash><peer_id>
+    #[allow(clippy::needless_borrow)]
     pub fn encode(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(Self::len());
         buf.extend_from_slice(&[self.pstrlen]);
         buf.extend_from_slice(&self.pstr.as_bytes());
-        buf.extend_from_slice(&self.reserved);
-        buf.extend_from_slice(&self.info_hash);
+        buf.extend_from_slice(&self.reserved.0);
+        // v2 的 info hash 在握手中截断为 20 字节
+        buf.extend_from_slice(&self.info_hash.truncated());
         buf.extend_from_slice(&self.peer_id);
         buf
     }
 
+    /// Decodes a complete handshake. The info hash is always returned as
+    /// [`InfoHash::V1`], since v2 hashes are truncated on the wire.
     pub fn decode<T>(bytes: T) -> Result<Handshake, PeerError>
     where
         T: AsRef<[u8]>,
     {
-        let content_len = bytes.as_ref().len();
-        if content_len != Self::len() {
-            // TODO: invalid content
+        let bytes = bytes.as_ref();
+        if bytes.len() != Self::len() {
+            return Err(PeerError::InvalidHandshake(Cow::Owned(format!(
+                "expected {} bytes, got {}",
+                Self::len(),
+                bytes.len()
+            ))));
+        }
+        if bytes[0] as usize != PROTOCOL_STRING.len() {
+            return Err(PeerError::InvalidHandshake(Cow::Borrowed(
+                "`pstrlen` is not 19.",
+            )));
         }
-        let mut buf = ByteBuffer::new(bytes.as_ref());
-        let mut obj = Handshake::default();
-        if let Some(&pstrlen) = buf.next() {
-            obj.pstrlen = pstrlen
+        let (pstr, rest) = bytes[1..].split_at(PROTOCOL_STRING.len());
+        if pstr != PROTOCOL_STRING.as_bytes() {
+            return Err(PeerError::InvalidHandshake(Cow::Borrowed(
+                "`pstr` is not `BitTorrent protocol`.",
+            )));
         }
+        let (reserved, rest) = rest.split_at(8);
+        let (info_hash, peer_id) = rest.split_at(20);
+        Ok(Self {
+            pstrlen: bytes[0],
+            pstr: PROTOCOL_STRING,
+            reserved: Reserved(reserved.try_into().unwrap()),
+            info_hash: InfoHash::V1(info_hash.try_into().unwrap()),
+            peer_id: peer_id.try_into().unwrap(),
+        })
+    }
+
+    /// Whether the other side's handshake is for the same torrent as this one.
+    pub fn matches(&self, theirs: &Handshake) -> bool {
+        self.info_hash.truncated() == theirs.info_hash.truncated()
+    }
+}
+
+/// Sends our handshake and reads the peer's, failing if it is for another torrent.
+///
+/// Both sides send without waiting for each other, so this works for outgoing
+/// connections as well as for incoming ones whose torrent is known. A listener
+/// serving several torrents reads the peer's handshake first with [`read_handshake`].
+pub async fn handshake<S>(stream: &mut S, ours: &Handshake) -> Result<Handshake, PeerError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(&ours.encode()).await?;
+    let theirs = read_handshake(stream).await?;
+    if !ours.matches(&theirs) {
+        return Err(PeerError::InfoHashMismatch);
+    }
+    Ok(theirs)
+}
+
+/// Reads a handshake, giving up as soon as the first byte shows another protocol.
+pub async fn read_handshake<S>(stream: &mut S) -> Result<Handshake, PeerError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = vec![0; Handshake::len()];
+    stream.read_exact(&mut buf[..1]).await?;
+    if buf[0] as usize != PROTOCOL_STRING.len() {
+        return Err(PeerError::InvalidHandshake(Cow::Borrowed(
+            "`pstrlen` is not 19.",
+        )));
+    }
+    stream.read_exact(&mut buf[1..]).await?;
+    Handshake::decode(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ours() -> Handshake {
+        Handshake::new(InfoHash::V1([1; 20]), *b"-RS0100-abcdefghijkl")
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let handshake = ours().with_reserved(Reserved::default().with_dht(true).with_fast(true));
+        let bytes = handshake.encode();
+        assert_eq!(bytes.len(), Handshake::len());
+        assert_eq!(&bytes[..20], b"\x13BitTorrent protocol");
+        assert_eq!(&bytes[20..28], &[0, 0, 0, 0, 0, 0, 0, 0x05]);
+        assert_eq!(Handshake::decode(&bytes).unwrap(), handshake);
+
+        assert!(matches!(
+            Handshake::decode(&bytes[..67]),
+            Err(PeerError::InvalidHandshake(_))
+        ));
+        let mut wrong = bytes.clone();
+        wrong[0] = 18;
+        assert!(Handshake::decode(&wrong).is_err());
+        let mut wrong = bytes;
+        wrong[1] = b'b';
+        assert!(Handshake::decode(&wrong).is_err());
+    }
+
+    #[test]
+    fn test_reserved() {
+        let reserved = Reserved([0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+        assert!(reserved.dht());
+        assert!(reserved.extension_protocol());
+        assert!(!reserved.fast());
+        let reserved = reserved.with_dht(false).with_fast(true);
+        assert_eq!(reserved, Reserved([0, 0, 0, 0, 0, 0x10, 0, 0x04]));
+    }
+
+    #[test]
+    fn test_truncated_v2_matches() {
+        let v2 = Handshake::new(InfoHash::V2([7; 32]), [0; 20]);
+        let theirs = Handshake::decode(v2.encode()).unwrap();
+        assert_eq!(theirs.info_hash, InfoHash::V1([7; 20]));
+        assert!(v2.matches(&theirs));
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (mut a, mut b) = tokio::io::duplex(256);
+        let theirs = Handshake::new(InfoHash::V1([1; 20]), [2; 20])
+            .with_reserved(Reserved::default().with_extension_protocol(true));
+        let remote = theirs.clone();
+        // 接收方先读对方的握手, 再回应
+        let incoming = tokio::spawn(async move {
+            let ours = read_handshake(&mut b).await.unwrap();
+            b.write_all(&remote.encode()).await.unwrap();
+            ours
+        });
+        let got = handshake(&mut a, &ours()).await.unwrap();
+        assert_eq!(got, theirs);
+        assert!(got.reserved.extension_protocol());
+        assert_eq!(incoming.await.unwrap(), ours());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_errors() {
+        let (mut a, mut b) = tokio::io::duplex(256);
+        b.write_all(&Handshake::new(InfoHash::V1([9; 20]), [2; 20]).encode())
+            .await
+            .unwrap();
+        assert!(matches!(
+            handshake(&mut a, &ours()).await,
+            Err(PeerError::InfoHashMismatch)
+        ));
+
+        let (mut a, mut b) = tokio::io::duplex(256);
+        b.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        assert!(matches!(
+            handshake(&mut a, &ours()).await,
+            Err(PeerError::InvalidHandshake(_))
+        ));
 
-        // let pstr: Vec<u8> = buf.take(PROTOCOL_STRING.len()).cloned().collect();
-        // let reserved: Vec<u8> = buf.take(8).cloned().collect();
-        // let info_hash: Vec<u8> = buf.take(20).cloned().collect();
-        // let peer_id: Vec<u8> = buf.take(20).cloned().collect();
-        // Ok(Self {
-        //     pstrlen: todo!(),
-        //     pstr: todo!(),
-        //     reserved: todo!(),
-        //     info_hash: todo!(),
-        //     peer_id: todo!(),
-        // })
-
-        todo!()
+        let (mut a, mut b) = tokio::io::duplex(256);
+        b.write_all(&ours().encode()[..30]).await.unwrap();
+        drop(b);
+        assert!(matches!(
+            handshake(&mut a, &ours()).await,
+            Err(PeerError::IOError(_))
+        ));
     }
 }
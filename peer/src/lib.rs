@@ -1,44 +1,65 @@
-mod error;
-mod handshake;
-
-use std::{io::copy, net::SocketAddr};
-
-use tokio::{io::AsyncReadExt, net::TcpStream};
-
-#[derive(Copy, Clone, Debug, PartialEq)]
-pub enum Message {}
-
-#[derive(Copy, Clone, Debug, PartialEq)]
-pub enum MessageValue {
-    Choke = 0,
-    Unchoke = 1,
-    Interested = 2,
-    NotInterested = 3,
-    Have = 4,
-    Bitfield = 5,
-    Request = 6,
-    Block = 7,
-    Cancel = 8,
-}
+mod bitfield;
+mod connection;
+pub mod error;
+pub mod handshake;
+mod message;
+
+use std::net::SocketAddr;
+
+use tokio::net::TcpStream;
+
+use crate::error::PeerError;
+use crate::handshake::Handshake;
+
+pub use crate::bitfield::Bitfield;
+pub use crate::connection::{
+    BlockRequest, ConnectionConfig, EventSender, PeerConnection, PeerEvent, PeerHandle,
+};
+pub use crate::message::{Message, MessageCodec, MessageValue, DEFAULT_MAX_MESSAGE_LEN};
 
 pub struct Peer;
 
 impl Peer {
-    pub async fn handshake(addr: SocketAddr) {
-        let mut stream = TcpStream::connect(addr).await.unwrap();
-        let mut buf = Vec::new();
-        stream.read_buf(&mut buf).await.unwrap();
+    /// Connects to a peer and exchanges handshakes, returning the connection and the peer's handshake.
+    pub async fn handshake(
+        addr: SocketAddr,
+        ours: &Handshake,
+    ) -> Result<(TcpStream, Handshake), PeerError> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let theirs = handshake::handshake(&mut stream, ours).await?;
+        Ok((stream, theirs))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use torrent::InfoHash;
+
+    // 依赖公网上的固定 peer, 离线或 peer 下线时必然失败, 行为由 test_handshake_with_listener 覆盖
     #[tokio::test]
+    #[ignore = "needs a live peer on the internet"]
     async fn test_tcp_conn() {
         let cases = [SocketAddr::new("198.54.132.42".parse().unwrap(), 54886)];
+        let ours = Handshake::new(InfoHash::V1([0; 20]), *b"-RS0100-000000000000");
         for addr in cases {
-            Peer::handshake(addr).await;
+            Peer::handshake(addr, &ours).await.unwrap();
         }
     }
+
+    #[tokio::test]
+    async fn test_handshake_with_listener() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let theirs = Handshake::new(InfoHash::V1([1; 20]), [2; 20]);
+        let remote = theirs.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            handshake::handshake(&mut stream, &remote).await.unwrap();
+        });
+
+        let ours = Handshake::new(InfoHash::V1([1; 20]), [3; 20]);
+        let (_, got) = Peer::handshake(addr, &ours).await.unwrap();
+        assert_eq!(got, theirs);
+    }
 }
@@ -0,0 +1,390 @@
+use std::borrow::Cow;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::error::PeerError;
+
+/// The largest message accepted by default: a 16 KiB block with room to spare,
+/// and the bitfield of a torrent with up to two million pieces.
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 1 << 18;
+
+/// The ids of the messages following the handshake.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MessageValue {
+    Choke = 0,
+    Unchoke = 1,
+    Interested = 2,
+    NotInterested = 3,
+    Have = 4,
+    Bitfield = 5,
+    Request = 6,
+    Piece = 7,
+    Cancel = 8,
+    Port = 9,
+}
+
+impl TryFrom<u8> for MessageValue {
+    type Error = PeerError;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        Ok(match id {
+            0 => Self::Choke,
+            1 => Self::Unchoke,
+            2 => Self::Interested,
+            3 => Self::NotInterested,
+            4 => Self::Have,
+            5 => Self::Bitfield,
+            6 => Self::Request,
+            7 => Self::Piece,
+            8 => Self::Cancel,
+            9 => Self::Port,
+            _ => return Err(PeerError::UnknownMessage(id)),
+        })
+    }
+}
+
+/// A peer wire message: `<length prefix><message id><payload>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Bytes),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Bytes,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// The DHT port of the peer (BEP 5).
+    Port(u16),
+}
+
+impl Message {
+    /// The message id, `None` for keep-alives which have none.
+    pub fn id(&self) -> Option<MessageValue> {
+        Some(match self {
+            Self::KeepAlive => return None,
+            Self::Choke => MessageValue::Choke,
+            Self::Unchoke => MessageValue::Unchoke,
+            Self::Interested => MessageValue::Interested,
+            Self::NotInterested => MessageValue::NotInterested,
+            Self::Have(_) => MessageValue::Have,
+            Self::Bitfield(_) => MessageValue::Bitfield,
+            Self::Request { .. } => MessageValue::Request,
+            Self::Piece { .. } => MessageValue::Piece,
+            Self::Cancel { .. } => MessageValue::Cancel,
+            Self::Port(_) => MessageValue::Port,
+        })
+    }
+
+    // 不包括 4 个字节的长度前缀
+    fn len(&self) -> usize {
+        match self {
+            Self::KeepAlive => 0,
+            Self::Choke | Self::Unchoke | Self::Interested | Self::NotInterested => 1,
+            Self::Have(_) => 5,
+            Self::Bitfield(bitfield) => 1 + bitfield.len(),
+            Self::Request { .. } | Self::Cancel { .. } => 13,
+            Self::Piece { block, .. } => 9 + block.len(),
+            Self::Port(_) => 3,
+        }
+    }
+}
+
+/// Frames [`Message`]s on a connection after the handshake.
+///
+/// ```no_run
+/// # async fn run(stream: tokio::net::TcpStream) {
+/// use peer::MessageCodec;
+/// use tokio_util::codec::Framed;
+///
+/// let framed = Framed::new(stream, MessageCodec::new());
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageCodec {
+    max_len: usize,
+}
+
+impl MessageCodec {
+    pub fn new() -> Self {
+        Self {
+            max_len: DEFAULT_MAX_MESSAGE_LEN,
+        }
+    }
+
+    /// Messages longer than this (without the length prefix) are an error,
+    /// [`DEFAULT_MAX_MESSAGE_LEN`] by default.
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = PeerError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, PeerError> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(src[..4].try_into().unwrap()) as usize;
+        // 在收到整个消息之前就拒绝, 不会为它分配内存
+        if len > self.max_len {
+            return Err(PeerError::MessageTooLong(len));
+        }
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        if len == 0 {
+            return Ok(Some(Message::KeepAlive));
+        }
+        let mut payload = src.split_to(len);
+        let id = MessageValue::try_from(payload.get_u8())?;
+        let expected = match id {
+            MessageValue::Choke
+            | MessageValue::Unchoke
+            | MessageValue::Interested
+            | MessageValue::NotInterested => Some(0),
+            MessageValue::Have => Some(4),
+            MessageValue::Request | MessageValue::Cancel => Some(12),
+            MessageValue::Port => Some(2),
+            MessageValue::Bitfield => None,
+            MessageValue::Piece if payload.len() < 8 => {
+                return Err(PeerError::InvalidMessage(Cow::Borrowed(
+                    "`piece` is shorter than its header.",
+                )))
+            }
+            MessageValue::Piece => None,
+        };
+        if let Some(expected) = expected {
+            if payload.len() != expected {
+                return Err(PeerError::InvalidMessage(Cow::Owned(format!(
+                    "{:?} payload must be {} bytes, got {}",
+                    id,
+                    expected,
+                    payload.len()
+                ))));
+            }
+        }
+        Ok(Some(match id {
+            MessageValue::Choke => Message::Choke,
+            MessageValue::Unchoke => Message::Unchoke,
+            MessageValue::Interested => Message::Interested,
+            MessageValue::NotInterested => Message::NotInterested,
+            MessageValue::Have => Message::Have(payload.get_u32()),
+            MessageValue::Bitfield => Message::Bitfield(payload.freeze()),
+            MessageValue::Request => Message::Request {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                length: payload.get_u32(),
+            },
+            MessageValue::Piece => Message::Piece {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                block: payload.freeze(),
+            },
+            MessageValue::Cancel => Message::Cancel {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                length: payload.get_u32(),
+            },
+            MessageValue::Port => Message::Port(payload.get_u16()),
+        }))
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = PeerError;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<(), PeerError> {
+        let len = msg.len();
+        if len > self.max_len {
+            return Err(PeerError::MessageTooLong(len));
+        }
+        dst.reserve(4 + len);
+        dst.put_u32(len as u32);
+        if let Some(id) = msg.id() {
+            dst.put_u8(id as u8);
+        }
+        match msg {
+            Message::KeepAlive
+            | Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested => {}
+            Message::Have(index) => dst.put_u32(index),
+            Message::Bitfield(bitfield) => dst.put_slice(&bitfield),
+            Message::Request {
+                index,
+                begin,
+                length,
+            }
+            | Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.put_u32(length);
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.put_slice(&block);
+            }
+            Message::Port(port) => dst.put_u16(port),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    use super::*;
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have(0x01020304),
+            Message::Bitfield(Bytes::from_static(&[0xff, 0x80])),
+            Message::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Piece {
+                index: 1,
+                begin: 16384,
+                block: Bytes::from(vec![7; 16384]),
+            },
+            Message::Cancel {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Port(6881),
+        ]
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::new();
+        for msg in messages() {
+            codec.encode(msg, &mut buf).unwrap();
+        }
+        for msg in messages() {
+            assert_eq!(codec.decode(&mut buf).unwrap(), Some(msg));
+        }
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(Message::Have(5), &mut buf).unwrap();
+        codec.encode(Message::KeepAlive, &mut buf).unwrap();
+        codec.encode(Message::Port(0x1ae1), &mut buf).unwrap();
+        assert_eq!(
+            &buf[..],
+            &[0, 0, 0, 5, 4, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 3, 9, 0x1a, 0xe1]
+        );
+    }
+
+    #[test]
+    fn test_partial_messages() {
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::from(&[0, 0, 0, 5, 4, 0, 0][..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&[0, 9]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Have(9)));
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        let mut codec = MessageCodec::new().with_max_len(100);
+        let mut buf = BytesMut::from(&[0, 0, 0, 101][..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(PeerError::MessageTooLong(101))
+        ));
+        let block = Bytes::from(vec![0; 100]);
+        let piece = Message::Piece {
+            index: 0,
+            begin: 0,
+            block,
+        };
+        assert!(codec.encode(piece, &mut BytesMut::new()).is_err());
+
+        for bytes in [
+            &[0, 0, 0, 3, 4, 0, 0][..],
+            &[0, 0, 0, 2, 0, 0],
+            &[0, 0, 0, 5, 7, 0, 0, 0, 0],
+        ] {
+            assert!(matches!(
+                codec.decode(&mut BytesMut::from(bytes)),
+                Err(PeerError::InvalidMessage(_))
+            ));
+        }
+        assert!(matches!(
+            codec.decode(&mut BytesMut::from(&[0, 0, 0, 1, 20][..])),
+            Err(PeerError::UnknownMessage(20))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_framed() {
+        let (a, b) = tokio::io::duplex(1024);
+        let mut sink = FramedWrite::new(a, MessageCodec::new());
+        let mut stream = FramedRead::new(b, MessageCodec::new());
+        tokio::spawn(async move {
+            for msg in messages() {
+                sink.send(msg).await.unwrap();
+            }
+        });
+        let received: Vec<Message> = stream.by_ref().map(Result::unwrap).collect().await;
+        assert_eq!(received, messages());
+    }
+}
@@ -8,6 +8,7 @@ pub struct ByteBuffer<'a> {
 }
 
 impl<'a> ByteBuffer<'a> {
+    #[allow(mismatched_lifetime_syntaxes)]
     pub fn new(data: &[u8]) -> ByteBuffer {
         ByteBuffer {
             data,
@@ -0,0 +1,91 @@
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use torrent::Info;
+
+use crate::error::StorageError;
+use crate::file::{Allocation, FileStorage};
+use crate::layout::FileState;
+use crate::memory::{DiscardStorage, MemoryStorage};
+use crate::mmap::MmapStorage;
+
+/// Where the content of a torrent is kept.
+///
+/// The methods block, so they are meant to run on an [`IoPool`](crate::IoPool),
+/// which [`TorrentStorage`](crate::TorrentStorage) does.
+pub trait Storage: Debug + Send + Sync {
+    /// Reads `length` bytes at `begin` within the piece.
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>, StorageError>;
+
+    /// Writes `data` at `begin` within the piece.
+    fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> Result<(), StorageError>;
+
+    /// Makes the written data durable.
+    fn flush(&self) -> Result<(), StorageError>;
+
+    /// Whether the stored piece matches its hash.
+    fn verify_piece(&self, index: u32) -> Result<bool, StorageError>;
+
+    /// Moves the content to the download directory `root`.
+    fn move_to(&self, root: &Path) -> Result<(), StorageError>;
+
+    /// Removes the content.
+    fn delete(&self) -> Result<(), StorageError>;
+
+    /// Reserves the space for the content before downloading.
+    fn allocate(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// The state of each file of the torrent on disk, `None` for the files
+    /// that do not exist. Backends without files return no states.
+    fn file_states(&self) -> Result<Vec<Option<FileState>>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    /// Skips the `file`-th file of the torrent or wants it again. Only
+    /// backends that keep files care about it; those that keep files but
+    /// cannot skip them return [`StorageError::Unsupported`].
+    fn set_skipped(&self, _file: usize, _skipped: bool) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+/// The kinds of [`Storage`], to choose one per torrent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// [`MemoryStorage`], e.g. for tests.
+    Memory,
+    /// [`FileStorage`], which reads and writes the files.
+    File(Allocation),
+    /// [`MmapStorage`], which maps the files into memory.
+    Mmap,
+    /// [`DiscardStorage`], which drops everything written.
+    Discard,
+}
+
+impl Default for Backend {
+    /// [`Backend::File`] with sparse files.
+    fn default() -> Self {
+        Backend::File(Allocation::Sparse)
+    }
+}
+
+impl Backend {
+    /// The storage of the torrent under the download directory `root`.
+    pub fn open(
+        self,
+        info: &Info,
+        root: impl Into<PathBuf>,
+    ) -> Result<Arc<dyn Storage>, StorageError> {
+        Ok(match self {
+            Backend::Memory => Arc::new(MemoryStorage::new(info)?),
+            Backend::File(allocation) => {
+                Arc::new(FileStorage::new(info, root)?.with_allocation(allocation))
+            }
+            Backend::Mmap => Arc::new(MmapStorage::new(info, root)?),
+            Backend::Discard => Arc::new(DiscardStorage::new(info)?),
+        })
+    }
+}
@@ -0,0 +1,632 @@
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use torrent::Info;
+
+use crate::backend::Storage;
+use crate::error::StorageError;
+use crate::layout::{FileState, Layout};
+
+/// Settings of a [`CachedStorage`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheConfig {
+    write_limit: usize,
+    read_limit: usize,
+    read_ahead: u32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            write_limit: 16 * 1024 * 1024,
+            read_limit: 32 * 1024 * 1024,
+            read_ahead: 1,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// How many bytes of pieces being written are kept before the oldest is
+    /// written even though it is not whole, `16` MiB by default. `0` writes
+    /// every block through.
+    pub fn with_write_limit(mut self, write_limit: usize) -> Self {
+        self.write_limit = write_limit;
+        self
+    }
+
+    /// How many bytes of pieces read are kept, `32` MiB by default. `0` reads
+    /// every block through.
+    pub fn with_read_limit(mut self, read_limit: usize) -> Self {
+        self.read_limit = read_limit;
+        self
+    }
+
+    /// How many pieces after a piece that is read are read too, `1` by default.
+    pub fn with_read_ahead(mut self, read_ahead: u32) -> Self {
+        self.read_ahead = read_ahead;
+        self
+    }
+
+    pub fn write_limit(&self) -> usize {
+        self.write_limit
+    }
+
+    pub fn read_limit(&self) -> usize {
+        self.read_limit
+    }
+
+    pub fn read_ahead(&self) -> u32 {
+        self.read_ahead
+    }
+}
+
+/// Counters of a [`CachedStorage`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Blocks read from the caches.
+    pub read_hits: u64,
+    /// Blocks read from the storage.
+    pub read_misses: u64,
+    /// Blocks written to the cache.
+    pub blocks_cached: u64,
+    /// Whole pieces written to the storage at once.
+    pub pieces_written: u64,
+    /// Pieces written to the storage before they were whole, to stay within
+    /// the write limit or because they were needed.
+    pub partial_writes: u64,
+    /// Bytes currently held for writing.
+    pub write_bytes: usize,
+    /// Bytes currently held for reading.
+    pub read_bytes: usize,
+}
+
+impl CacheStats {
+    /// The share of blocks read from the caches, `0` before any read.
+    pub fn hit_rate(&self) -> f64 {
+        let reads = self.read_hits + self.read_misses;
+        if reads == 0 {
+            return 0.0;
+        }
+        self.read_hits as f64 / reads as f64
+    }
+}
+
+// 正在写的分片, 以及其中已经收到的字节范围
+#[derive(Debug)]
+struct Pending {
+    data: Vec<u8>,
+    filled: Vec<Range<usize>>,
+}
+
+impl Pending {
+    fn insert(&mut self, begin: usize, data: &[u8]) {
+        self.data[begin..begin + data.len()].copy_from_slice(data);
+        self.filled.push(begin..begin + data.len());
+        self.filled.sort_by_key(|range| range.start);
+        // 合并重叠或相邻的范围
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(self.filled.len());
+        for range in self.filled.drain(..) {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        self.filled = merged;
+    }
+
+    fn is_complete(&self) -> bool {
+        self.filled.len() == 1 && self.filled[0] == (0..self.data.len())
+    }
+
+    fn overlaps(&self, range: &Range<usize>) -> bool {
+        self.filled
+            .iter()
+            .any(|filled| filled.start < range.end && range.start < filled.end)
+    }
+}
+
+#[derive(Debug, Default)]
+struct WriteCache {
+    pieces: HashMap<u32, Pending>,
+    order: VecDeque<u32>,
+    bytes: usize,
+}
+
+#[derive(Debug, Default)]
+struct ReadCache {
+    pieces: HashMap<u32, Arc<Vec<u8>>>,
+    // 最近使用的在后面
+    order: VecDeque<u32>,
+    bytes: usize,
+    // 每次因为写入而失效时加一, 不持锁读下层期间变了就不能缓存读到的内容
+    generation: u64,
+}
+
+impl ReadCache {
+    fn get(&mut self, index: u32) -> Option<Arc<Vec<u8>>> {
+        let piece = self.pieces.get(&index)?.clone();
+        self.touch(index);
+        Some(piece)
+    }
+
+    // 已经被挤掉的分片不能再放回 order, 否则 order 和 pieces 不一致
+    fn touch(&mut self, index: u32) {
+        if !self.pieces.contains_key(&index) {
+            return;
+        }
+        if let Some(n) = self.order.iter().position(|&i| i == index) {
+            self.order.remove(n);
+        }
+        self.order.push_back(index);
+    }
+
+    fn insert(&mut self, index: u32, piece: Arc<Vec<u8>>, limit: usize) {
+        self.remove(index);
+        // 比整个缓存还大的分片不缓存
+        if piece.len() > limit {
+            return;
+        }
+        self.bytes += piece.len();
+        self.pieces.insert(index, piece);
+        self.order.push_back(index);
+        while self.bytes > limit {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(piece) = self.pieces.remove(&oldest) {
+                self.bytes -= piece.len();
+            }
+        }
+    }
+
+    fn remove(&mut self, index: u32) {
+        if let Some(piece) = self.pieces.remove(&index) {
+            self.bytes -= piece.len();
+            self.order.retain(|&i| i != index);
+        }
+    }
+
+    // 分片被写入, 缓存的内容过时了
+    fn invalidate(&mut self, index: u32) {
+        self.remove(index);
+        self.generation += 1;
+    }
+
+    fn clear(&mut self) {
+        self.pieces.clear();
+        self.order.clear();
+        self.bytes = 0;
+        self.generation += 1;
+    }
+}
+
+/// A [`Storage`] that caches another one.
+///
+/// Blocks written are kept until their piece is whole and then written at
+/// once, which saves many small writes on slow disks. Pieces read are kept
+/// whole, along with the pieces after them, as peers usually ask for the
+/// rest of a piece they started on.
+#[derive(Debug)]
+pub struct CachedStorage {
+    inner: Arc<dyn Storage>,
+    layout: Layout,
+    config: CacheConfig,
+    // 锁的顺序: 先 writes 后 reads
+    writes: Mutex<WriteCache>,
+    reads: Mutex<ReadCache>,
+    stats: Mutex<CacheStats>,
+}
+
+impl CachedStorage {
+    pub fn new(
+        info: &Info,
+        inner: Arc<dyn Storage>,
+        config: CacheConfig,
+    ) -> Result<Self, StorageError> {
+        Ok(Self {
+            inner,
+            layout: Layout::new(info)?,
+            config,
+            writes: Mutex::new(WriteCache::default()),
+            reads: Mutex::new(ReadCache::default()),
+            stats: Mutex::new(CacheStats::default()),
+        })
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = *self.stats.lock().unwrap();
+        stats.write_bytes = self.writes.lock().unwrap().bytes;
+        stats.read_bytes = self.reads.lock().unwrap().bytes;
+        stats
+    }
+
+    // 把分片写到下层, 调用方持有 writes 锁
+    fn write_pending(&self, index: u32, pending: Pending) -> Result<(), StorageError> {
+        self.reads.lock().unwrap().invalidate(index);
+        if pending.is_complete() {
+            self.inner.write_block(index, 0, &pending.data)?;
+            self.stats.lock().unwrap().pieces_written += 1;
+        } else {
+            for range in &pending.filled {
+                self.inner
+                    .write_block(index, range.start as u32, &pending.data[range.clone()])?;
+            }
+            self.stats.lock().unwrap().partial_writes += 1;
+        }
+        Ok(())
+    }
+
+    fn take_pending(writes: &mut WriteCache, index: u32) -> Option<Pending> {
+        let pending = writes.pieces.remove(&index)?;
+        writes.order.retain(|&i| i != index);
+        writes.bytes -= pending.data.len();
+        Some(pending)
+    }
+
+    fn write_all(&self, writes: &mut WriteCache) -> Result<(), StorageError> {
+        while let Some(index) = writes.order.front().copied() {
+            let pending = Self::take_pending(writes, index).unwrap();
+            self.write_pending(index, pending)?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for CachedStorage {
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>, StorageError> {
+        self.layout.block_offset(index, begin, length as usize)?;
+        let range = begin as usize..(begin + length) as usize;
+        if let Some(piece) = self.reads.lock().unwrap().get(index) {
+            self.stats.lock().unwrap().read_hits += 1;
+            return Ok(piece[range].to_vec());
+        }
+
+        // 持锁决定怎么读, 读下层时不持锁, 以免所有读写都排在磁盘 IO 后面
+        let mut writes = self.writes.lock().unwrap();
+        if let Some(pending) = writes.pieces.get(&index) {
+            if pending
+                .filled
+                .iter()
+                .any(|filled| filled.start <= range.start && range.end <= filled.end)
+            {
+                self.stats.lock().unwrap().read_hits += 1;
+                return Ok(pending.data[range].to_vec());
+            }
+            // 部分在缓存中, 先写下去再读
+            if pending.overlaps(&range) {
+                let pending = Self::take_pending(&mut writes, index).unwrap();
+                self.write_pending(index, pending)?;
+            }
+        }
+        self.stats.lock().unwrap().read_misses += 1;
+        // 还有块在写缓存中的分片, 下层的内容不完整, 不能放进读缓存
+        if self.config.read_limit == 0 || writes.pieces.contains_key(&index) {
+            drop(writes);
+            return self.inner.read_block(index, begin, length);
+        }
+        let (generation, ahead) = {
+            let reads = self.reads.lock().unwrap();
+            let last = (index.saturating_add(self.config.read_ahead))
+                .min(self.layout.piece_count as u32 - 1);
+            let ahead: Vec<u32> = (index + 1..=last)
+                .filter(|ahead| {
+                    !reads.pieces.contains_key(ahead) && !writes.pieces.contains_key(ahead)
+                })
+                .collect();
+            (reads.generation, ahead)
+        };
+        drop(writes);
+
+        let size = self.layout.checked_piece_size(index)?;
+        let piece = Arc::new(self.inner.read_block(index, 0, size)?);
+        let mut read_ahead = Vec::with_capacity(ahead.len());
+        for ahead in ahead {
+            let size = self.layout.checked_piece_size(ahead)?;
+            // 预读失败不影响这次读取
+            if let Ok(data) = self.inner.read_block(ahead, 0, size) {
+                read_ahead.push((ahead, Arc::new(data)));
+            }
+        }
+
+        // 读的期间有写入时, 读到的内容可能已经过时, 不放进缓存
+        let mut reads = self.reads.lock().unwrap();
+        if reads.generation == generation {
+            reads.insert(index, piece.clone(), self.config.read_limit);
+            for (ahead, data) in read_ahead {
+                if !reads.pieces.contains_key(&ahead) {
+                    reads.insert(ahead, data, self.config.read_limit);
+                }
+            }
+            // 预读的分片不应挤掉这次读的分片
+            reads.touch(index);
+        }
+        Ok(piece[range].to_vec())
+    }
+
+    fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> Result<(), StorageError> {
+        self.layout.block_offset(index, begin, data.len())?;
+        let size = self.layout.checked_piece_size(index)? as usize;
+        let mut writes = self.writes.lock().unwrap();
+        self.reads.lock().unwrap().invalidate(index);
+        if self.config.write_limit == 0 || (begin == 0 && data.len() == size) {
+            // 整个分片或不缓存时直接写, 但不能被之前缓存的块覆盖
+            if let Some(pending) = Self::take_pending(&mut writes, index) {
+                self.write_pending(index, pending)?;
+            }
+            return self.inner.write_block(index, begin, data);
+        }
+
+        let cache = &mut *writes;
+        let pending = cache.pieces.entry(index).or_insert_with(|| {
+            cache.order.push_back(index);
+            cache.bytes += size;
+            Pending {
+                data: vec![0; size],
+                filled: Vec::new(),
+            }
+        });
+        pending.insert(begin as usize, data);
+        let complete = pending.is_complete();
+        self.stats.lock().unwrap().blocks_cached += 1;
+
+        if complete {
+            let pending = Self::take_pending(&mut writes, index).unwrap();
+            self.write_pending(index, pending)?;
+        }
+        while writes.bytes > self.config.write_limit {
+            let oldest = writes.order.front().copied().unwrap();
+            let pending = Self::take_pending(&mut writes, oldest).unwrap();
+            self.write_pending(oldest, pending)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        let mut writes = self.writes.lock().unwrap();
+        self.write_all(&mut writes)?;
+        self.inner.flush()
+    }
+
+    fn verify_piece(&self, index: u32) -> Result<bool, StorageError> {
+        let mut writes = self.writes.lock().unwrap();
+        if let Some(pending) = Self::take_pending(&mut writes, index) {
+            self.write_pending(index, pending)?;
+        }
+        self.inner.verify_piece(index)
+    }
+
+    fn move_to(&self, root: &Path) -> Result<(), StorageError> {
+        let mut writes = self.writes.lock().unwrap();
+        self.write_all(&mut writes)?;
+        self.inner.move_to(root)
+    }
+
+    fn delete(&self) -> Result<(), StorageError> {
+        let mut writes = self.writes.lock().unwrap();
+        *writes = WriteCache::default();
+        self.reads.lock().unwrap().clear();
+        self.inner.delete()
+    }
+
+    fn allocate(&self) -> Result<(), StorageError> {
+        self.inner.allocate()
+    }
+
+    fn file_states(&self) -> Result<Vec<Option<FileState>>, StorageError> {
+        // 缓存的块写下去之后文件才是最新的
+        let mut writes = self.writes.lock().unwrap();
+        self.write_all(&mut writes)?;
+        self.inner.file_states()
+    }
+
+    fn set_skipped(&self, file: usize, skipped: bool) -> Result<(), StorageError> {
+        // 缓存的块要按原来的设置写下去
+        let mut writes = self.writes.lock().unwrap();
+        self.write_all(&mut writes)?;
+        self.reads.lock().unwrap().clear();
+        self.inner.set_skipped(file, skipped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::fixture::{info, piece};
+    use crate::memory::MemoryStorage;
+
+    // 读分片 0 时停下, 直到测试放行
+    #[derive(Debug)]
+    struct SlowStorage {
+        inner: MemoryStorage,
+        entered: Mutex<mpsc::Sender<()>>,
+        release: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl Storage for SlowStorage {
+        fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>, StorageError> {
+            if index == 0 {
+                self.entered.lock().unwrap().send(()).unwrap();
+                self.release.lock().unwrap().recv().unwrap();
+            }
+            self.inner.read_block(index, begin, length)
+        }
+
+        fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> Result<(), StorageError> {
+            self.inner.write_block(index, begin, data)
+        }
+
+        fn flush(&self) -> Result<(), StorageError> {
+            self.inner.flush()
+        }
+
+        fn verify_piece(&self, index: u32) -> Result<bool, StorageError> {
+            self.inner.verify_piece(index)
+        }
+
+        fn move_to(&self, root: &Path) -> Result<(), StorageError> {
+            self.inner.move_to(root)
+        }
+
+        fn delete(&self) -> Result<(), StorageError> {
+            self.inner.delete()
+        }
+    }
+
+    fn cached(config: CacheConfig) -> (Arc<MemoryStorage>, CachedStorage) {
+        let inner = Arc::new(MemoryStorage::new(&info()).unwrap());
+        let cached = CachedStorage::new(&info(), inner.clone(), config).unwrap();
+        (inner, cached)
+    }
+
+    #[test]
+    fn test_write_coalescing() {
+        let (inner, cache) = cached(CacheConfig::default());
+        cache.write_block(1, 0, &piece(1)[..4]).unwrap();
+        cache.write_block(1, 6, &piece(1)[6..]).unwrap();
+        // 还没写到下层, 但可以从缓存读
+        assert_eq!(inner.read_block(1, 0, 4).unwrap(), vec![0; 4]);
+        assert_eq!(cache.read_block(1, 6, 2).unwrap(), &piece(1)[6..8]);
+        assert_eq!(cache.stats().write_bytes, 10);
+
+        cache.write_block(1, 3, &piece(1)[3..7]).unwrap();
+        assert!(inner.verify_piece(1).unwrap());
+        let stats = cache.stats();
+        assert_eq!(stats.blocks_cached, 3);
+        assert_eq!(stats.pieces_written, 1);
+        assert_eq!(stats.partial_writes, 0);
+        assert_eq!(stats.write_bytes, 0);
+
+        cache.write_block(2, 0, &piece(2)[..2]).unwrap();
+        assert!(!cache.verify_piece(2).unwrap());
+        assert_eq!(inner.read_block(2, 0, 2).unwrap(), &piece(2)[..2]);
+        assert_eq!(cache.stats().partial_writes, 1);
+    }
+
+    #[test]
+    fn test_write_limit() {
+        let (inner, cache) = cached(CacheConfig::default().with_write_limit(15));
+        cache.write_block(0, 0, &piece(0)[..5]).unwrap();
+        cache.write_block(1, 0, &piece(1)[..5]).unwrap();
+        // 超过限制, 最早的分片 0 被写下去
+        assert_eq!(inner.read_block(0, 0, 5).unwrap(), &piece(0)[..5]);
+        assert_eq!(inner.read_block(1, 0, 5).unwrap(), vec![0; 5]);
+        assert_eq!(cache.stats().write_bytes, 10);
+
+        // 读取只有一部分在缓存中的范围
+        assert_eq!(cache.read_block(1, 3, 4).unwrap(), vec![13, 14, 0, 0]);
+        cache.flush().unwrap();
+        assert_eq!(cache.stats().write_bytes, 0);
+    }
+
+    #[test]
+    fn test_read_cache() {
+        let (inner, cache) = cached(CacheConfig::default().with_read_limit(20));
+        for index in 0..3 {
+            inner.write_block(index, 0, &piece(index)).unwrap();
+        }
+        assert_eq!(cache.read_block(0, 0, 4).unwrap(), &piece(0)[..4]);
+        assert_eq!(cache.read_block(0, 4, 6).unwrap(), &piece(0)[4..]);
+        // 分片 1 是预读的
+        assert_eq!(cache.read_block(1, 0, 10).unwrap(), piece(1));
+        let stats = cache.stats();
+        assert_eq!((stats.read_hits, stats.read_misses), (2, 1));
+        assert!((stats.hit_rate() - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(stats.read_bytes, 20);
+
+        // 写入使缓存失效
+        cache.write_block(1, 0, &[0; 10]).unwrap();
+        assert_eq!(cache.read_block(1, 0, 2).unwrap(), vec![0; 2]);
+        // 超过限制时去掉最久没用的分片 0
+        assert!(cache.stats().read_bytes <= 20);
+        cache.read_block(0, 0, 1).unwrap();
+        assert_eq!(cache.stats().read_misses, 3);
+    }
+
+    #[test]
+    fn test_read_limit_smaller_than_pieces() {
+        // 分片比读缓存还大时不缓存, 也不能卡住
+        let config = CacheConfig::default().with_read_limit(5).with_read_ahead(0);
+        let (inner, cache) = cached(config);
+        for index in 0..3 {
+            inner.write_block(index, 0, &piece(index)).unwrap();
+        }
+        assert_eq!(cache.read_block(0, 0, 10).unwrap(), piece(0));
+        assert_eq!(cache.read_block(1, 0, 10).unwrap(), piece(1));
+        assert_eq!(cache.stats().read_bytes, 0);
+
+        // 预读挤掉了这次读的分片
+        let config = CacheConfig::default().with_read_limit(10);
+        let (inner, cache) = cached(config);
+        for index in 0..3 {
+            inner.write_block(index, 0, &piece(index)).unwrap();
+        }
+        assert_eq!(cache.read_block(0, 0, 10).unwrap(), piece(0));
+        assert_eq!(cache.read_block(1, 0, 10).unwrap(), piece(1));
+        assert_eq!(cache.read_block(0, 0, 10).unwrap(), piece(0));
+        assert!(cache.stats().read_bytes <= 10);
+    }
+
+    #[test]
+    fn test_write_during_inner_read() {
+        let (entered_tx, entered) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel();
+        let inner = Arc::new(SlowStorage {
+            inner: MemoryStorage::new(&info()).unwrap(),
+            entered: Mutex::new(entered_tx),
+            release: Mutex::new(release_rx),
+        });
+        let cache = Arc::new(CachedStorage::new(&info(), inner, CacheConfig::default()).unwrap());
+
+        let reader = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.read_block(0, 0, 10).unwrap())
+        };
+        entered.recv().unwrap();
+        // 读下层时不持有锁, 写入不用等读完成
+        let (done_tx, done) = mpsc::channel();
+        let writer = {
+            let cache = cache.clone();
+            thread::spawn(move || {
+                cache.write_block(0, 0, &piece(0)).unwrap();
+                done_tx.send(()).unwrap();
+            })
+        };
+        let written = done.recv_timeout(Duration::from_secs(5));
+        release.send(()).unwrap();
+        assert!(written.is_ok());
+        writer.join().unwrap();
+        reader.join().unwrap();
+
+        // 读到的旧内容没有放进缓存
+        assert_eq!(cache.stats().read_bytes, 0);
+        release.send(()).unwrap();
+        assert_eq!(cache.read_block(0, 0, 10).unwrap(), piece(0));
+    }
+
+    #[test]
+    fn test_read_while_writing() {
+        let (_, cache) = cached(CacheConfig::default());
+        cache.write_block(1, 0, &piece(1)[..4]).unwrap();
+        // 读不重叠的范围不能把下层的旧内容放进读缓存
+        assert_eq!(cache.read_block(1, 6, 4).unwrap(), vec![0; 4]);
+        assert_eq!(cache.stats().read_bytes, 0);
+        assert_eq!(cache.read_block(1, 0, 4).unwrap(), &piece(1)[..4]);
+
+        // 写下去之后读缓存里也不会留着旧的分片
+        assert_eq!(cache.read_block(2, 0, 2).unwrap(), vec![0; 2]);
+        cache.write_block(2, 0, &piece(2)[..2]).unwrap();
+        cache.flush().unwrap();
+        assert_eq!(cache.read_block(2, 0, 2).unwrap(), &piece(2)[..2]);
+        assert_eq!(cache.read_block(1, 0, 4).unwrap(), &piece(1)[..4]);
+    }
+}
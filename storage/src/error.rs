@@ -0,0 +1,17 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error(transparent)]
+    IOError(#[from] ::std::io::Error),
+    #[error("invalid path: {0}")]
+    InvalidPath(::std::borrow::Cow<'static, str>),
+    #[error("invalid block: {0}")]
+    InvalidBlock(::std::borrow::Cow<'static, str>),
+    #[error("unsupported: {0}")]
+    Unsupported(::std::borrow::Cow<'static, str>),
+    #[error("the data was discarded")]
+    Discarded,
+    #[error("the storage IO threads have stopped")]
+    PoolClosed,
+}
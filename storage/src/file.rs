@@ -0,0 +1,474 @@
+use std::borrow::Cow;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::UNIX_EPOCH;
+
+use torrent::Info;
+
+use crate::backend::Storage;
+use crate::error::StorageError;
+use crate::layout::{FileEntry, FileState, Layout, Span};
+
+/// How files are created before downloading.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Allocation {
+    /// Files are set to their full length without writing, which leaves them
+    /// sparse on file systems that support it.
+    #[default]
+    Sparse,
+    /// Files are filled with zeros, so the space is reserved up front and
+    /// pieces are not fragmented.
+    Full,
+}
+
+/// The content of a torrent in its files under a download directory.
+///
+/// Blocks are spread over the files they span. Data of skipped files that
+/// shares a piece with wanted files goes to a partial-piece file under
+/// `.<name>.parts` instead, so skipped files are never created.
+#[derive(Debug)]
+pub struct FileStorage {
+    root: RwLock<PathBuf>,
+    layout: Layout,
+    allocation: Allocation,
+    skipped: Mutex<Vec<bool>>,
+}
+
+impl FileStorage {
+    /// The torrent's files under `root`, with paths sanitized.
+    pub fn new(info: &Info, root: impl Into<PathBuf>) -> Result<Self, StorageError> {
+        let layout = Layout::new(info)?;
+        let skipped = vec![false; layout.files.len()];
+        Ok(Self {
+            root: RwLock::new(root.into()),
+            layout,
+            allocation: Allocation::default(),
+            skipped: Mutex::new(skipped),
+        })
+    }
+
+    pub fn with_allocation(mut self, allocation: Allocation) -> Self {
+        self.allocation = allocation;
+        self
+    }
+
+    /// Skips the files set in `skipped`, in the order of the torrent's files.
+    pub fn with_skipped_files(mut self, skipped: &[bool]) -> Self {
+        let current = self.skipped.get_mut().unwrap();
+        for (current, &skipped) in current.iter_mut().zip(skipped) {
+            *current = skipped;
+        }
+        self
+    }
+
+    pub fn root(&self) -> PathBuf {
+        self.root.read().unwrap().clone()
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.layout.files
+    }
+
+    pub fn is_skipped(&self, file: usize) -> bool {
+        self.skipped.lock().unwrap()[file]
+    }
+
+    fn path(&self, root: &Path, file: usize) -> PathBuf {
+        root.join(&self.layout.files[file].path)
+    }
+
+    fn parts_dir(&self, root: &Path) -> PathBuf {
+        root.join(format!(".{}.parts", self.layout.name().display()))
+    }
+
+    fn part_path(&self, root: &Path, index: u32) -> PathBuf {
+        self.parts_dir(root).join(format!("{}.part", index))
+    }
+}
+
+impl Storage for FileStorage {
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>, StorageError> {
+        let len = length as usize;
+        let offset = self.layout.block_offset(index, begin, len)?;
+        let root = self.root.read().unwrap();
+        let skipped = self.skipped.lock().unwrap().clone();
+        let part = self.part_path(&root, index);
+        let mut buf = vec![0; len];
+        for span in self.layout.spans(offset, len) {
+            let bytes = &mut buf[span.buf_offset..span.buf_offset + span.len];
+            if skipped[span.file] && part.exists() {
+                read_at(&part, begin as u64 + span.buf_offset as u64, bytes)?;
+            } else {
+                read_at(&self.path(&root, span.file), span.file_offset, bytes)?;
+            }
+        }
+        Ok(buf)
+    }
+
+    fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> Result<(), StorageError> {
+        let offset = self.layout.block_offset(index, begin, data.len())?;
+        let root = self.root.read().unwrap();
+        let skipped = self.skipped.lock().unwrap().clone();
+        for span in self.layout.spans(offset, data.len()) {
+            let bytes = &data[span.buf_offset..span.buf_offset + span.len];
+            if skipped[span.file] {
+                // 在分片文件中的位置就是在分片中的位置
+                let part = self.part_path(&root, index);
+                write_at(&part, begin as u64 + span.buf_offset as u64, bytes)?;
+            } else {
+                write_at(&self.path(&root, span.file), span.file_offset, bytes)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        let root = self.root.read().unwrap();
+        for n in 0..self.layout.files.len() {
+            let path = self.path(&root, n);
+            if path.exists() {
+                OpenOptions::new().write(true).open(path)?.sync_all()?;
+            }
+        }
+        // 跳过的文件的数据在分片文件中, 同样要落盘
+        let parts = match fs::read_dir(self.parts_dir(&root)) {
+            Ok(parts) => parts,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for part in parts {
+            OpenOptions::new()
+                .write(true)
+                .open(part?.path())?
+                .sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn verify_piece(&self, index: u32) -> Result<bool, StorageError> {
+        let size = self.layout.checked_piece_size(index)?;
+        let data = self.read_block(index, 0, size)?;
+        Ok(self.layout.verify(index, &data))
+    }
+
+    fn move_to(&self, to: &Path) -> Result<(), StorageError> {
+        let mut root = self.root.write().unwrap();
+        for n in 0..self.layout.files.len() {
+            move_file(&self.path(&root, n), &self.path(to, n))?;
+        }
+        move_file(&self.parts_dir(&root), &self.parts_dir(to))?;
+        remove_empty_dirs(&root.join(self.layout.name()));
+        *root = to.to_owned();
+        Ok(())
+    }
+
+    fn delete(&self) -> Result<(), StorageError> {
+        let root = self.root.read().unwrap();
+        for n in 0..self.layout.files.len() {
+            ignore_not_found(fs::remove_file(self.path(&root, n)))?;
+        }
+        ignore_not_found(fs::remove_dir_all(self.parts_dir(&root)))?;
+        remove_empty_dirs(&root.join(self.layout.name()));
+        Ok(())
+    }
+
+    /// Creates the directories and the files that are not skipped.
+    fn allocate(&self) -> Result<(), StorageError> {
+        let root = self.root.read().unwrap();
+        let skipped = self.skipped.lock().unwrap().clone();
+        for (n, file) in self.layout.files.iter().enumerate() {
+            if skipped[n] {
+                continue;
+            }
+            let mut handle = open(&self.path(&root, n))?;
+            let len = handle.metadata()?.len();
+            if self.allocation == Allocation::Full && len < file.length {
+                handle.seek(SeekFrom::Start(len))?;
+                let zeros = vec![0; 1 << 16];
+                let mut left = file.length - len;
+                while left > 0 {
+                    let n = left.min(zeros.len() as u64) as usize;
+                    handle.write_all(&zeros[..n])?;
+                    left -= n as u64;
+                }
+            } else {
+                handle.set_len(file.length)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn file_states(&self) -> Result<Vec<Option<FileState>>, StorageError> {
+        file_states(&self.root.read().unwrap(), &self.layout.files)
+    }
+
+    /// When a file is wanted again, its data kept in partial-piece files is
+    /// moved into it.
+    fn set_skipped(&self, file: usize, skipped: bool) -> Result<(), StorageError> {
+        let root = self.root.read().unwrap();
+        let mut flags = self.skipped.lock().unwrap();
+        if file >= flags.len() {
+            return Err(StorageError::InvalidPath(Cow::Owned(format!(
+                "the torrent has no file {}",
+                file
+            ))));
+        }
+        if skipped {
+            flags[file] = true;
+            return Ok(());
+        }
+        // 把分片文件中属于这个文件的部分写回去, 全部写完才不再跳过这个文件
+        for index in self.layout.pieces_of(file) {
+            let part = self.part_path(&root, index);
+            let part_len = match fs::metadata(&part) {
+                Ok(metadata) => metadata.len() as usize,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let size = self.layout.piece_size(index).unwrap() as usize;
+            let spans: Vec<Span> = self
+                .layout
+                .spans(index as u64 * self.layout.piece_length, size);
+            for span in spans.iter().filter(|span| span.file == file) {
+                // 分片文件只写到收到的最后一个块为止
+                let len = part_len.saturating_sub(span.buf_offset).min(span.len);
+                if len == 0 {
+                    continue;
+                }
+                let mut bytes = vec![0; len];
+                read_at(&part, span.buf_offset as u64, &mut bytes)?;
+                write_at(&self.path(&root, file), span.file_offset, &bytes)?;
+            }
+            if !spans
+                .iter()
+                .any(|span| span.file != file && flags[span.file])
+            {
+                fs::remove_file(&part)?;
+            }
+        }
+        flags[file] = false;
+        Ok(())
+    }
+}
+
+pub(crate) fn open(path: &Path) -> io::Result<fs::File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+fn write_at(path: &Path, offset: u64, bytes: &[u8]) -> io::Result<()> {
+    let mut file = open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(bytes)
+}
+
+fn read_at(path: &Path, offset: u64, bytes: &mut [u8]) -> io::Result<()> {
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(bytes)
+}
+
+pub(crate) fn file_states(
+    root: &Path,
+    files: &[FileEntry],
+) -> Result<Vec<Option<FileState>>, StorageError> {
+    let mut states = Vec::with_capacity(files.len());
+    for file in files {
+        let metadata = match fs::metadata(root.join(&file.path)) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                states.push(None);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() as i64);
+        states.push(Some(FileState {
+            length: metadata.len(),
+            mtime,
+        }));
+    }
+    Ok(states)
+}
+
+pub(crate) fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Moves a file or directory, copying it when it is on another file system.
+pub(crate) fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if !from.exists() || from == to {
+        return Ok(());
+    }
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    if from.is_dir() {
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            move_file(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        fs::remove_dir(from)
+    } else {
+        fs::copy(from, to)?;
+        fs::remove_file(from)
+    }
+}
+
+/// Removes `dir` and the directories under it if they hold no files.
+pub(crate) fn remove_empty_dirs(dir: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if entry.path().is_dir() {
+            remove_empty_dirs(&entry.path());
+        }
+    }
+    // 目录不为空时删除会失败, 忽略即可
+    let _ = fs::remove_dir(dir);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::{content, info, piece, temp_dir};
+
+    #[test]
+    fn test_write_and_read_across_files() {
+        let root = temp_dir("write");
+        let storage = FileStorage::new(&info(), &root).unwrap();
+        for index in [2, 0, 1] {
+            storage.write_block(index, 0, &piece(index)).unwrap();
+        }
+        assert_eq!(fs::read(root.join("album/a")).unwrap(), &content()[..12]);
+        assert_eq!(
+            fs::read(root.join("album/sub/b")).unwrap(),
+            &content()[12..]
+        );
+
+        let block = storage.read_block(1, 1, 5).unwrap();
+        assert_eq!(block, &content()[11..16]);
+        assert!(matches!(
+            storage.read_block(2, 0, 6),
+            Err(StorageError::InvalidBlock(_))
+        ));
+        assert!(storage.write_block(2, 0, &piece(1)).is_err());
+
+        assert!(storage.verify_piece(1).unwrap());
+        storage.write_block(1, 4, &[0; 2]).unwrap();
+        assert!(!storage.verify_piece(1).unwrap());
+        assert!(storage.verify_piece(3).is_err());
+        storage.flush().unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_allocate() {
+        for allocation in [Allocation::Sparse, Allocation::Full] {
+            let root = temp_dir(&format!("{:?}", allocation));
+            let storage = FileStorage::new(&info(), &root)
+                .unwrap()
+                .with_allocation(allocation)
+                .with_skipped_files(&[false, true]);
+            storage.allocate().unwrap();
+            let a = fs::read(root.join("album/a")).unwrap();
+            assert_eq!(a, vec![0; 12]);
+            assert!(!root.join("album/sub/b").exists());
+            fs::remove_dir_all(root).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_unskip_partly_written_piece() {
+        let root = temp_dir("unskip");
+        let storage = FileStorage::new(&info(), &root)
+            .unwrap()
+            .with_skipped_files(&[false, true]);
+        // 只收到分片 1 的第一个块, 其中 b 的部分只有 2 个字节
+        storage.write_block(1, 0, &piece(1)[..4]).unwrap();
+        storage.set_skipped(1, false).unwrap();
+        assert!(!storage.is_skipped(1));
+        assert!(!root.join(".album.parts/1.part").exists());
+        assert_eq!(
+            fs::read(root.join("album/sub/b")).unwrap(),
+            &content()[12..14]
+        );
+        assert_eq!(storage.read_block(1, 0, 4).unwrap(), &content()[10..14]);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_skipped_files() {
+        let root = temp_dir("skipped");
+        let storage = FileStorage::new(&info(), &root)
+            .unwrap()
+            .with_skipped_files(&[false, true]);
+        assert!(storage.is_skipped(1));
+        storage.write_block(0, 0, &piece(0)).unwrap();
+        storage.write_block(1, 0, &piece(1)[..4]).unwrap();
+        storage.write_block(1, 4, &piece(1)[4..]).unwrap();
+
+        // b 的部分放在分片文件中
+        assert_eq!(fs::read(root.join("album/a")).unwrap(), &content()[..12]);
+        assert!(!root.join("album/sub/b").exists());
+        let part = root.join(".album.parts/1.part");
+        assert_eq!(fs::read(&part).unwrap()[2..], content()[12..20]);
+        assert_eq!(storage.read_block(1, 0, 10).unwrap(), &content()[10..20]);
+        storage.flush().unwrap();
+
+        storage.set_skipped(1, false).unwrap();
+        assert!(!part.exists());
+        assert_eq!(
+            fs::read(root.join("album/sub/b")).unwrap(),
+            &content()[12..20]
+        );
+        assert_eq!(storage.read_block(1, 0, 10).unwrap(), &content()[10..20]);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_move_and_delete() {
+        let from = temp_dir("move-from");
+        let to = temp_dir("move-to");
+        let storage = FileStorage::new(&info(), &from)
+            .unwrap()
+            .with_skipped_files(&[false, true]);
+        storage.write_block(1, 0, &piece(1)).unwrap();
+
+        let states = storage.file_states().unwrap();
+        assert_eq!(states[0].unwrap().length, 12);
+        assert!(states[0].unwrap().mtime > 0);
+        assert_eq!(states[1], None);
+
+        storage.move_to(&to).unwrap();
+        assert_eq!(storage.root(), to);
+        assert!(!from.join("album").exists());
+        assert!(!from.join(".album.parts").exists());
+        assert!(to.join(".album.parts/1.part").exists());
+        assert_eq!(storage.read_block(1, 0, 10).unwrap(), piece(1));
+
+        storage.delete().unwrap();
+        assert_eq!(fs::read_dir(&to).unwrap().count(), 0);
+        let _ = fs::remove_dir_all(from);
+        fs::remove_dir_all(to).unwrap();
+    }
+}
@@ -0,0 +1,32 @@
+// 测试用的种子: 分片长度 10, 分片 0 = a 的 0..10, 分片 1 = a 的 10..12 + b 的 0..8, 分片 2 = b 的 8..13
+use std::fs;
+use std::path::PathBuf;
+
+use sha1::{Digest, Sha1};
+use torrent::fixture::multiple_file;
+use torrent::Info;
+
+pub fn info() -> Info {
+    let mut info = multiple_file("album", 10, [("a", 12), ("sub/b", 13)]);
+    if let Info::MultipleFile(multiple) = &mut info {
+        multiple.pieces = (0..3)
+            .flat_map(|index| Sha1::digest(piece(index)))
+            .collect();
+    }
+    info
+}
+
+pub fn content() -> Vec<u8> {
+    (0..25).collect()
+}
+
+pub fn piece(index: u32) -> Vec<u8> {
+    let begin = index as usize * 10;
+    content()[begin..(begin + 10).min(25)].to_vec()
+}
+
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("storage-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
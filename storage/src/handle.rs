@@ -0,0 +1,129 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use torrent::Info;
+
+use crate::backend::{Backend, Storage};
+use crate::error::StorageError;
+use crate::layout::FileState;
+use crate::pool::IoPool;
+
+/// The storage of one torrent, whose IO runs on an [`IoPool`] so it never
+/// stalls the tokio runtime.
+#[derive(Debug, Clone)]
+pub struct TorrentStorage {
+    storage: Arc<dyn Storage>,
+    pool: IoPool,
+}
+
+impl TorrentStorage {
+    pub fn new(storage: Arc<dyn Storage>, pool: IoPool) -> Self {
+        Self { storage, pool }
+    }
+
+    /// Opens the torrent's storage with the chosen backend.
+    pub fn open(
+        info: &Info,
+        root: impl Into<PathBuf>,
+        backend: Backend,
+        pool: IoPool,
+    ) -> Result<Self, StorageError> {
+        Ok(Self::new(backend.open(info, root)?, pool))
+    }
+
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    pub async fn read_block(
+        &self,
+        index: u32,
+        begin: u32,
+        length: u32,
+    ) -> Result<Vec<u8>, StorageError> {
+        let storage = self.storage.clone();
+        self.pool
+            .run(move || storage.read_block(index, begin, length))
+            .await
+    }
+
+    pub async fn write_block(
+        &self,
+        index: u32,
+        begin: u32,
+        data: Vec<u8>,
+    ) -> Result<(), StorageError> {
+        let storage = self.storage.clone();
+        self.pool
+            .run(move || storage.write_block(index, begin, &data))
+            .await
+    }
+
+    pub async fn flush(&self) -> Result<(), StorageError> {
+        let storage = self.storage.clone();
+        self.pool.run(move || storage.flush()).await
+    }
+
+    pub async fn verify_piece(&self, index: u32) -> Result<bool, StorageError> {
+        let storage = self.storage.clone();
+        self.pool.run(move || storage.verify_piece(index)).await
+    }
+
+    pub async fn move_to(&self, root: impl Into<PathBuf>) -> Result<(), StorageError> {
+        let storage = self.storage.clone();
+        let root = root.into();
+        self.pool.run(move || storage.move_to(&root)).await
+    }
+
+    pub async fn delete(&self) -> Result<(), StorageError> {
+        let storage = self.storage.clone();
+        self.pool.run(move || storage.delete()).await
+    }
+
+    pub async fn allocate(&self) -> Result<(), StorageError> {
+        let storage = self.storage.clone();
+        self.pool.run(move || storage.allocate()).await
+    }
+
+    pub async fn file_states(&self) -> Result<Vec<Option<FileState>>, StorageError> {
+        let storage = self.storage.clone();
+        self.pool.run(move || storage.file_states()).await
+    }
+
+    pub async fn set_skipped(&self, file: usize, skipped: bool) -> Result<(), StorageError> {
+        let storage = self.storage.clone();
+        self.pool
+            .run(move || storage.set_skipped(file, skipped))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::fixture::{info, piece, temp_dir};
+
+    #[tokio::test]
+    async fn test_backends() {
+        let root = temp_dir("backends");
+        for backend in [Backend::Memory, Backend::default(), Backend::Mmap] {
+            let storage = TorrentStorage::open(&info(), &root, backend, IoPool::new(2)).unwrap();
+            storage.allocate().await.unwrap();
+            for index in 0..3 {
+                storage.write_block(index, 0, piece(index)).await.unwrap();
+            }
+            storage.flush().await.unwrap();
+            assert!(storage.verify_piece(2).await.unwrap(), "{:?}", backend);
+            assert_eq!(storage.read_block(0, 0, 10).await.unwrap(), piece(0));
+            storage.delete().await.unwrap();
+        }
+
+        let storage =
+            TorrentStorage::open(&info(), &root, Backend::Discard, IoPool::new(1)).unwrap();
+        storage.write_block(0, 0, piece(0)).await.unwrap();
+        assert!(!storage.verify_piece(0).await.unwrap());
+        let _ = fs::remove_dir_all(root);
+    }
+}
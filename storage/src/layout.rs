@@ -0,0 +1,271 @@
+use std::borrow::Cow;
+use std::path::{Component, Path, PathBuf};
+
+use sha1::{Digest, Sha1};
+use torrent::{Info, Sha1Hash};
+
+use crate::error::StorageError;
+
+// Windows 上不能作为文件名的名字
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Makes a path from a torrent safe to create under the download directory.
+///
+/// Root, `.` and `..` components are dropped so the path cannot escape the
+/// directory, and characters or names that are not allowed in file names on
+/// common platforms are replaced.
+pub fn sanitize_path(path: &Path) -> Result<PathBuf, StorageError> {
+    let sanitized: PathBuf = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(sanitize_name(&name.to_string_lossy())),
+            _ => None,
+        })
+        .collect();
+    if sanitized.as_os_str().is_empty() {
+        return Err(StorageError::InvalidPath(Cow::Owned(format!(
+            "`{}` has no file name",
+            path.display()
+        ))));
+    }
+    Ok(sanitized)
+}
+
+fn sanitize_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_control() || r#"/\:*?"<>|"#.contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    // Windows 会去掉结尾的点和空格
+    let name = name.trim_end_matches(['.', ' ']);
+    if name.is_empty() {
+        return "_".to_owned();
+    }
+    let stem = name.split('.').next().unwrap_or(name);
+    if RESERVED_NAMES.contains(&stem.to_ascii_uppercase().as_str()) {
+        return format!("_{}", name);
+    }
+    name.to_owned()
+}
+
+/// A file of the torrent on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// The sanitized path, relative to the download directory.
+    pub path: PathBuf,
+    /// Where the file starts within the content.
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// The size and modification time of a file on disk, to tell whether it
+/// changed since it was last seen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileState {
+    pub length: u64,
+    /// Seconds since the Unix epoch.
+    pub mtime: i64,
+}
+
+// 一段连续的字节在某个文件中的位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Span {
+    pub file: usize,
+    pub file_offset: u64,
+    /// 在读写的缓冲区中的位置
+    pub buf_offset: usize,
+    pub len: usize,
+}
+
+/// Where the pieces of a torrent are on disk.
+#[derive(Debug, Clone)]
+pub(crate) struct Layout {
+    pub files: Vec<FileEntry>,
+    pub piece_length: u64,
+    pub length: u64,
+    pub piece_count: usize,
+    pub hashes: Vec<Sha1Hash>,
+}
+
+impl Layout {
+    pub fn new(info: &Info) -> Result<Self, StorageError> {
+        let files = match info {
+            Info::SingleFile(single) => vec![FileEntry {
+                path: sanitize_path(Path::new(&single.name))?,
+                offset: 0,
+                length: single.length as u64,
+            }],
+            Info::MultipleFile(multiple) => {
+                let dir = sanitize_path(Path::new(&multiple.name))?;
+                let mut files = Vec::with_capacity(multiple.files.len());
+                for (file, range) in multiple.files.iter().zip(info.file_ranges()) {
+                    files.push(FileEntry {
+                        path: dir.join(sanitize_path(&file.path)?),
+                        offset: range.start as u64,
+                        length: file.length as u64,
+                    });
+                }
+                files
+            }
+        };
+        Ok(Self {
+            files,
+            piece_length: info.piece_length() as u64,
+            length: info.length() as u64,
+            piece_count: info.piece_count(),
+            hashes: (0..info.piece_count())
+                .filter_map(|index| info.piece_hash(index))
+                .collect(),
+        })
+    }
+
+    pub fn piece_size(&self, index: u32) -> Option<u64> {
+        if index as usize >= self.piece_count {
+            return None;
+        }
+        let begin = index as u64 * self.piece_length;
+        Some(self.piece_length.min(self.length - begin))
+    }
+
+    /// The size of the piece, or an error if there is no such piece.
+    pub fn checked_piece_size(&self, index: u32) -> Result<u32, StorageError> {
+        self.piece_size(index)
+            .map(|size| size as u32)
+            .ok_or_else(|| StorageError::InvalidBlock(Cow::Owned(format!("no piece {}", index))))
+    }
+
+    /// The directory of a multi-file torrent, or the file of a single-file one.
+    pub fn name(&self) -> &Path {
+        Path::new(self.files[0].path.components().next().unwrap().as_os_str())
+    }
+
+    /// Whether `data` is the piece, by its hash.
+    pub fn verify(&self, index: u32, data: &[u8]) -> bool {
+        self.hashes
+            .get(index as usize)
+            .is_some_and(|hash| Sha1::digest(data)[..] == hash[..])
+    }
+
+    /// The byte range `begin..begin + len` of the piece, checked to be within it.
+    pub fn block_offset(&self, index: u32, begin: u32, len: usize) -> Result<u64, StorageError> {
+        let size = self.checked_piece_size(index)? as u64;
+        if begin as u64 + len as u64 > size {
+            return Err(StorageError::InvalidBlock(Cow::Owned(format!(
+                "{} bytes at {} are past the end of piece {}",
+                len, begin, index
+            ))));
+        }
+        Ok(index as u64 * self.piece_length + begin as u64)
+    }
+
+    /// The parts of the files holding `len` bytes of the content at `offset`.
+    pub fn spans(&self, offset: u64, len: usize) -> Vec<Span> {
+        let end = offset + len as u64;
+        self.files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| {
+                file.length > 0 && file.offset < end && offset < file.offset + file.length
+            })
+            .map(|(n, file)| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.offset + file.length);
+                Span {
+                    file: n,
+                    file_offset: start - file.offset,
+                    buf_offset: (start - offset) as usize,
+                    len: (stop - start) as usize,
+                }
+            })
+            .collect()
+    }
+
+    /// The pieces overlapping the file.
+    pub fn pieces_of(&self, file: usize) -> std::ops::Range<u32> {
+        let file = &self.files[file];
+        if file.length == 0 {
+            return 0..0;
+        }
+        let first = file.offset / self.piece_length;
+        let last = (file.offset + file.length - 1) / self.piece_length;
+        first as u32..last as u32 + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use torrent::fixture::multiple_file;
+
+    use super::*;
+
+    #[test]
+    fn test_sanitize_path() {
+        let cases = [
+            ("a/b/c.txt", "a/b/c.txt"),
+            ("/etc/passwd", "etc/passwd"),
+            ("../../x", "x"),
+            ("a/./b", "a/b"),
+            ("what?.mkv", "what_.mkv"),
+            ("dots...", "dots"),
+            ("con.txt", "_con.txt"),
+            ("line\nbreak", "line_break"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(
+                sanitize_path(Path::new(path)).unwrap(),
+                PathBuf::from(expected)
+            );
+        }
+        assert!(sanitize_path(Path::new("../..")).is_err());
+        assert!(sanitize_path(Path::new("")).is_err());
+    }
+
+    fn info() -> Info {
+        multiple_file("album", 10, [("1.mp3", 12), ("empty", 0), ("../2.mp3", 13)])
+    }
+
+    #[test]
+    fn test_layout() {
+        let layout = Layout::new(&info()).unwrap();
+        assert_eq!(layout.files[2].path, PathBuf::from("album/2.mp3"));
+        assert_eq!(layout.files[2].offset, 12);
+        assert_eq!(layout.piece_size(2), Some(5));
+        assert_eq!(layout.piece_size(3), None);
+
+        // 分片 1 跨越文件 0 和 2, 空文件不出现
+        let spans = layout.spans(10, 10);
+        assert_eq!(
+            spans,
+            vec![
+                Span {
+                    file: 0,
+                    file_offset: 10,
+                    buf_offset: 0,
+                    len: 2
+                },
+                Span {
+                    file: 2,
+                    file_offset: 0,
+                    buf_offset: 2,
+                    len: 8
+                }
+            ]
+        );
+        assert_eq!(layout.pieces_of(0), 0..2);
+        assert_eq!(layout.pieces_of(1), 0..0);
+        assert_eq!(layout.pieces_of(2), 1..3);
+
+        assert_eq!(layout.block_offset(2, 1, 4).unwrap(), 21);
+        assert!(layout.block_offset(2, 1, 5).is_err());
+        assert!(layout.block_offset(3, 0, 1).is_err());
+    }
+}
@@ -0,0 +1,21 @@
+mod backend;
+mod cache;
+pub mod error;
+mod file;
+#[cfg(test)]
+mod fixture;
+mod handle;
+mod layout;
+mod memory;
+mod mmap;
+mod pool;
+
+pub use crate::backend::{Backend, Storage};
+pub use crate::cache::{CacheConfig, CacheStats, CachedStorage};
+pub use crate::error::StorageError;
+pub use crate::file::{Allocation, FileStorage};
+pub use crate::handle::TorrentStorage;
+pub use crate::layout::{sanitize_path, FileEntry, FileState};
+pub use crate::memory::{DiscardStorage, MemoryStorage};
+pub use crate::mmap::MmapStorage;
+pub use crate::pool::IoPool;
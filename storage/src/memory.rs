@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use torrent::Info;
+
+use crate::backend::Storage;
+use crate::error::StorageError;
+use crate::layout::Layout;
+
+/// The content of a torrent in memory, e.g. for tests.
+///
+/// Pieces are allocated when first written, and bytes never written read as
+/// zeros. Moving does nothing, and deleting drops everything.
+#[derive(Debug)]
+pub struct MemoryStorage {
+    layout: Layout,
+    pieces: Mutex<HashMap<u32, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new(info: &Info) -> Result<Self, StorageError> {
+        Ok(Self {
+            layout: Layout::new(info)?,
+            pieces: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>, StorageError> {
+        self.layout.block_offset(index, begin, length as usize)?;
+        let range = begin as usize..(begin + length) as usize;
+        Ok(match self.pieces.lock().unwrap().get(&index) {
+            Some(piece) => piece[range].to_vec(),
+            None => vec![0; range.len()],
+        })
+    }
+
+    fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> Result<(), StorageError> {
+        self.layout.block_offset(index, begin, data.len())?;
+        let size = self.layout.checked_piece_size(index)? as usize;
+        let mut pieces = self.pieces.lock().unwrap();
+        let piece = pieces.entry(index).or_insert_with(|| vec![0; size]);
+        piece[begin as usize..begin as usize + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn verify_piece(&self, index: u32) -> Result<bool, StorageError> {
+        let size = self.layout.checked_piece_size(index)?;
+        let data = self.read_block(index, 0, size)?;
+        Ok(self.layout.verify(index, &data))
+    }
+
+    fn move_to(&self, _root: &Path) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn delete(&self) -> Result<(), StorageError> {
+        self.pieces.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+/// A sink that drops everything written, e.g. to benchmark downloading.
+///
+/// Blocks are still checked to be within the torrent, but reading any of them
+/// fails with [`StorageError::Discarded`] and no piece ever verifies.
+#[derive(Debug)]
+pub struct DiscardStorage {
+    layout: Layout,
+}
+
+impl DiscardStorage {
+    pub fn new(info: &Info) -> Result<Self, StorageError> {
+        Ok(Self {
+            layout: Layout::new(info)?,
+        })
+    }
+}
+
+impl Storage for DiscardStorage {
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>, StorageError> {
+        self.layout.block_offset(index, begin, length as usize)?;
+        Err(StorageError::Discarded)
+    }
+
+    fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> Result<(), StorageError> {
+        self.layout.block_offset(index, begin, data.len())?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn verify_piece(&self, index: u32) -> Result<bool, StorageError> {
+        self.layout.checked_piece_size(index)?;
+        Ok(false)
+    }
+
+    fn move_to(&self, _root: &Path) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn delete(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::{info, piece};
+
+    #[test]
+    fn test_memory() {
+        let storage = MemoryStorage::new(&info()).unwrap();
+        assert_eq!(storage.read_block(1, 0, 4).unwrap(), vec![0; 4]);
+        assert!(!storage.verify_piece(1).unwrap());
+
+        storage.write_block(1, 4, &piece(1)[4..]).unwrap();
+        storage.write_block(1, 0, &piece(1)[..4]).unwrap();
+        assert!(storage.verify_piece(1).unwrap());
+        assert_eq!(storage.read_block(1, 2, 3).unwrap(), &piece(1)[2..5]);
+        assert!(storage.write_block(2, 4, &[0; 2]).is_err());
+
+        storage.delete().unwrap();
+        assert!(!storage.verify_piece(1).unwrap());
+    }
+
+    #[test]
+    fn test_discard() {
+        let storage = DiscardStorage::new(&info()).unwrap();
+        storage.write_block(0, 0, &piece(0)).unwrap();
+        assert!(storage.write_block(3, 0, &piece(0)).is_err());
+        assert!(matches!(
+            storage.read_block(0, 0, 10),
+            Err(StorageError::Discarded)
+        ));
+        assert!(!storage.verify_piece(0).unwrap());
+    }
+}
@@ -0,0 +1,208 @@
+use std::borrow::Cow;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+use memmap2::MmapMut;
+use torrent::Info;
+
+use crate::backend::Storage;
+use crate::error::StorageError;
+use crate::file::{file_states, ignore_not_found, move_file, open, remove_empty_dirs};
+use crate::layout::{FileEntry, FileState, Layout};
+
+/// The content of a torrent in its files, mapped into memory.
+///
+/// Files are created at their full length and mapped when first used, so
+/// reads and writes are plain memory copies and the kernel does the IO.
+/// Skipping files is not supported.
+#[derive(Debug)]
+pub struct MmapStorage {
+    root: RwLock<PathBuf>,
+    layout: Layout,
+    maps: Vec<Mutex<Option<MmapMut>>>,
+}
+
+impl MmapStorage {
+    /// The torrent's files under `root`, with paths sanitized.
+    pub fn new(info: &Info, root: impl Into<PathBuf>) -> Result<Self, StorageError> {
+        let layout = Layout::new(info)?;
+        let maps = layout.files.iter().map(|_| Mutex::new(None)).collect();
+        Ok(Self {
+            root: RwLock::new(root.into()),
+            layout,
+            maps,
+        })
+    }
+
+    pub fn root(&self) -> PathBuf {
+        self.root.read().unwrap().clone()
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.layout.files
+    }
+
+    // 在文件的映射上执行 f, 必要时先创建文件并映射
+    fn with_map<T>(
+        &self,
+        root: &Path,
+        file: usize,
+        f: impl FnOnce(&mut MmapMut) -> T,
+    ) -> Result<T, StorageError> {
+        let mut map = self.maps[file].lock().unwrap();
+        if map.is_none() {
+            let handle = open(&root.join(&self.layout.files[file].path))?;
+            if handle.metadata()?.len() != self.layout.files[file].length {
+                handle.set_len(self.layout.files[file].length)?;
+            }
+            // SAFETY: 文件只通过这个映射读写, 不会在映射期间被截断
+            *map = Some(unsafe { MmapMut::map_mut(&handle)? });
+        }
+        Ok(f(map.as_mut().unwrap()))
+    }
+
+    // 解除所有映射, 以便移动或删除文件
+    fn unmap(&self) -> Result<(), StorageError> {
+        for map in &self.maps {
+            if let Some(map) = map.lock().unwrap().take() {
+                map.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for MmapStorage {
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>, StorageError> {
+        let len = length as usize;
+        let offset = self.layout.block_offset(index, begin, len)?;
+        let root = self.root.read().unwrap();
+        let mut buf = vec![0; len];
+        for span in self.layout.spans(offset, len) {
+            let start = span.file_offset as usize;
+            self.with_map(&root, span.file, |map| {
+                buf[span.buf_offset..span.buf_offset + span.len]
+                    .copy_from_slice(&map[start..start + span.len])
+            })?;
+        }
+        Ok(buf)
+    }
+
+    fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> Result<(), StorageError> {
+        let offset = self.layout.block_offset(index, begin, data.len())?;
+        let root = self.root.read().unwrap();
+        for span in self.layout.spans(offset, data.len()) {
+            let start = span.file_offset as usize;
+            self.with_map(&root, span.file, |map| {
+                map[start..start + span.len]
+                    .copy_from_slice(&data[span.buf_offset..span.buf_offset + span.len])
+            })?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        for map in &self.maps {
+            if let Some(map) = map.lock().unwrap().as_ref() {
+                map.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn verify_piece(&self, index: u32) -> Result<bool, StorageError> {
+        let size = self.layout.checked_piece_size(index)?;
+        let data = self.read_block(index, 0, size)?;
+        Ok(self.layout.verify(index, &data))
+    }
+
+    fn move_to(&self, to: &Path) -> Result<(), StorageError> {
+        let mut root = self.root.write().unwrap();
+        self.unmap()?;
+        for file in &self.layout.files {
+            move_file(&root.join(&file.path), &to.join(&file.path))?;
+        }
+        remove_empty_dirs(&root.join(self.layout.name()));
+        *root = to.to_owned();
+        Ok(())
+    }
+
+    fn delete(&self) -> Result<(), StorageError> {
+        let root = self.root.read().unwrap();
+        self.unmap()?;
+        for file in &self.layout.files {
+            ignore_not_found(fs::remove_file(root.join(&file.path)))?;
+        }
+        remove_empty_dirs(&root.join(self.layout.name()));
+        Ok(())
+    }
+
+    fn file_states(&self) -> Result<Vec<Option<FileState>>, StorageError> {
+        file_states(&self.root.read().unwrap(), &self.layout.files)
+    }
+
+    /// Creates the files at their full length.
+    fn allocate(&self) -> Result<(), StorageError> {
+        let root = self.root.read().unwrap();
+        for file in &self.layout.files {
+            let handle = open(&root.join(&file.path))?;
+            handle.set_len(file.length)?;
+        }
+        Ok(())
+    }
+
+    /// Fails when asked to skip a file, as the files are always created whole.
+    fn set_skipped(&self, file: usize, skipped: bool) -> Result<(), StorageError> {
+        if file >= self.layout.files.len() {
+            return Err(StorageError::InvalidPath(Cow::Owned(format!(
+                "the torrent has no file {}",
+                file
+            ))));
+        }
+        if skipped {
+            return Err(StorageError::Unsupported(Cow::Borrowed(
+                "the mmap backend cannot skip files",
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::{content, info, piece, temp_dir};
+
+    #[test]
+    fn test_mmap() {
+        let from = temp_dir("mmap-from");
+        let to = temp_dir("mmap-to");
+        let storage = MmapStorage::new(&info(), &from).unwrap();
+        for index in 0..3 {
+            storage.write_block(index, 0, &piece(index)).unwrap();
+        }
+        assert!(storage.verify_piece(1).unwrap());
+        assert_eq!(storage.read_block(1, 1, 5).unwrap(), &content()[11..16]);
+        assert!(storage.read_block(2, 0, 6).is_err());
+        storage.flush().unwrap();
+        assert_eq!(fs::read(from.join("album/a")).unwrap(), &content()[..12]);
+
+        storage.move_to(&to).unwrap();
+        assert!(!from.join("album").exists());
+        assert_eq!(fs::read(to.join("album/sub/b")).unwrap(), &content()[12..]);
+        assert_eq!(storage.read_block(2, 0, 5).unwrap(), piece(2));
+
+        // 不支持跳过文件, 不能假装成功
+        assert!(matches!(
+            storage.set_skipped(1, true),
+            Err(StorageError::Unsupported(_))
+        ));
+        storage.set_skipped(1, false).unwrap();
+
+        storage.delete().unwrap();
+        assert!(!to.join("album").exists());
+        let _ = fs::remove_dir_all(from);
+        let _ = fs::remove_dir_all(to);
+    }
+}
@@ -0,0 +1,84 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use tokio::sync::oneshot;
+
+use crate::error::StorageError;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads that run blocking file IO, so it never stalls the tokio runtime.
+///
+/// The pool is shared by cloning it; its threads stop once every clone is dropped.
+#[derive(Debug, Clone)]
+pub struct IoPool {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl IoPool {
+    pub fn new(threads: usize) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for n in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("storage-io-{}", n))
+                .spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        // 任务 panic 时结果的发送端被丢弃, 调用方会得到错误
+                        Ok(job) => {
+                            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                        }
+                        Err(_) => break,
+                    }
+                })
+                .expect("failed to spawn a storage IO thread");
+        }
+        Self { jobs }
+    }
+
+    /// Runs `f` on one of the threads and waits for its result.
+    pub async fn run<F, T>(&self, f: F) -> Result<T, StorageError>
+    where
+        F: FnOnce() -> Result<T, StorageError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            let _ = tx.send(f());
+        });
+        self.jobs.send(job).map_err(|_| StorageError::PoolClosed)?;
+        rx.await.map_err(|_| StorageError::PoolClosed)?
+    }
+}
+
+impl Default for IoPool {
+    /// A pool of `4` threads.
+    fn default() -> Self {
+        Self::new(4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run() {
+        let pool = IoPool::new(2);
+        let name = pool
+            .run(|| Ok(thread::current().name().unwrap().to_owned()))
+            .await
+            .unwrap();
+        assert!(name.starts_with("storage-io-"));
+
+        let result: Result<(), _> = pool.run(|| panic!("boom")).await;
+        assert!(matches!(result, Err(StorageError::PoolClosed)));
+        // panic 之后线程仍然可用
+        for _ in 0..4 {
+            assert_eq!(pool.clone().run(|| Ok(1)).await.unwrap(), 1);
+        }
+    }
+}
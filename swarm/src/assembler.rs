@@ -0,0 +1,612 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use peer::{Bitfield, BlockRequest};
+use sha1::{Digest, Sha1};
+use torrent::{Info, Sha1Hash};
+
+/// The size pieces are requested in. Most clients refuse larger requests.
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+/// What became of a block received from a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockOutcome {
+    /// Stored, the piece is still missing blocks.
+    Stored,
+    /// Already received, from this or another peer.
+    Duplicate,
+    /// Not requested from this peer, or not a block of the torrent at all.
+    Unrequested,
+    /// The last missing block. The piece matched its hash, here is its data.
+    Verified(Vec<u8>),
+    /// The last missing block, but the piece did not match its hash. The piece
+    /// was reset to be downloaded again; these peers sent its blocks.
+    HashFailed(Vec<SocketAddr>),
+}
+
+#[derive(Debug, Default)]
+struct BlockState {
+    requested_by: Vec<SocketAddr>,
+    received: bool,
+    // 从 resume 恢复的块没有来源
+    received_from: Option<SocketAddr>,
+}
+
+#[derive(Debug)]
+struct PartialPiece {
+    data: Vec<u8>,
+    blocks: Vec<BlockState>,
+    received: usize,
+}
+
+impl PartialPiece {
+    // 没有收到任何块, 也没有人在下载时可以丢弃
+    fn is_abandoned(&self) -> bool {
+        self.received == 0 && self.blocks.iter().all(|b| b.requested_by.is_empty())
+    }
+}
+
+/// Puts pieces together from the blocks peers send.
+///
+/// Each piece is split into [`BLOCK_SIZE`] blocks which are requested from
+/// peers and may arrive in any order and from several peers. When the last
+/// block of a piece arrives it is checked against the piece hash of the
+/// [`Info`]. The assembler does no IO: verified pieces are handed to the
+/// caller to be written.
+#[derive(Debug)]
+pub struct PieceAssembler {
+    piece_length: u32,
+    length: u64,
+    hashes: Vec<Sha1Hash>,
+    have: Bitfield,
+    partial: HashMap<u32, PartialPiece>,
+    cancels: Vec<(SocketAddr, BlockRequest)>,
+}
+
+impl PieceAssembler {
+    pub fn new(info: &Info) -> Self {
+        let hashes = (0..info.piece_count())
+            .map(|index| info.piece_hash(index).unwrap())
+            .collect();
+        Self {
+            piece_length: info.piece_length() as u32,
+            length: info.length() as u64,
+            hashes,
+            have: Bitfield::for_info(info),
+            partial: HashMap::new(),
+            cancels: Vec::new(),
+        }
+    }
+
+    /// Starts with the pieces already verified, e.g. by a resume.
+    ///
+    /// Panics if the bitfield is not of the torrent's length.
+    pub fn with_have(mut self, have: Bitfield) -> Self {
+        assert_eq!(have.len(), self.hashes.len(), "bitfield of another torrent");
+        self.have = have;
+        self
+    }
+
+    /// The verified pieces.
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// The size of the piece, which is shorter than the piece length for the last one.
+    pub fn piece_size(&self, index: u32) -> u32 {
+        let begin = index as u64 * self.piece_length as u64;
+        (self.length - begin).min(self.piece_length as u64) as u32
+    }
+
+    /// The blocks the piece is split into.
+    pub fn blocks(&self, index: u32) -> impl Iterator<Item = BlockRequest> {
+        let size = self.piece_size(index);
+        (0..size.div_ceil(BLOCK_SIZE)).map(move |n| {
+            let begin = n * BLOCK_SIZE;
+            BlockRequest {
+                index,
+                begin,
+                length: BLOCK_SIZE.min(size - begin),
+            }
+        })
+    }
+
+    /// The pieces of which some blocks were received or requested.
+    pub fn partial_pieces(&self) -> impl Iterator<Item = u32> + '_ {
+        self.partial.keys().copied()
+    }
+
+    pub fn is_partial(&self, index: u32) -> bool {
+        self.partial.contains_key(&index)
+    }
+
+    /// Whether every block of the piece was received or requested.
+    pub fn is_fully_requested(&self, index: u32) -> bool {
+        self.partial.get(&index).is_some_and(|piece| {
+            piece
+                .blocks
+                .iter()
+                .all(|state| state.received || !state.requested_by.is_empty())
+        })
+    }
+
+    /// The blocks of the piece that were requested but not received yet.
+    pub fn outstanding_blocks(&self, index: u32) -> Vec<BlockRequest> {
+        let Some(piece) = self.partial.get(&index) else {
+            return Vec::new();
+        };
+        self.blocks(index)
+            .zip(&piece.blocks)
+            .filter(|(_, state)| !state.received && !state.requested_by.is_empty())
+            .map(|(block, _)| block)
+            .collect()
+    }
+
+    /// The blocks of the piece received so far, one bit per block, to be saved
+    /// in the resume data. `None` if nothing of the piece was received.
+    pub fn received_blocks(&self, index: u32) -> Option<Bitfield> {
+        let piece = self
+            .partial
+            .get(&index)
+            .filter(|piece| piece.received > 0)?;
+        let mut blocks = Bitfield::new(piece.blocks.len());
+        for (n, state) in piece.blocks.iter().enumerate() {
+            if state.received {
+                blocks.set(n);
+            }
+        }
+        Some(blocks)
+    }
+
+    /// Restores the blocks of a piece received before a restart, from the
+    /// resume data and the piece as read from storage.
+    ///
+    /// Returns `false` and changes nothing if the piece is verified or
+    /// already partial, `blocks` or `data` do not match the piece, or every
+    /// block is set; such a piece is to be checked against its hash instead.
+    pub fn restore_partial(&mut self, index: u32, blocks: &Bitfield, data: &[u8]) -> bool {
+        if index as usize >= self.hashes.len()
+            || self.have.get(index as usize)
+            || self.partial.contains_key(&index)
+            || data.len() != self.piece_size(index) as usize
+        {
+            return false;
+        }
+        let count = self.blocks(index).count();
+        if blocks.len() != count || blocks.count() == count {
+            return false;
+        }
+        let ranges: Vec<BlockRequest> = self.blocks(index).collect();
+        let piece = self.partial_piece(index);
+        for (n, block) in ranges.into_iter().enumerate() {
+            if blocks.get(n) {
+                let range = block.begin as usize..(block.begin + block.length) as usize;
+                piece.data[range.clone()].copy_from_slice(&data[range]);
+                piece.blocks[n].received = true;
+                piece.received += 1;
+            }
+        }
+        self.drop_if_abandoned(index);
+        true
+    }
+
+    /// The peers the block is requested from.
+    pub fn requested_from(&self, block: BlockRequest) -> &[SocketAddr] {
+        match (self.block_number(block), self.partial.get(&block.index)) {
+            (Some(n), Some(piece)) => &piece.blocks[n].requested_by,
+            _ => &[],
+        }
+    }
+
+    /// The requests to cancel because the block arrived from another peer,
+    /// which happens when a block is requested from several peers in endgame.
+    pub fn take_cancels(&mut self) -> Vec<(SocketAddr, BlockRequest)> {
+        std::mem::take(&mut self.cancels)
+    }
+
+    /// Picks up to `max` blocks of the piece that were neither received nor
+    /// requested from anyone, and records them as requested from `peer`.
+    pub fn request_blocks(
+        &mut self,
+        peer: SocketAddr,
+        index: u32,
+        max: usize,
+    ) -> Vec<BlockRequest> {
+        if index as usize >= self.hashes.len() || self.have.get(index as usize) {
+            return Vec::new();
+        }
+        let blocks: Vec<BlockRequest> = self.blocks(index).collect();
+        let piece = self.partial_piece(index);
+        let mut picked = Vec::new();
+        for (block, state) in blocks.into_iter().zip(&mut piece.blocks) {
+            if picked.len() == max {
+                break;
+            }
+            if !state.received && state.requested_by.is_empty() {
+                state.requested_by.push(peer);
+                picked.push(block);
+            }
+        }
+        self.drop_if_abandoned(index);
+        picked
+    }
+
+    /// Records a specific block as requested from `peer`, even if it is already
+    /// requested from others. Returns `false` if the block is not needed.
+    pub fn request_block(&mut self, peer: SocketAddr, block: BlockRequest) -> bool {
+        let Some(n) = self.block_number(block) else {
+            return false;
+        };
+        let state = &mut self.partial_piece(block.index).blocks[n];
+        if state.received {
+            return false;
+        }
+        if !state.requested_by.contains(&peer) {
+            state.requested_by.push(peer);
+        }
+        true
+    }
+
+    /// Forgets that the block was requested from `peer`, e.g. after a cancel.
+    pub fn cancel(&mut self, peer: SocketAddr, block: BlockRequest) {
+        let Some(n) = self.block_number(block) else {
+            return;
+        };
+        if let Some(piece) = self.partial.get_mut(&block.index) {
+            piece.blocks[n].requested_by.retain(|addr| *addr != peer);
+            self.drop_if_abandoned(block.index);
+        }
+    }
+
+    /// Forgets every request to `peer`, e.g. when it chokes us or disconnects,
+    /// and returns the blocks that no other peer was asked for.
+    pub fn release_peer(&mut self, peer: SocketAddr) -> Vec<BlockRequest> {
+        let mut released = Vec::new();
+        let indices: Vec<u32> = self.partial.keys().copied().collect();
+        for index in indices {
+            let blocks: Vec<BlockRequest> = self.blocks(index).collect();
+            let piece = self.partial.get_mut(&index).unwrap();
+            for (block, state) in blocks.into_iter().zip(&mut piece.blocks) {
+                let before = state.requested_by.len();
+                state.requested_by.retain(|addr| *addr != peer);
+                if state.requested_by.len() < before && state.requested_by.is_empty() {
+                    released.push(block);
+                }
+            }
+            self.drop_if_abandoned(index);
+        }
+        released.sort_by_key(|block| (block.index, block.begin));
+        released
+    }
+
+    /// Stores a block received from `peer`.
+    ///
+    /// Only blocks requested from that peer are accepted, anything else is
+    /// reported and dropped so a peer cannot slip data into a piece.
+    pub fn on_block(
+        &mut self,
+        peer: SocketAddr,
+        index: u32,
+        begin: u32,
+        data: &[u8],
+    ) -> BlockOutcome {
+        let block = BlockRequest {
+            index,
+            begin,
+            length: data.len() as u32,
+        };
+        if index as usize >= self.hashes.len() {
+            return BlockOutcome::Unrequested;
+        }
+        if self.have.get(index as usize) {
+            return BlockOutcome::Duplicate;
+        }
+        let (Some(n), Some(piece)) = (self.block_number(block), self.partial.get_mut(&index))
+        else {
+            return BlockOutcome::Unrequested;
+        };
+        let state = &mut piece.blocks[n];
+        if state.received {
+            return BlockOutcome::Duplicate;
+        }
+        if !state.requested_by.contains(&peer) {
+            return BlockOutcome::Unrequested;
+        }
+        for other in state.requested_by.drain(..).filter(|addr| *addr != peer) {
+            self.cancels.push((other, block));
+        }
+        state.received = true;
+        state.received_from = Some(peer);
+        let begin = begin as usize;
+        piece.data[begin..begin + data.len()].copy_from_slice(data);
+        piece.received += 1;
+        if piece.received < piece.blocks.len() {
+            return BlockOutcome::Stored;
+        }
+
+        let piece = self.partial.remove(&index).unwrap();
+        if Sha1::digest(&piece.data)[..] == self.hashes[index as usize] {
+            self.have.set(index as usize);
+            BlockOutcome::Verified(piece.data)
+        } else {
+            let mut peers: Vec<SocketAddr> = piece
+                .blocks
+                .iter()
+                .filter_map(|state| state.received_from)
+                .collect();
+            peers.sort();
+            peers.dedup();
+            BlockOutcome::HashFailed(peers)
+        }
+    }
+
+    // 块在分片中的序号, 不是这个种子的块时为 None
+    fn block_number(&self, block: BlockRequest) -> Option<usize> {
+        if block.index as usize >= self.hashes.len() || self.have.get(block.index as usize) {
+            return None;
+        }
+        let size = self.piece_size(block.index);
+        let aligned = block.begin.is_multiple_of(BLOCK_SIZE) && block.begin < size;
+        if !aligned || block.length != BLOCK_SIZE.min(size - block.begin) {
+            return None;
+        }
+        Some((block.begin / BLOCK_SIZE) as usize)
+    }
+
+    fn partial_piece(&mut self, index: u32) -> &mut PartialPiece {
+        let size = self.piece_size(index);
+        self.partial.entry(index).or_insert_with(|| PartialPiece {
+            data: vec![0; size as usize],
+            blocks: (0..size.div_ceil(BLOCK_SIZE))
+                .map(|_| BlockState::default())
+                .collect(),
+            received: 0,
+        })
+    }
+
+    fn drop_if_abandoned(&mut self, index: u32) {
+        if self
+            .partial
+            .get(&index)
+            .is_some_and(PartialPiece::is_abandoned)
+        {
+            self.partial.remove(&index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bencode::Dict;
+    use torrent::SingleFile;
+
+    use super::*;
+    use crate::fixture::peer;
+
+    const PIECE_LENGTH: usize = 2 * BLOCK_SIZE as usize;
+
+    // 两个完整的分片和一个 1000 字节的分片
+    fn content() -> Vec<u8> {
+        (0..2 * PIECE_LENGTH + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect()
+    }
+
+    fn info(content: &[u8]) -> Info {
+        let pieces = content
+            .chunks(PIECE_LENGTH)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        Info::SingleFile(SingleFile {
+            piece_length: PIECE_LENGTH as i64,
+            pieces,
+            private: None,
+            name: "content".to_owned(),
+            source: None,
+            length: content.len() as i64,
+            md5sum: None,
+            extra: Dict::new(),
+        })
+    }
+
+    fn block_data(content: &[u8], block: BlockRequest) -> &[u8] {
+        let begin = block.index as usize * PIECE_LENGTH + block.begin as usize;
+        &content[begin..begin + block.length as usize]
+    }
+
+    #[test]
+    fn test_blocks() {
+        let assembler = PieceAssembler::new(&info(&content()));
+        assert_eq!(assembler.piece_count(), 3);
+        assert_eq!(assembler.piece_size(0), 2 * BLOCK_SIZE);
+        assert_eq!(assembler.piece_size(2), 1000);
+        let blocks: Vec<BlockRequest> = assembler.blocks(0).collect();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(
+            (blocks[1].begin, blocks[1].length),
+            (BLOCK_SIZE, BLOCK_SIZE)
+        );
+        let blocks: Vec<BlockRequest> = assembler.blocks(2).collect();
+        assert_eq!(
+            blocks,
+            vec![BlockRequest {
+                index: 2,
+                begin: 0,
+                length: 1000
+            }]
+        );
+    }
+
+    #[test]
+    fn test_out_of_order_from_several_peers() {
+        let content = content();
+        let mut assembler = PieceAssembler::new(&info(&content));
+        let first = assembler.request_blocks(peer(1), 0, 1);
+        let second = assembler.request_blocks(peer(2), 0, 5);
+        assert_eq!(first.len() + second.len(), 2);
+        assert_ne!(first, second);
+        // 已经全部请求过了
+        assert!(assembler.request_blocks(peer(3), 0, 5).is_empty());
+        assert!(assembler.is_partial(0));
+
+        let outcome = assembler.on_block(peer(2), 0, BLOCK_SIZE, block_data(&content, second[0]));
+        assert_eq!(outcome, BlockOutcome::Stored);
+        let outcome = assembler.on_block(peer(1), 0, 0, block_data(&content, first[0]));
+        assert_eq!(
+            outcome,
+            BlockOutcome::Verified(content[..PIECE_LENGTH].to_vec())
+        );
+        assert!(assembler.have().get(0));
+        assert!(!assembler.is_partial(0));
+        assert!(assembler.request_blocks(peer(1), 0, 5).is_empty());
+
+        let last = assembler.request_blocks(peer(1), 2, 5);
+        let outcome = assembler.on_block(peer(1), 2, 0, block_data(&content, last[0]));
+        assert_eq!(
+            outcome,
+            BlockOutcome::Verified(content[2 * PIECE_LENGTH..].to_vec())
+        );
+        assert_eq!(assembler.have().count(), 2);
+    }
+
+    #[test]
+    fn test_duplicate_and_unrequested_blocks() {
+        let content = content();
+        let mut assembler = PieceAssembler::new(&info(&content));
+        let blocks = assembler.request_blocks(peer(1), 1, 5);
+        let data = block_data(&content, blocks[0]);
+
+        // 没有向这个 peer 请求过
+        assert_eq!(
+            assembler.on_block(peer(2), 1, 0, data),
+            BlockOutcome::Unrequested
+        );
+        // 不是块的边界, 长度不对, 分片不存在
+        assert_eq!(
+            assembler.on_block(peer(1), 1, 1, data),
+            BlockOutcome::Unrequested
+        );
+        assert_eq!(
+            assembler.on_block(peer(1), 1, 0, &data[1..]),
+            BlockOutcome::Unrequested
+        );
+        assert_eq!(
+            assembler.on_block(peer(1), 3, 0, data),
+            BlockOutcome::Unrequested
+        );
+
+        // endgame 时同一个块向两个 peer 请求
+        assert!(assembler.request_block(peer(2), blocks[0]));
+        assert_eq!(assembler.requested_from(blocks[0]), &[peer(1), peer(2)]);
+        assert_eq!(assembler.outstanding_blocks(1), blocks);
+        assert!(assembler.is_fully_requested(1));
+        assert_eq!(
+            assembler.on_block(peer(1), 1, 0, data),
+            BlockOutcome::Stored
+        );
+        // 另一个 peer 的请求需要取消
+        assert_eq!(assembler.take_cancels(), vec![(peer(2), blocks[0])]);
+        assert!(assembler.take_cancels().is_empty());
+        assert_eq!(assembler.outstanding_blocks(1), vec![blocks[1]]);
+        assert_eq!(
+            assembler.on_block(peer(2), 1, 0, data),
+            BlockOutcome::Duplicate
+        );
+        assert!(!assembler.request_block(peer(3), blocks[0]));
+    }
+
+    #[test]
+    fn test_hash_failure() {
+        let content = content();
+        let mut assembler = PieceAssembler::new(&info(&content));
+        let first = assembler.request_blocks(peer(1), 0, 1);
+        let second = assembler.request_blocks(peer(2), 0, 1);
+        assembler.on_block(peer(1), 0, 0, block_data(&content, first[0]));
+        let bad = vec![0; BLOCK_SIZE as usize];
+        assert_eq!(
+            assembler.on_block(peer(2), 0, second[0].begin, &bad),
+            BlockOutcome::HashFailed(vec![peer(1), peer(2)])
+        );
+        assert!(!assembler.have().get(0));
+        // 分片重新下载
+        assert!(!assembler.is_partial(0));
+        assert_eq!(assembler.request_blocks(peer(3), 0, 5).len(), 2);
+    }
+
+    #[test]
+    fn test_release_peer() {
+        let content = content();
+        let mut assembler = PieceAssembler::new(&info(&content));
+        let blocks = assembler.request_blocks(peer(1), 0, 5);
+        assembler.request_blocks(peer(1), 1, 1);
+        assembler.request_block(peer(2), blocks[1]);
+
+        // 同时向 peer 2 请求的块不需要重新请求
+        let released = assembler.release_peer(peer(1));
+        assert_eq!(
+            released,
+            vec![
+                blocks[0],
+                BlockRequest {
+                    index: 1,
+                    begin: 0,
+                    length: BLOCK_SIZE
+                }
+            ]
+        );
+        assert!(assembler.is_partial(0));
+        assert!(!assembler.is_partial(1));
+        assert_eq!(
+            assembler.on_block(peer(1), 0, 0, block_data(&content, blocks[0])),
+            BlockOutcome::Unrequested
+        );
+
+        assembler.cancel(peer(2), blocks[1]);
+        assert_eq!(assembler.partial_pieces().count(), 0);
+        assert_eq!(assembler.request_blocks(peer(3), 0, 5), blocks);
+    }
+
+    #[test]
+    fn test_with_have() {
+        let content = content();
+        let mut have = Bitfield::new(3);
+        have.set(1);
+        let mut assembler = PieceAssembler::new(&info(&content)).with_have(have);
+        assert!(assembler.request_blocks(peer(1), 1, 5).is_empty());
+        assert_eq!(
+            assembler.on_block(peer(1), 1, 0, &content[..BLOCK_SIZE as usize]),
+            BlockOutcome::Duplicate
+        );
+    }
+
+    #[test]
+    fn test_restore_partial() {
+        let content = content();
+        let mut assembler = PieceAssembler::new(&info(&content));
+        let blocks = assembler.request_blocks(peer(1), 0, 2);
+        assert_eq!(assembler.received_blocks(0), None);
+        assembler.on_block(peer(1), 0, BLOCK_SIZE, block_data(&content, blocks[1]));
+        let received = assembler.received_blocks(0).unwrap();
+        assert_eq!(received.iter().collect::<Vec<_>>(), [1]);
+
+        // 重启后从 resume 和存储恢复, 只需要再下载块 0
+        let mut restored = PieceAssembler::new(&info(&content));
+        let piece = &content[..PIECE_LENGTH];
+        assert!(!restored.restore_partial(0, &Bitfield::new(3), piece));
+        assert!(!restored.restore_partial(0, &received, &piece[1..]));
+        assert!(!restored.restore_partial(0, &Bitfield::full(2), piece));
+        assert!(restored.restore_partial(0, &received, piece));
+        assert!(!restored.restore_partial(0, &received, piece));
+        assert_eq!(restored.received_blocks(0), Some(received));
+        let missing = restored.request_blocks(peer(2), 0, 5);
+        assert_eq!(missing, vec![blocks[0]]);
+        assert_eq!(
+            restored.on_block(peer(2), 0, 0, block_data(&content, missing[0])),
+            BlockOutcome::Verified(piece.to_vec())
+        );
+    }
+}
@@ -0,0 +1,397 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Settings of a [`Choker`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChokerConfig {
+    unchoke_slots: usize,
+    interval: Duration,
+    optimistic_interval: Duration,
+    snub_timeout: Duration,
+}
+
+impl Default for ChokerConfig {
+    fn default() -> Self {
+        Self {
+            unchoke_slots: 4,
+            interval: Duration::from_secs(10),
+            optimistic_interval: Duration::from_secs(30),
+            snub_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl ChokerConfig {
+    /// How many peers are unchoked at once, one of them optimistically, `4` by default.
+    pub fn with_unchoke_slots(mut self, unchoke_slots: usize) -> Self {
+        self.unchoke_slots = unchoke_slots;
+        self
+    }
+
+    /// How often the unchoked peers are chosen again, `10` seconds by default.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How often the optimistic unchoke moves to another peer, `30` seconds by default.
+    pub fn with_optimistic_interval(mut self, optimistic_interval: Duration) -> Self {
+        self.optimistic_interval = optimistic_interval;
+        self
+    }
+
+    /// A peer that sent us no block for this long is snubbing us, `60` seconds by default.
+    pub fn with_snub_timeout(mut self, snub_timeout: Duration) -> Self {
+        self.snub_timeout = snub_timeout;
+        self
+    }
+
+    pub fn unchoke_slots(&self) -> usize {
+        self.unchoke_slots
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn optimistic_interval(&self) -> Duration {
+        self.optimistic_interval
+    }
+
+    pub fn snub_timeout(&self) -> Duration {
+        self.snub_timeout
+    }
+}
+
+/// The transfer rates of a peer, in bytes per second, as measured by the caller.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    /// How fast the peer sends to us.
+    pub download_rate: u64,
+    /// How fast we send to the peer.
+    pub upload_rate: u64,
+    /// Whether the peer is interested in our pieces.
+    pub interested: bool,
+}
+
+/// A change the [`Choker`] wants sent to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChokeAction {
+    Choke(SocketAddr),
+    Unchoke(SocketAddr),
+}
+
+#[derive(Debug)]
+struct PeerState {
+    stats: PeerStats,
+    connected_at: Instant,
+    last_block: Option<Instant>,
+    last_optimistic: Option<Instant>,
+    unchoked: bool,
+}
+
+/// Decides which peers we upload to (tit-for-tat).
+///
+/// Every interval the interested peers that send to us the fastest get the
+/// regular unchoke slots; when seeding, those we upload to the fastest do.
+/// Peers that snub us, sending nothing for a while, lose their regular slot.
+/// The last slot is an optimistic unchoke that moves every optimistic interval
+/// to the choked peer that has waited the longest for one, so new peers get a
+/// chance to prove themselves.
+///
+/// The choker only decides: the caller feeds it rates, calls [`Choker::tick`]
+/// and sends the resulting [`ChokeAction`]s. Time is passed in so that it can
+/// be driven by simulated peers.
+#[derive(Debug)]
+pub struct Choker {
+    config: ChokerConfig,
+    peers: HashMap<SocketAddr, PeerState>,
+    optimistic: Option<SocketAddr>,
+    last_round: Option<Instant>,
+    last_rotation: Option<Instant>,
+}
+
+impl Choker {
+    pub fn new(config: ChokerConfig) -> Self {
+        Self {
+            config,
+            peers: HashMap::new(),
+            optimistic: None,
+            last_round: None,
+            last_rotation: None,
+        }
+    }
+
+    /// A new peer, choked like every peer at first.
+    pub fn add_peer(&mut self, peer: SocketAddr, now: Instant) {
+        self.peers.insert(
+            peer,
+            PeerState {
+                stats: PeerStats::default(),
+                connected_at: now,
+                last_block: None,
+                last_optimistic: None,
+                unchoked: false,
+            },
+        );
+    }
+
+    /// Forgets a disconnected peer, freeing its slot at the next round.
+    pub fn remove_peer(&mut self, peer: SocketAddr) {
+        self.peers.remove(&peer);
+        if self.optimistic == Some(peer) {
+            self.optimistic = None;
+        }
+    }
+
+    pub fn update(&mut self, peer: SocketAddr, stats: PeerStats) {
+        if let Some(state) = self.peers.get_mut(&peer) {
+            state.stats = stats;
+        }
+    }
+
+    /// The peer sent us a block, so it is not snubbing us.
+    pub fn block_received(&mut self, peer: SocketAddr, now: Instant) {
+        if let Some(state) = self.peers.get_mut(&peer) {
+            state.last_block = Some(now);
+        }
+    }
+
+    /// Whether the peer sent us no block within the snub timeout.
+    pub fn is_snubbed(&self, peer: SocketAddr, now: Instant) -> bool {
+        self.peers.get(&peer).is_some_and(|state| {
+            let since = state.last_block.unwrap_or(state.connected_at);
+            now.saturating_duration_since(since) >= self.config.snub_timeout
+        })
+    }
+
+    pub fn is_unchoked(&self, peer: SocketAddr) -> bool {
+        self.peers.get(&peer).is_some_and(|state| state.unchoked)
+    }
+
+    /// The optimistically unchoked peer.
+    pub fn optimistic(&self) -> Option<SocketAddr> {
+        self.optimistic
+    }
+
+    /// Chooses the unchoked peers again if the interval has passed since the
+    /// last round, and returns what changed. Snubbing is ignored when seeding.
+    pub fn tick(&mut self, now: Instant, seeding: bool) -> Vec<ChokeAction> {
+        let due = self
+            .last_round
+            .is_none_or(|last| now.saturating_duration_since(last) >= self.config.interval);
+        if !due {
+            return Vec::new();
+        }
+        self.last_round = Some(now);
+
+        // 按速率排序, 速率相同时按地址, 保证结果确定
+        let mut ranked: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|(peer, state)| {
+                state.stats.interested && (seeding || !self.is_snubbed(**peer, now))
+            })
+            .map(|(peer, _)| *peer)
+            .collect();
+        ranked.sort_by_key(|peer| {
+            let stats = self.peers[peer].stats;
+            let rate = if seeding {
+                stats.upload_rate
+            } else {
+                stats.download_rate
+            };
+            (Reverse(rate), *peer)
+        });
+        ranked.truncate(self.config.unchoke_slots.saturating_sub(1));
+
+        let rotate = self.last_rotation.is_none_or(|last| {
+            now.saturating_duration_since(last) >= self.config.optimistic_interval
+        });
+        let keep = self
+            .optimistic
+            .is_some_and(|peer| self.peers[&peer].stats.interested && !ranked.contains(&peer));
+        if self.config.unchoke_slots == 0 {
+            self.optimistic = None;
+        } else if rotate || !keep {
+            self.optimistic = self.next_optimistic(&ranked);
+            if let Some(peer) = self.optimistic {
+                self.peers.get_mut(&peer).unwrap().last_optimistic = Some(now);
+            }
+            if rotate {
+                self.last_rotation = Some(now);
+            }
+        }
+
+        let mut actions = Vec::new();
+        let mut peers: Vec<SocketAddr> = self.peers.keys().copied().collect();
+        peers.sort();
+        for peer in peers {
+            let unchoke = ranked.contains(&peer) || self.optimistic == Some(peer);
+            let state = self.peers.get_mut(&peer).unwrap();
+            match (state.unchoked, unchoke) {
+                (false, true) => actions.push(ChokeAction::Unchoke(peer)),
+                (true, false) => actions.push(ChokeAction::Choke(peer)),
+                _ => {}
+            }
+            state.unchoked = unchoke;
+        }
+        actions
+    }
+
+    // 等待最久的 (从来没有被乐观 unchoke 过的最先) 感兴趣的 peer
+    fn next_optimistic(&self, regular: &[SocketAddr]) -> Option<SocketAddr> {
+        self.peers
+            .iter()
+            .filter(|(peer, state)| state.stats.interested && !regular.contains(peer))
+            .min_by_key(|(peer, state)| (state.last_optimistic, **peer))
+            .map(|(peer, _)| *peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::peer;
+
+    fn stats(download_rate: u64, upload_rate: u64) -> PeerStats {
+        PeerStats {
+            download_rate,
+            upload_rate,
+            interested: true,
+        }
+    }
+
+    // 5 个 peer, 下载速率 peer 1 最慢, peer 5 最快, 上传速率相反
+    fn choker(now: Instant) -> Choker {
+        choker_with(ChokerConfig::default(), now)
+    }
+
+    fn choker_with(config: ChokerConfig, now: Instant) -> Choker {
+        let mut choker = Choker::new(config);
+        for n in 1..=5 {
+            choker.add_peer(peer(n), now);
+            choker.update(peer(n), stats(n as u64 * 1000, (6 - n) as u64 * 1000));
+            choker.block_received(peer(n), now);
+        }
+        choker
+    }
+
+    fn unchoked(actions: &[ChokeAction]) -> Vec<SocketAddr> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                ChokeAction::Unchoke(peer) => Some(*peer),
+                ChokeAction::Choke(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_unchoke_fastest() {
+        let start = Instant::now();
+        let mut choker = choker(start);
+        let actions = choker.tick(start, false);
+        // 3 个最快的, 再加上乐观 unchoke 剩下的第一个
+        assert_eq!(unchoked(&actions), vec![peer(1), peer(3), peer(4), peer(5)]);
+        assert_eq!(choker.optimistic(), Some(peer(1)));
+        assert!(!choker.is_unchoked(peer(2)));
+
+        // 间隔没到, 不重新选择
+        choker.update(peer(2), stats(10_000, 0));
+        assert!(choker
+            .tick(start + Duration::from_secs(5), false)
+            .is_empty());
+
+        let actions = choker.tick(start + Duration::from_secs(10), false);
+        assert_eq!(
+            actions,
+            vec![ChokeAction::Unchoke(peer(2)), ChokeAction::Choke(peer(3))]
+        );
+    }
+
+    #[test]
+    fn test_optimistic_rotation() {
+        let start = Instant::now();
+        let mut choker = choker(start);
+        choker.tick(start, false);
+        assert_eq!(choker.optimistic(), Some(peer(1)));
+
+        for secs in [10, 20] {
+            let now = start + Duration::from_secs(secs);
+            choker.block_received(peer(1), now);
+            assert!(choker.tick(now, false).is_empty());
+        }
+        let actions = choker.tick(start + Duration::from_secs(30), false);
+        assert_eq!(
+            actions,
+            vec![ChokeAction::Choke(peer(1)), ChokeAction::Unchoke(peer(2))]
+        );
+        assert_eq!(choker.optimistic(), Some(peer(2)));
+
+        // 断开后马上换一个
+        choker.remove_peer(peer(2));
+        choker.tick(start + Duration::from_secs(40), false);
+        assert_eq!(choker.optimistic(), Some(peer(1)));
+    }
+
+    #[test]
+    fn test_seeding_ranks_by_upload() {
+        let start = Instant::now();
+        let mut choker = choker(start);
+        let actions = choker.tick(start, true);
+        assert_eq!(unchoked(&actions), vec![peer(1), peer(2), peer(3), peer(4)]);
+        assert_eq!(choker.optimistic(), Some(peer(4)));
+    }
+
+    #[test]
+    fn test_anti_snubbing() {
+        let start = Instant::now();
+        let config = ChokerConfig::default().with_optimistic_interval(Duration::from_secs(600));
+        let mut choker = choker_with(config, start);
+        choker.tick(start, false);
+        let now = start + Duration::from_secs(60);
+        for n in [1, 2, 3, 4] {
+            choker.block_received(peer(n), now);
+        }
+        assert!(choker.is_snubbed(peer(5), now));
+        assert!(!choker.is_snubbed(peer(4), now));
+
+        // 最快的 peer 5 不再发送数据, 失去位置
+        let actions = choker.tick(now, false);
+        assert_eq!(
+            actions,
+            vec![ChokeAction::Unchoke(peer(2)), ChokeAction::Choke(peer(5))]
+        );
+        // 做种时不考虑
+        choker.update(peer(5), stats(5000, 10_000));
+        let actions = choker.tick(now + Duration::from_secs(10), true);
+        assert!(actions.contains(&ChokeAction::Unchoke(peer(5))));
+        assert!(!choker.is_snubbed(peer(9), now));
+    }
+
+    #[test]
+    fn test_not_interested() {
+        let start = Instant::now();
+        let mut choker = choker(start);
+        choker.tick(start, false);
+        choker.update(
+            peer(5),
+            PeerStats {
+                interested: false,
+                ..stats(5000, 0)
+            },
+        );
+        let actions = choker.tick(start + Duration::from_secs(10), false);
+        assert_eq!(
+            actions,
+            vec![ChokeAction::Unchoke(peer(2)), ChokeAction::Choke(peer(5))]
+        );
+        assert_eq!(
+            Choker::new(ChokerConfig::default().with_unchoke_slots(0)).tick(start, false),
+            vec![]
+        );
+    }
+}
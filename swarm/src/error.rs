@@ -0,0 +1,9 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SwarmError {
+    #[error(transparent)]
+    BencodeError(#[from] bencode::BencodeError),
+    #[error("invalid resume data: {0}")]
+    InvalidResume(::std::borrow::Cow<'static, str>),
+}
@@ -0,0 +1,6 @@
+// 测试共用的辅助函数
+use std::net::SocketAddr;
+
+pub fn peer(n: u8) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, n], 6881))
+}
@@ -0,0 +1,15 @@
+mod assembler;
+mod choker;
+pub mod error;
+#[cfg(test)]
+mod fixture;
+mod picker;
+mod resume;
+mod stream;
+
+pub use crate::assembler::{BlockOutcome, PieceAssembler, BLOCK_SIZE};
+pub use crate::choker::{ChokeAction, Choker, ChokerConfig, PeerStats};
+pub use crate::error::SwarmError;
+pub use crate::picker::{PiecePicker, Priority};
+pub use crate::resume::{ResumeData, TrackerState};
+pub use crate::stream::{Deadline, StreamFile};
@@ -0,0 +1,472 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::time::Instant;
+
+use peer::{Bitfield, BlockRequest};
+use rand::seq::SliceRandom;
+use torrent::Info;
+
+use crate::assembler::PieceAssembler;
+
+/// How urgently a piece (or the pieces of a file) is wanted.
+///
+/// Pieces of a higher priority are always picked before those of a lower one,
+/// skipped pieces are never downloaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Decides which blocks to request from which peer.
+///
+/// Partially downloaded pieces are completed first, in order, so few pieces
+/// are in progress at once. New pieces are picked rarest-first among the
+/// connected peers, except for the first few which are picked at random to
+/// get something to share quickly. In sequential mode pieces are picked in
+/// order instead, for streaming. Pieces with a deadline go before all others,
+/// the earliest deadline first. Once every missing block is requested,
+/// the picker enters endgame and requests the outstanding blocks from other
+/// peers as well; the losers are cancelled through
+/// [`PieceAssembler::take_cancels`].
+///
+/// The picker does no networking: the coordinator reports the peers'
+/// bitfields and asks for blocks when a peer has room in its queue.
+#[derive(Debug)]
+pub struct PiecePicker {
+    availability: Vec<u32>,
+    priorities: Vec<Priority>,
+    peers: HashMap<SocketAddr, Bitfield>,
+    deadlines: HashMap<usize, Instant>,
+    random_first: usize,
+    sequential: bool,
+}
+
+impl PiecePicker {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            availability: vec![0; piece_count],
+            priorities: vec![Priority::Normal; piece_count],
+            peers: HashMap::new(),
+            deadlines: HashMap::new(),
+            random_first: 4,
+            sequential: false,
+        }
+    }
+
+    /// Until this many pieces are verified, new pieces are picked at random
+    /// instead of rarest-first, `4` by default.
+    pub fn with_random_first(mut self, random_first: usize) -> Self {
+        self.random_first = random_first;
+        self
+    }
+
+    /// Picks new pieces in order, see [`PiecePicker::set_sequential`].
+    pub fn with_sequential(mut self, sequential: bool) -> Self {
+        self.sequential = sequential;
+        self
+    }
+
+    /// Downloads new pieces in order rather than rarest-first, so media can
+    /// be played while it downloads. Priorities and deadlines still apply.
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    pub fn is_sequential(&self) -> bool {
+        self.sequential
+    }
+
+    /// Wants the pieces by `deadline`: they are picked before any piece
+    /// without one, even skipped ones. A piece keeps its earliest deadline.
+    ///
+    /// For a byte range of the content, the pieces are [`Info::piece_range`].
+    pub fn set_deadline(&mut self, pieces: Range<usize>, deadline: Instant) {
+        for index in pieces.take_while(|&index| index < self.priorities.len()) {
+            self.deadlines
+                .entry(index)
+                .and_modify(|current| *current = (*current).min(deadline))
+                .or_insert(deadline);
+        }
+    }
+
+    pub fn deadline(&self, index: usize) -> Option<Instant> {
+        self.deadlines.get(&index).copied()
+    }
+
+    pub fn clear_deadlines(&mut self) {
+        self.deadlines.clear();
+    }
+
+    /// A connected peer and its bitfield. Panics if the bitfield is of another length.
+    pub fn add_peer(&mut self, peer: SocketAddr, bitfield: Bitfield) {
+        assert_eq!(
+            bitfield.len(),
+            self.availability.len(),
+            "bitfield of another torrent"
+        );
+        self.remove_peer(peer);
+        for index in bitfield.iter() {
+            self.availability[index] += 1;
+        }
+        self.peers.insert(peer, bitfield);
+    }
+
+    /// The peer announced a new piece with `have`.
+    pub fn peer_has(&mut self, peer: SocketAddr, index: usize) {
+        let count = self.availability.len();
+        let bitfield = self
+            .peers
+            .entry(peer)
+            .or_insert_with(|| Bitfield::new(count));
+        if index < count && !bitfield.get(index) {
+            bitfield.set(index);
+            self.availability[index] += 1;
+        }
+    }
+
+    pub fn remove_peer(&mut self, peer: SocketAddr) {
+        if let Some(bitfield) = self.peers.remove(&peer) {
+            for index in bitfield.iter() {
+                self.availability[index] -= 1;
+            }
+        }
+    }
+
+    /// How many connected peers have the piece.
+    pub fn availability(&self, index: usize) -> u32 {
+        self.availability[index]
+    }
+
+    pub fn priority(&self, index: usize) -> Priority {
+        self.priorities[index]
+    }
+
+    pub fn set_piece_priority(&mut self, index: usize, priority: Priority) {
+        self.priorities[index] = priority;
+    }
+
+    /// Sets the priority of every piece from the priorities of the files, in
+    /// the order of the files of `info`. A piece shared by several files gets
+    /// the highest of their priorities, so it is only skipped if all of them are.
+    pub fn set_file_priorities(&mut self, info: &Info, priorities: &[Priority]) {
+        self.priorities.fill(Priority::Skip);
+        for (range, &priority) in info.file_ranges().into_iter().zip(priorities) {
+            for piece in &mut self.priorities[info.piece_range(range)] {
+                *piece = (*piece).max(priority);
+            }
+        }
+    }
+
+    /// Whether every wanted piece that is missing has all of its blocks requested.
+    pub fn in_endgame(&self, assembler: &PieceAssembler) -> bool {
+        self.wanted(assembler)
+            .all(|index| assembler.is_fully_requested(index as u32))
+    }
+
+    /// Picks up to `max` blocks to request from `peer` and records them as
+    /// requested in the assembler.
+    pub fn pick_blocks(
+        &self,
+        peer: SocketAddr,
+        assembler: &mut PieceAssembler,
+        max: usize,
+    ) -> Vec<BlockRequest> {
+        let Some(has) = self.peers.get(&peer) else {
+            return Vec::new();
+        };
+        let mut picked = Vec::new();
+
+        // 先完成已经开始下载的分片
+        let mut partial: Vec<u32> = assembler
+            .partial_pieces()
+            .filter(|&index| has.get(index as usize) && self.is_wanted(index as usize))
+            .collect();
+        partial.sort_by_key(|&index| (self.urgency(index as usize), index));
+        for &index in &partial {
+            if picked.len() == max {
+                return picked;
+            }
+            picked.extend(assembler.request_blocks(peer, index, max - picked.len()));
+        }
+
+        for index in self.new_pieces(has, assembler) {
+            if picked.len() == max {
+                return picked;
+            }
+            picked.extend(assembler.request_blocks(peer, index, max - picked.len()));
+        }
+
+        // endgame: 所有的块都请求过了, 向这个 peer 重复请求还没有收到的块
+        if picked.is_empty() && self.in_endgame(assembler) {
+            for &index in &partial {
+                for block in assembler.outstanding_blocks(index) {
+                    if picked.len() == max {
+                        return picked;
+                    }
+                    if !assembler.requested_from(block).contains(&peer) {
+                        assembler.request_block(peer, block);
+                        picked.push(block);
+                    }
+                }
+            }
+        }
+        picked
+    }
+
+    // 还没有开始下载, 这个 peer 有的分片, 按照下载的顺序排列
+    fn new_pieces(&self, has: &Bitfield, assembler: &PieceAssembler) -> Vec<u32> {
+        let mut pieces: Vec<u32> = self
+            .wanted(assembler)
+            .filter(|&index| has.get(index) && !assembler.is_partial(index as u32))
+            .map(|index| index as u32)
+            .collect();
+        if self.sequential {
+            pieces.sort_by_key(|&index| (self.urgency(index as usize), index));
+        } else if assembler.have().count() < self.random_first {
+            // 优先级仍然严格有效, 同一优先级内随机
+            pieces.shuffle(&mut rand::thread_rng());
+            pieces.sort_by_key(|&index| self.urgency(index as usize));
+        } else {
+            pieces.sort_by_key(|&index| {
+                let index = index as usize;
+                (self.urgency(index), self.availability[index], index)
+            });
+        }
+        pieces
+    }
+
+    // 排序的依据: 有期限的分片最先, 期限早的在前, 然后是优先级
+    fn urgency(&self, index: usize) -> (bool, Option<Instant>, Reverse<Priority>) {
+        let deadline = self.deadline(index);
+        (
+            deadline.is_none(),
+            deadline,
+            Reverse(self.priorities[index]),
+        )
+    }
+
+    fn wanted<'a>(&'a self, assembler: &'a PieceAssembler) -> impl Iterator<Item = usize> + 'a {
+        (0..self.availability.len())
+            .filter(move |&index| self.is_wanted(index) && !assembler.have().get(index))
+    }
+
+    fn is_wanted(&self, index: usize) -> bool {
+        self.priorities[index] != Priority::Skip || self.deadlines.contains_key(&index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use torrent::fixture::multiple_file;
+
+    use super::*;
+    use crate::assembler::BLOCK_SIZE;
+    use crate::fixture::peer;
+
+    // 每个分片一个块, 内容不重要, 测试中不会校验
+    fn info(files: &[i64]) -> Info {
+        let files = files.iter().enumerate();
+        let files = files.map(|(n, &length)| (format!("{}.bin", n), length));
+        multiple_file("content", BLOCK_SIZE as i64, files)
+    }
+
+    fn bitfield(len: usize, pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(len);
+        for &index in pieces {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    fn indices(blocks: &[BlockRequest]) -> Vec<u32> {
+        blocks.iter().map(|block| block.index).collect()
+    }
+
+    #[test]
+    fn test_availability() {
+        let mut picker = PiecePicker::new(4);
+        picker.add_peer(peer(1), bitfield(4, &[0, 1]));
+        picker.add_peer(peer(2), bitfield(4, &[1]));
+        picker.peer_has(peer(2), 3);
+        picker.peer_has(peer(2), 3);
+        assert_eq!(
+            (0..4).map(|i| picker.availability(i)).collect::<Vec<_>>(),
+            vec![1, 2, 0, 1]
+        );
+        picker.remove_peer(peer(1));
+        assert_eq!(
+            (0..4).map(|i| picker.availability(i)).collect::<Vec<_>>(),
+            vec![0, 1, 0, 1]
+        );
+    }
+
+    #[test]
+    fn test_rarest_first() {
+        let info = info(&[5 * BLOCK_SIZE as i64]);
+        let mut assembler = PieceAssembler::new(&info);
+        let mut picker = PiecePicker::new(5).with_random_first(0);
+        picker.add_peer(peer(1), bitfield(5, &[0, 1, 2, 3, 4]));
+        picker.add_peer(peer(2), bitfield(5, &[0, 1, 2]));
+        picker.add_peer(peer(3), bitfield(5, &[0, 3]));
+        let picked = picker.pick_blocks(peer(1), &mut assembler, 5);
+        assert_eq!(indices(&picked), vec![4, 1, 2, 3, 0]);
+        // 都已经请求过了, 进入 endgame
+        assert!(picker.in_endgame(&assembler));
+        let picked = picker.pick_blocks(peer(2), &mut assembler, 5);
+        assert_eq!(indices(&picked), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_random_first() {
+        let info = info(&[5 * BLOCK_SIZE as i64]);
+        let mut assembler = PieceAssembler::new(&info);
+        let mut picker = PiecePicker::new(5);
+        picker.add_peer(peer(1), bitfield(5, &[0, 1, 2, 3, 4]));
+        picker.set_piece_priority(2, Priority::High);
+        picker.set_piece_priority(4, Priority::Skip);
+        let picked = picker.pick_blocks(peer(1), &mut assembler, 5);
+        // 随机顺序, 但优先级更高的在前面, 跳过的不下载
+        assert_eq!(picked[0].index, 2);
+        let mut rest = indices(&picked[1..]);
+        rest.sort();
+        assert_eq!(rest, vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn test_partial_pieces_first() {
+        // 每个分片两个块
+        let info = multiple_file(
+            "content",
+            2 * BLOCK_SIZE as i64,
+            [("0.bin", 6 * BLOCK_SIZE as i64)],
+        );
+        let mut assembler = PieceAssembler::new(&info);
+        let mut picker = PiecePicker::new(3).with_random_first(0);
+        picker.add_peer(peer(1), bitfield(3, &[0, 1, 2]));
+        picker.add_peer(peer(2), bitfield(3, &[0, 1, 2]));
+        picker.add_peer(peer(3), bitfield(3, &[1, 2]));
+
+        assert_eq!(
+            indices(&picker.pick_blocks(peer(1), &mut assembler, 1)),
+            vec![0]
+        );
+        // peer 2 继续下载分片 0, 然后才是最少的分片
+        let picked = picker.pick_blocks(peer(2), &mut assembler, 3);
+        assert_eq!(indices(&picked), vec![0, 1, 1]);
+        assert_eq!(picked[0].begin, BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_file_priorities() {
+        let info = info(&[BLOCK_SIZE as i64 + 10, 10, 3 * BLOCK_SIZE as i64, 0]);
+        let mut picker = PiecePicker::new(5);
+        picker.set_file_priorities(
+            &info,
+            &[
+                Priority::Skip,
+                Priority::Low,
+                Priority::Skip,
+                Priority::High,
+            ],
+        );
+        // 分片 1 同时属于文件 0, 1, 2
+        assert_eq!(
+            (0..5).map(|i| picker.priority(i)).collect::<Vec<_>>(),
+            vec![
+                Priority::Skip,
+                Priority::Low,
+                Priority::Skip,
+                Priority::Skip,
+                Priority::Skip
+            ]
+        );
+
+        let mut assembler = PieceAssembler::new(&info);
+        picker.add_peer(peer(1), Bitfield::full(5));
+        assert_eq!(
+            indices(&picker.pick_blocks(peer(1), &mut assembler, 5)),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn test_endgame() {
+        let info = info(&[3 * BLOCK_SIZE as i64]);
+        let mut assembler = PieceAssembler::new(&info);
+        let mut picker = PiecePicker::new(3).with_random_first(0);
+        picker.add_peer(peer(1), Bitfield::full(3));
+        picker.add_peer(peer(2), Bitfield::full(3));
+
+        assert_eq!(picker.pick_blocks(peer(1), &mut assembler, 2).len(), 2);
+        assert!(!picker.in_endgame(&assembler));
+        let last = picker.pick_blocks(peer(2), &mut assembler, 2);
+        assert_eq!(last.len(), 1);
+        assert!(picker.in_endgame(&assembler));
+
+        // peer 2 重复请求 peer 1 还没有发来的块
+        let duplicates = picker.pick_blocks(peer(2), &mut assembler, 5);
+        assert_eq!(duplicates.len(), 2);
+        assert!(duplicates.iter().all(|block| !last.contains(block)));
+        assert!(picker.pick_blocks(peer(2), &mut assembler, 5).is_empty());
+
+        let block = duplicates[0];
+        let data = vec![0; block.length as usize];
+        assembler.on_block(peer(2), block.index, block.begin, &data);
+        assert_eq!(assembler.take_cancels(), vec![(peer(1), block)]);
+    }
+
+    #[test]
+    fn test_sequential() {
+        let info = info(&[5 * BLOCK_SIZE as i64]);
+        let mut assembler = PieceAssembler::new(&info);
+        let mut picker = PiecePicker::new(5).with_sequential(true);
+        picker.add_peer(peer(1), Bitfield::full(5));
+        picker.add_peer(peer(2), bitfield(5, &[0, 1]));
+        picker.set_piece_priority(3, Priority::High);
+        let picked = picker.pick_blocks(peer(1), &mut assembler, 5);
+        assert_eq!(indices(&picked), vec![3, 0, 1, 2, 4]);
+    }
+
+    #[test]
+    fn test_deadlines() {
+        let info = info(&[6 * BLOCK_SIZE as i64]);
+        let mut assembler = PieceAssembler::new(&info);
+        let mut picker = PiecePicker::new(6).with_random_first(0);
+        picker.add_peer(peer(1), Bitfield::full(6));
+        picker.add_peer(peer(2), bitfield(6, &[0, 1, 2, 3]));
+        picker.set_piece_priority(0, Priority::High);
+        picker.set_piece_priority(5, Priority::Skip);
+
+        let now = Instant::now();
+        // 第 3 个块到最后: 分片 2..6
+        let bytes = 2 * BLOCK_SIZE as i64 + 100..info.length();
+        picker.set_deadline(info.piece_range(bytes), now + Duration::from_secs(2));
+        picker.set_deadline(3..4, now + Duration::from_millis(500));
+        picker.set_deadline(3..4, now + Duration::from_secs(5));
+        assert_eq!(picker.deadline(3), Some(now + Duration::from_millis(500)));
+        assert_eq!(picker.deadline(0), None);
+
+        // 有期限的优先, 跳过的分片也下载, 期限相同时仍按优先级和稀有程度
+        let picked = picker.pick_blocks(peer(1), &mut assembler, 6);
+        assert_eq!(indices(&picked), vec![3, 4, 2, 5, 0, 1]);
+
+        picker.clear_deadlines();
+        assert_eq!(picker.deadline(3), None);
+    }
+
+    #[test]
+    fn test_unknown_peer() {
+        let info = info(&[BLOCK_SIZE as i64]);
+        let mut assembler = PieceAssembler::new(&info);
+        let picker = PiecePicker::new(1);
+        assert!(picker.pick_blocks(peer(1), &mut assembler, 5).is_empty());
+    }
+}
@@ -0,0 +1,513 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use bencode::{BenObject, Dict};
+use peer::Bitfield;
+use storage::FileState;
+use torrent::{Info, InfoHash};
+
+use crate::assembler::BLOCK_SIZE;
+use crate::error::SwarmError;
+use crate::picker::Priority;
+
+const FILE_FORMAT: &str = "swarm resume file";
+const FILE_VERSION: i64 = 1;
+
+/// What a tracker told us, to carry on announcing where we left off.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerState {
+    pub url: String,
+    /// The `tracker id` to send back in announces.
+    pub tracker_id: Option<String>,
+    /// When we last announced, in seconds since the Unix epoch.
+    pub last_announce: Option<i64>,
+    /// Whether the `completed` event was sent.
+    pub completed: bool,
+}
+
+/// The state of a torrent saved between runs, so it resumes without hashing
+/// its files again.
+///
+/// It is stored as a bencoded dict. Before trusting it,
+/// [`ResumeData::validate`] compares the files it saw with those on disk and
+/// tells which pieces have to be checked again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeData {
+    pub info_hash: InfoHash,
+    /// The verified pieces.
+    pub have: Bitfield,
+    /// The blocks received of pieces not verified yet, one bit per
+    /// [`BLOCK_SIZE`](crate::BLOCK_SIZE) block, as returned by
+    /// [`PieceAssembler::received_blocks`](crate::PieceAssembler::received_blocks).
+    pub partial: BTreeMap<u32, Bitfield>,
+    /// The files when the data was saved, as returned by
+    /// [`Storage::file_states`](storage::Storage::file_states).
+    pub files: Vec<Option<FileState>>,
+    pub file_priorities: Vec<Priority>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub peers: Vec<SocketAddr>,
+    pub trackers: Vec<TrackerState>,
+}
+
+impl ResumeData {
+    /// The state of a torrent that has nothing yet.
+    pub fn new(info_hash: InfoHash, piece_count: usize) -> Self {
+        Self {
+            info_hash,
+            have: Bitfield::new(piece_count),
+            partial: BTreeMap::new(),
+            files: Vec::new(),
+            file_priorities: Vec::new(),
+            uploaded: 0,
+            downloaded: 0,
+            peers: Vec::new(),
+            trackers: Vec::new(),
+        }
+    }
+
+    /// Checks the data against the torrent and the `files` now on disk.
+    ///
+    /// Returns the pieces to check again, which are no longer counted as
+    /// verified or partial. When it is empty, the torrent resumes as saved.
+    /// Everything is checked again if the data is of another torrent.
+    /// Partial pieces that are out of range, verified, or whose block count
+    /// does not match the piece are dropped.
+    pub fn validate(&mut self, info: &Info, files: &[Option<FileState>]) -> Vec<usize> {
+        let piece_count = info.piece_count();
+        let same_torrent = info.info_hash().ok() == Some(self.info_hash)
+            && self.have.len() == piece_count
+            && self.files.len() == files.len();
+        if !same_torrent {
+            self.have = Bitfield::new(piece_count);
+            self.partial.clear();
+            return (0..piece_count).collect();
+        }
+
+        self.partial.retain(|&index, blocks| {
+            let index = index as usize;
+            info.piece_size(index).is_some_and(|size| {
+                !self.have.get(index) && blocks.len() == (size as u32).div_ceil(BLOCK_SIZE) as usize
+            })
+        });
+
+        let ranges = info.file_ranges();
+        let mut recheck = vec![false; piece_count];
+        for ((saved, current), range) in self.files.iter().zip(files).zip(ranges) {
+            if saved != current {
+                for index in info.piece_range(range) {
+                    recheck[index] = true;
+                }
+            }
+        }
+        let pieces: Vec<usize> = (0..piece_count).filter(|&index| recheck[index]).collect();
+        for &index in &pieces {
+            self.have.clear(index);
+            self.partial.remove(&(index as u32));
+        }
+        pieces
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SwarmError> {
+        Ok(BenObject::Dict(self.to_dict()).bencode()?)
+    }
+
+    fn to_dict(&self) -> Dict {
+        let mut dict = Dict::new();
+        dict.insert("file-format".to_owned(), FILE_FORMAT.into());
+        dict.insert("file-version".to_owned(), FILE_VERSION.into());
+        dict.insert("info-hash".to_owned(), self.info_hash.to_string().into());
+        dict.insert("piece-count".to_owned(), (self.have.len() as i64).into());
+        dict.insert("pieces".to_owned(), self.have.as_bytes().into());
+
+        let partial = self
+            .partial
+            .iter()
+            .map(|(&index, blocks)| {
+                BenObject::Dict(Dict::from([
+                    ("piece".to_owned(), index.into()),
+                    ("block-count".to_owned(), (blocks.len() as i64).into()),
+                    ("blocks".to_owned(), blocks.as_bytes().into()),
+                ]))
+            })
+            .collect();
+        dict.insert("partial".to_owned(), BenObject::List(partial));
+
+        // 不存在的文件用空 dict 表示
+        let files = self
+            .files
+            .iter()
+            .map(|state| {
+                BenObject::Dict(match state {
+                    Some(state) => Dict::from([
+                        ("length".to_owned(), saturating_int(state.length)),
+                        ("mtime".to_owned(), state.mtime.into()),
+                    ]),
+                    None => Dict::new(),
+                })
+            })
+            .collect();
+        dict.insert("files".to_owned(), BenObject::List(files));
+        let priorities = self
+            .file_priorities
+            .iter()
+            .map(|&priority| BenObject::Int(priority_to_int(priority)))
+            .collect();
+        dict.insert("priorities".to_owned(), BenObject::List(priorities));
+
+        dict.insert("uploaded".to_owned(), saturating_int(self.uploaded));
+        dict.insert("downloaded".to_owned(), saturating_int(self.downloaded));
+
+        // 与 tracker 的 compact 格式相同
+        let (mut peers, mut peers6) = (Vec::new(), Vec::new());
+        for peer in &self.peers {
+            match peer {
+                SocketAddr::V4(addr) => {
+                    peers.extend_from_slice(&addr.ip().octets());
+                    peers.extend_from_slice(&addr.port().to_be_bytes());
+                }
+                SocketAddr::V6(addr) => {
+                    peers6.extend_from_slice(&addr.ip().octets());
+                    peers6.extend_from_slice(&addr.port().to_be_bytes());
+                }
+            }
+        }
+        dict.insert("peers".to_owned(), peers.into());
+        dict.insert("peers6".to_owned(), peers6.into());
+
+        let trackers = self
+            .trackers
+            .iter()
+            .map(|tracker| {
+                let mut dict = Dict::new();
+                dict.insert("url".to_owned(), tracker.url.as_str().into());
+                if let Some(tracker_id) = &tracker.tracker_id {
+                    dict.insert("tracker-id".to_owned(), tracker_id.as_str().into());
+                }
+                if let Some(last_announce) = tracker.last_announce {
+                    dict.insert("last-announce".to_owned(), last_announce.into());
+                }
+                dict.insert("completed".to_owned(), (tracker.completed as i64).into());
+                BenObject::Dict(dict)
+            })
+            .collect();
+        dict.insert("trackers".to_owned(), BenObject::List(trackers));
+        dict
+    }
+
+    pub fn from_bytes<T>(bytes: T) -> Result<Self, SwarmError>
+    where
+        T: AsRef<[u8]>,
+    {
+        let mut dict = match BenObject::from_bytes(bytes)? {
+            BenObject::Dict(dict) => dict,
+            _ => return Err(invalid("the resume data is not a dict")),
+        };
+        if string(&mut dict, "file-format")? != FILE_FORMAT {
+            return Err(invalid("`file-format` is not a swarm resume file"));
+        }
+        if int(&mut dict, "file-version")? != FILE_VERSION {
+            return Err(invalid("unsupported `file-version`"));
+        }
+        let info_hash = string(&mut dict, "info-hash")?
+            .parse()
+            .map_err(|_| invalid("`info-hash` is not an info hash"))?;
+        let piece_count = int(&mut dict, "piece-count")? as usize;
+        let have = bitfield(&byte_string(&mut dict, "pieces")?, piece_count)?;
+
+        let mut partial = BTreeMap::new();
+        for obj in list(&mut dict, "partial")? {
+            let mut piece = into_dict(obj, "partial")?;
+            let index = int(&mut piece, "piece")? as u32;
+            let count = int(&mut piece, "block-count")? as usize;
+            partial.insert(index, bitfield(&byte_string(&mut piece, "blocks")?, count)?);
+        }
+
+        let mut files = Vec::new();
+        for obj in list(&mut dict, "files")? {
+            let mut file = into_dict(obj, "files")?;
+            files.push(if file.is_empty() {
+                None
+            } else {
+                Some(FileState {
+                    length: int(&mut file, "length")? as u64,
+                    mtime: int(&mut file, "mtime")?,
+                })
+            });
+        }
+        let mut file_priorities = Vec::new();
+        for obj in list(&mut dict, "priorities")? {
+            match obj {
+                BenObject::Int(n) => file_priorities.push(priority_from_int(n)?),
+                _ => return Err(invalid("`priorities` does not map to a list of ints")),
+            }
+        }
+
+        let peers4 = byte_string(&mut dict, "peers")?;
+        let peers6 = byte_string(&mut dict, "peers6")?;
+        if !peers4.len().is_multiple_of(6) || !peers6.len().is_multiple_of(18) {
+            return Err(invalid("`peers` is not a list of compact addresses"));
+        }
+        let mut peers = Vec::new();
+        for addr in peers4.chunks_exact(6) {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addr[..4]).unwrap());
+            peers.push(SocketAddr::new(
+                ip.into(),
+                u16::from_be_bytes([addr[4], addr[5]]),
+            ));
+        }
+        for addr in peers6.chunks_exact(18) {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addr[..16]).unwrap());
+            peers.push(SocketAddr::new(
+                ip.into(),
+                u16::from_be_bytes([addr[16], addr[17]]),
+            ));
+        }
+
+        let mut trackers = Vec::new();
+        for obj in list(&mut dict, "trackers")? {
+            let mut tracker = into_dict(obj, "trackers")?;
+            trackers.push(TrackerState {
+                url: string(&mut tracker, "url")?,
+                tracker_id: optional(&mut tracker, "tracker-id", string)?,
+                last_announce: optional(&mut tracker, "last-announce", int)?,
+                completed: int(&mut tracker, "completed")? != 0,
+            });
+        }
+
+        Ok(Self {
+            info_hash,
+            have,
+            partial,
+            files,
+            file_priorities,
+            uploaded: int(&mut dict, "uploaded")? as u64,
+            downloaded: int(&mut dict, "downloaded")? as u64,
+            peers,
+            trackers,
+        })
+    }
+}
+
+// bencode 的整数是 i64, 超出的部分截断而不是变成负数
+fn saturating_int(n: u64) -> BenObject {
+    BenObject::Int(i64::try_from(n).unwrap_or(i64::MAX))
+}
+
+fn invalid(reason: &'static str) -> SwarmError {
+    SwarmError::InvalidResume(Cow::Borrowed(reason))
+}
+
+fn missing(key: &str) -> SwarmError {
+    SwarmError::InvalidResume(Cow::Owned(format!("`{}` does not exist", key)))
+}
+
+fn wrong_type(key: &str, expected: &str) -> SwarmError {
+    SwarmError::InvalidResume(Cow::Owned(format!(
+        "`{}` does not map to {}",
+        key, expected
+    )))
+}
+
+fn optional<T>(
+    dict: &mut Dict,
+    key: &str,
+    get: fn(&mut Dict, &str) -> Result<T, SwarmError>,
+) -> Result<Option<T>, SwarmError> {
+    if dict.contains_key(key) {
+        get(dict, key).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+fn int(dict: &mut Dict, key: &str) -> Result<i64, SwarmError> {
+    match dict.remove(key) {
+        Some(BenObject::Int(n)) if n >= 0 => Ok(n),
+        Some(_) => Err(wrong_type(key, "a non-negative int")),
+        None => Err(missing(key)),
+    }
+}
+
+fn string(dict: &mut Dict, key: &str) -> Result<String, SwarmError> {
+    match dict.remove(key) {
+        Some(BenObject::String(s)) => Ok(s),
+        Some(_) => Err(wrong_type(key, "string (or maps to invalid UTF8)")),
+        None => Err(missing(key)),
+    }
+}
+
+// 合法 UTF8 的字节串会被解析成 String
+fn byte_string(dict: &mut Dict, key: &str) -> Result<Vec<u8>, SwarmError> {
+    match dict.remove(key) {
+        Some(BenObject::Bytes(bytes)) => Ok(bytes),
+        Some(BenObject::String(s)) => Ok(s.into_bytes()),
+        Some(_) => Err(wrong_type(key, "bytes")),
+        None => Err(missing(key)),
+    }
+}
+
+fn list(dict: &mut Dict, key: &str) -> Result<Vec<BenObject>, SwarmError> {
+    match dict.remove(key) {
+        Some(BenObject::List(list)) => Ok(list),
+        Some(_) => Err(wrong_type(key, "a list")),
+        None => Err(missing(key)),
+    }
+}
+
+fn into_dict(obj: BenObject, key: &str) -> Result<Dict, SwarmError> {
+    match obj {
+        BenObject::Dict(dict) => Ok(dict),
+        _ => Err(wrong_type(key, "a list of dicts")),
+    }
+}
+
+fn bitfield(bytes: &[u8], len: usize) -> Result<Bitfield, SwarmError> {
+    Bitfield::from_bytes(bytes, len).map_err(|_| invalid("a bitfield does not match its length"))
+}
+
+fn priority_to_int(priority: Priority) -> i64 {
+    match priority {
+        Priority::Skip => 0,
+        Priority::Low => 1,
+        Priority::Normal => 2,
+        Priority::High => 3,
+    }
+}
+
+fn priority_from_int(n: i64) -> Result<Priority, SwarmError> {
+    match n {
+        0 => Ok(Priority::Skip),
+        1 => Ok(Priority::Low),
+        2 => Ok(Priority::Normal),
+        3 => Ok(Priority::High),
+        _ => Err(invalid("unknown priority")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use torrent::fixture::multiple_file;
+
+    use super::*;
+
+    // 分片长度 10, 文件 a 是分片 0..2, 文件 b 是分片 1..3
+    fn info() -> Info {
+        multiple_file("album", 10, [("a", 12), ("b", 13)])
+    }
+
+    fn resume() -> ResumeData {
+        let info = info();
+        let mut resume = ResumeData::new(info.info_hash().unwrap(), info.piece_count());
+        resume.have.set(0);
+        resume.have.set(2);
+        let mut blocks = Bitfield::new(1);
+        blocks.set(0);
+        resume.partial.insert(1, blocks);
+        resume.files = vec![
+            Some(FileState {
+                length: 12,
+                mtime: 1_700_000_000,
+            }),
+            None,
+        ];
+        resume.file_priorities = vec![Priority::High, Priority::Skip];
+        resume.uploaded = 1 << 40;
+        resume.downloaded = 25;
+        resume.peers = vec![
+            "10.0.0.1:6881".parse().unwrap(),
+            "[2001:db8::1]:51413".parse().unwrap(),
+        ];
+        resume.trackers = vec![
+            TrackerState {
+                url: "udp://tracker.example:1337/announce".to_owned(),
+                tracker_id: Some("abc".to_owned()),
+                last_announce: Some(1_700_000_100),
+                completed: false,
+            },
+            TrackerState {
+                url: "http://backup.example/announce".to_owned(),
+                tracker_id: None,
+                last_announce: None,
+                completed: true,
+            },
+        ];
+        resume
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let resume = resume();
+        let bytes = resume.to_bytes().unwrap();
+        assert_eq!(ResumeData::from_bytes(&bytes).unwrap(), resume);
+
+        // 超过 i64 的计数截断为 i64::MAX
+        let mut huge = resume.clone();
+        huge.uploaded = u64::MAX;
+        let restored = ResumeData::from_bytes(huge.to_bytes().unwrap()).unwrap();
+        assert_eq!(restored.uploaded, i64::MAX as u64);
+
+        let empty = ResumeData::new(InfoHash::V2([7; 32]), 0);
+        assert_eq!(
+            ResumeData::from_bytes(empty.to_bytes().unwrap()).unwrap(),
+            empty
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(ResumeData::from_bytes(b"i1e").is_err());
+        assert!(ResumeData::from_bytes(b"de").is_err());
+
+        let mut dict = resume().to_dict();
+        dict.insert("file-version".to_owned(), BenObject::Int(2));
+        let bytes = BenObject::Dict(dict).bencode().unwrap();
+        assert!(matches!(
+            ResumeData::from_bytes(bytes),
+            Err(SwarmError::InvalidResume(_))
+        ));
+
+        let mut dict = resume().to_dict();
+        dict.insert("pieces".to_owned(), BenObject::Bytes(vec![0xff]));
+        let bytes = BenObject::Dict(dict).bencode().unwrap();
+        assert!(ResumeData::from_bytes(bytes).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let info = info();
+        let mut resume = resume();
+        let files = resume.files.clone();
+        assert!(resume.validate(&info, &files).is_empty());
+        assert_eq!(resume.have.iter().collect::<Vec<_>>(), [0, 2]);
+
+        // 不属于这个种子的未完成分片被丢掉
+        let saved = resume.partial.clone();
+        resume.partial.insert(0, Bitfield::new(1));
+        resume.partial.insert(3, Bitfield::new(1));
+        let mut resized = resume.clone();
+        resized.partial.insert(1, Bitfield::new(2));
+        assert!(resume.validate(&info, &files).is_empty());
+        assert_eq!(resume.partial, saved);
+        assert!(resized.validate(&info, &files).is_empty());
+        assert!(resized.partial.is_empty());
+
+        // 文件 b 出现了, 分片 1 和 2 要重新校验
+        let mut changed = files.clone();
+        changed[1] = Some(FileState {
+            length: 13,
+            mtime: 1_700_000_200,
+        });
+        assert_eq!(resume.validate(&info, &changed), [1, 2]);
+        assert_eq!(resume.have.iter().collect::<Vec<_>>(), [0]);
+        assert!(resume.partial.is_empty());
+
+        // 其他种子的数据全部重新校验
+        let mut other = ResumeData::new(InfoHash::V1([0; 20]), 3);
+        other.have.set(1);
+        other.files = files.clone();
+        assert_eq!(other.validate(&info, &files), [0, 1, 2]);
+        assert_eq!(other.have.count(), 0);
+    }
+}
@@ -0,0 +1,273 @@
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::ops::Range;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use peer::Bitfield;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::sync::{mpsc, watch};
+use torrent::Info;
+
+type Changed = Pin<Box<dyn Future<Output = Result<(), watch::error::RecvError>> + Send>>;
+
+/// Pieces a [`StreamFile`] is waiting for, to be passed to
+/// [`PiecePicker::set_deadline`](crate::PiecePicker::set_deadline).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deadline {
+    pub pieces: Range<usize>,
+    pub at: Instant,
+}
+
+/// A file of a torrent that can be read while the torrent downloads.
+///
+/// Reads wait until the bytes at the current position are in verified pieces,
+/// as published on the `have` channel by whoever verifies them. Only verified
+/// bytes are ever read from `inner`, the file being downloaded, which must be
+/// positioned at its start.
+///
+/// With [`StreamFile::with_deadlines`], a read that has to wait asks for the
+/// missing piece and a few after it to be downloaded first.
+pub struct StreamFile<R> {
+    inner: R,
+    range: Range<u64>,
+    piece_length: u64,
+    pos: u64,
+    have: watch::Receiver<Bitfield>,
+    changed: Option<Changed>,
+    deadlines: Option<(mpsc::UnboundedSender<Deadline>, Duration)>,
+    read_ahead: usize,
+    requested: Option<usize>,
+}
+
+impl<R> StreamFile<R>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    /// The `file`-th file of the torrent, in the order of [`Info::file_ranges`].
+    ///
+    /// Panics if the torrent has no such file.
+    pub fn new(inner: R, info: &Info, file: usize, have: watch::Receiver<Bitfield>) -> Self {
+        let range = info.file_ranges()[file].clone();
+        Self {
+            inner,
+            range: range.start as u64..range.end as u64,
+            piece_length: info.piece_length() as u64,
+            pos: 0,
+            have,
+            changed: None,
+            deadlines: None,
+            read_ahead: 4,
+            requested: None,
+        }
+    }
+
+    /// Sends a [`Deadline`] `within` from now when a read waits for pieces.
+    pub fn with_deadlines(
+        mut self,
+        deadlines: mpsc::UnboundedSender<Deadline>,
+        within: Duration,
+    ) -> Self {
+        self.deadlines = Some((deadlines, within));
+        self
+    }
+
+    /// How many pieces a deadline covers, starting with the missing one, `4` by default.
+    pub fn with_read_ahead(mut self, pieces: usize) -> Self {
+        self.read_ahead = pieces.max(1);
+        self
+    }
+
+    /// The length of the file.
+    pub fn len(&self) -> u64 {
+        self.range.end - self.range.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The current position within the file.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    // 从当前位置开始, 已经校验过的连续字节数
+    fn available(&mut self) -> u64 {
+        let have = self.have.borrow_and_update();
+        let offset = self.range.start + self.pos;
+        let mut piece = offset / self.piece_length;
+        let mut end = offset;
+        while end < self.range.end && have.get(piece as usize) {
+            piece += 1;
+            end = piece * self.piece_length;
+        }
+        end.min(self.range.end) - offset
+    }
+
+    fn request_deadline(&mut self) {
+        let Some((deadlines, within)) = &self.deadlines else {
+            return;
+        };
+        let first = ((self.range.start + self.pos) / self.piece_length) as usize;
+        // 同一个分片只请求一次
+        if self.requested == Some(first) {
+            return;
+        }
+        let last = ((self.range.end - 1) / self.piece_length) as usize;
+        let deadline = Deadline {
+            pieces: first..(first + self.read_ahead).min(last + 1),
+            at: Instant::now() + *within,
+        };
+        let _ = deadlines.send(deadline);
+        self.requested = Some(first);
+    }
+}
+
+impl<R> AsyncRead for StreamFile<R>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.pos >= this.len() || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            let available = this.available();
+            if available > 0 {
+                let max = available.min(buf.remaining() as u64) as usize;
+                let mut limited = ReadBuf::new(buf.initialize_unfilled_to(max));
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+                let n = limited.filled().len();
+                buf.advance(n);
+                this.pos += n as u64;
+                return Poll::Ready(Ok(()));
+            }
+
+            this.request_deadline();
+            let changed = this.changed.get_or_insert_with(|| {
+                let mut have = this.have.clone();
+                Box::pin(async move { have.changed().await })
+            });
+            let result = ready!(changed.as_mut().poll(cx));
+            this.changed = None;
+            if result.is_err() {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the torrent stopped before the bytes were downloaded",
+                )));
+            }
+        }
+    }
+}
+
+impl<R> AsyncSeek for StreamFile<R>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let target = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => this.len().checked_add_signed(offset),
+            SeekFrom::Current(offset) => this.pos.checked_add_signed(offset),
+        };
+        let Some(target) = target else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            ));
+        };
+        Pin::new(&mut this.inner).start_seek(SeekFrom::Start(target))
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        let pos = ready!(Pin::new(&mut this.inner).poll_complete(cx))?;
+        this.pos = pos;
+        Poll::Ready(Ok(pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+    use torrent::fixture::multiple_file;
+
+    use super::*;
+
+    // 分片长度 16, 文件 1 是内容的 20..50, 即分片 1, 2, 3
+    fn info() -> Info {
+        multiple_file("content", 16, [("0.bin", 20), ("1.bin", 30)])
+    }
+
+    fn data() -> Vec<u8> {
+        (20..50).collect()
+    }
+
+    #[tokio::test]
+    async fn test_read_waits_for_pieces() {
+        let (have_tx, have) = watch::channel(Bitfield::new(4));
+        let (deadlines, mut requested) = mpsc::unbounded_channel();
+        let mut file = StreamFile::new(Cursor::new(data()), &info(), 1, have)
+            .with_deadlines(deadlines, Duration::from_millis(100))
+            .with_read_ahead(2);
+        assert_eq!(file.len(), 30);
+        let reader = tokio::spawn(async move {
+            let mut out = Vec::new();
+            file.read_to_end(&mut out).await.unwrap();
+            out
+        });
+
+        assert_eq!(requested.recv().await.unwrap().pieces, 1..3);
+        // 分片 3 不连续, 还不能读
+        have_tx.send_modify(|have| {
+            have.set(1);
+            have.set(3);
+        });
+        let deadline = requested.recv().await.unwrap();
+        assert_eq!(deadline.pieces, 2..4);
+        assert!(deadline.at > Instant::now());
+        assert!(!reader.is_finished());
+
+        have_tx.send_modify(|have| have.set(2));
+        assert_eq!(reader.await.unwrap(), data());
+    }
+
+    #[tokio::test]
+    async fn test_seek() {
+        let mut have = Bitfield::new(4);
+        have.set(2);
+        have.set(3);
+        let (_have_tx, have) = watch::channel(have);
+        let mut file = StreamFile::new(Cursor::new(data()), &info(), 1, have);
+
+        assert_eq!(file.seek(SeekFrom::Start(12)).await.unwrap(), 12);
+        let mut out = Vec::new();
+        file.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, &data()[12..]);
+
+        assert_eq!(file.seek(SeekFrom::End(-5)).await.unwrap(), 25);
+        assert_eq!(file.read_u8().await.unwrap(), 45);
+        assert_eq!(file.seek(SeekFrom::Current(-2)).await.unwrap(), 24);
+        assert_eq!(file.position(), 24);
+        assert!(file.seek(SeekFrom::Current(-30)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_torrent_stopped() {
+        let (have_tx, have) = watch::channel(Bitfield::new(4));
+        let mut file = StreamFile::new(Cursor::new(data()), &info(), 1, have);
+        drop(have_tx);
+        let err = file.read_u8().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
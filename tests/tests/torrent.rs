@@ -3,6 +3,7 @@ use std::{
     path::PathBuf,
 };
 
+use bencode::Dict;
 use torrent::{File, Info, TorrentFile};
 
 extern crate torrent;
@@ -72,6 +73,25 @@ fn test_parse_multiple_file_torrent() {
             vec!["udp://tracker.thinelephant.org:12750/announce".to_owned()]
         ])
     );
+    // 最后一个分片比 piece length 短
+    let count = parsed.info.piece_count();
+    let piece_length = parsed.info.piece_length();
+    assert_eq!(
+        parsed.info.piece_size(count - 1),
+        Some(parsed.info.length() - (count as i64 - 1) * piece_length)
+    );
+    assert!(parsed.info.piece_size(count - 1).unwrap() < piece_length);
+    assert_eq!(parsed.info.piece_size(0), Some(piece_length));
+    assert_eq!(parsed.info.piece_size(count), None);
+    assert!(parsed.info.piece_hash(count - 1).is_some());
+    assert_eq!(parsed.info.piece_hash(count), None);
+    assert_eq!(parsed.info.piece_range(0..1), 0..1);
+    assert_eq!(parsed.info.piece_range(0..piece_length + 1), 0..2);
+    assert_eq!(parsed.info.piece_range(piece_length..piece_length), 1..1);
+    assert_eq!(
+        parsed.info.piece_range(0..parsed.info.length() + 100),
+        0..count
+    );
 
     let file =
         std::fs::File::open("tests/files/MP3-daily-2022-April-02-Pop-Folk-[rarbg.to].torrent")
@@ -118,105 +138,120 @@ fn test_parse_multiple_file_torrent() {
                     md5sum: None,
                     path: PathBuf::from(
                         r"Filzmooser-Ein_Herz_Voll_Musik-WEB-DE-2006-ALPMP3/00_filzmooser-ein_herz_voll_musik-web-de-2006.jpg"
-                    )
+                    ),
+                    extra: Dict::new(),
                 },
                 File {
                     length: 430,
                     md5sum: None,
                     path: PathBuf::from(
                         r"Filzmooser-Ein_Herz_Voll_Musik-WEB-DE-2006-ALPMP3/00_filzmooser-ein_herz_voll_musik-web-de-2006.m3u"
-                    )
+                    ),
+                    extra: Dict::new(),
                 },
                 File {
                     length: 1771,
                     md5sum: None,
                     path: PathBuf::from(
                         r"Filzmooser-Ein_Herz_Voll_Musik-WEB-DE-2006-ALPMP3/00_filzmooser-ein_herz_voll_musik-web-de-2006.nfo"
-                    )
+                    ),
+                    extra: Dict::new(),
                 },
                 File {
                     length: 8491981,
                     md5sum: None,
                     path: PathBuf::from(
                         r"Filzmooser-Ein_Herz_Voll_Musik-WEB-DE-2006-ALPMP3/01_filzmooser_-_costa_brava.mp3"
-                    )
+                    ),
+                    extra: Dict::new(),
                 },
                 File {
                     length: 6529662,
                     md5sum: None,
                     path: PathBuf::from(
                         r"Filzmooser-Ein_Herz_Voll_Musik-WEB-DE-2006-ALPMP3/02_filzmooser_-_karibik_faszination.mp3"
-                    )
+                    ),
+                    extra: Dict::new(),
                 },
                 File {
                     length: 7435589,
                     md5sum: None,
                     path: PathBuf::from(
                         r"Filzmooser-Ein_Herz_Voll_Musik-WEB-DE-2006-ALPMP3/03_filzmooser_-_mariella.mp3"
-                    )
+                    ),
+                    extra: Dict::new(),
                 },
                 File {
                     length: 7353042,
                     md5sum: None,
                     path: PathBuf::from(
                         r"Filzmooser-Ein_Herz_Voll_Musik-WEB-DE-2006-ALPMP3/04_filzmooser_-_in_der_ferne.mp3"
-                    )
+                    ),
+                    extra: Dict::new(),
                 },
                 File {
                     length: 5453418,
                     md5sum: None,
                     path: PathBuf::from(
                         r"Filzmooser-Ein_Herz_Voll_Musik-WEB-DE-2006-ALPMP3/05_filzmooser_-_i_wait_for_you.mp3"
-                    )
+                    ),
+                    extra: Dict::new(),
                 },
                 File {
                     length: 8535867,
                     md5sum: None,
                     path: PathBuf::from(
                         r"Filzmooser-Ein_Herz_Voll_Musik-WEB-DE-2006-ALPMP3/06_filzmooser_-_guten_tag_sonne.mp3"
-                    )
+                    ),
+                    extra: Dict::new(),
                 },
                 File {
                     length: 7197352,
                     md5sum: None,
                     path: PathBuf::from(
                         r"Filzmooser-Ein_Herz_Voll_Musik-WEB-DE-2006-ALPMP3/07_filzmooser_-_ein_herz_voll_musik.mp3"
-                    )
+                    ),
+                    extra: Dict::new(),
                 },
                 File {
                     length: 6404275,
                     md5sum: None,
                     path: PathBuf::from(
                         r"Filzmooser-Ein_Herz_Voll_Musik-WEB-DE-2006-ALPMP3/08_filzmooser_-_haymos_dance.mp3"
-                    )
+                    ),
+                    extra: Dict::new(),
                 },
                 File {
                     length: 7979981,
                     md5sum: None,
                     path: PathBuf::from(
                         r"Filzmooser-Ein_Herz_Voll_Musik-WEB-DE-2006-ALPMP3/09_filzmooser_-_save_your_love.mp3"
-                    )
+                    ),
+                    extra: Dict::new(),
                 },
                 File {
                     length: 7909973,
                     md5sum: None,
                     path: PathBuf::from(
                         r"Filzmooser-Ein_Herz_Voll_Musik-WEB-DE-2006-ALPMP3/10_filzmooser_-_hula_cha_cha.mp3"
-                    )
+                    ),
+                    extra: Dict::new(),
                 },
                 File {
                     length: 7418914,
                     md5sum: None,
                     path: PathBuf::from(
                         r"Filzmooser-Ein_Herz_Voll_Musik-WEB-DE-2006-ALPMP3/11_filzmooser_-_nicht_jeder_tag_bringt_sonnenschein.mp3"
-                    )
+                    ),
+                    extra: Dict::new(),
                 },
                 File {
                     length: 7000911,
                     md5sum: None,
                     path: PathBuf::from(
                         r"Filzmooser-Ein_Herz_Voll_Musik-WEB-DE-2006-ALPMP3/12_filzmooser_-_pegasus.mp3"
-                    )
+                    ),
+                    extra: Dict::new(),
                 },
             ]
         )
@@ -224,3 +259,33 @@ fn test_parse_multiple_file_torrent() {
         panic!("not a multiple file torrent")
     }
 }
+
+#[test]
+fn test_bencode_round_trip() {
+    for path in [
+        "tests/files/debian-iso.torrent",
+        "tests/files/debian-11.3.0-amd64-netinst.iso.torrent",
+        "tests/files/MP3-daily-2022-April-02-Electronic-[rarbg.to].torrent",
+        "tests/files/MP3-daily-2022-April-02-Pop-Folk-[rarbg.to].torrent",
+        "tests/files/multiple-file-no-announce.torrent",
+    ] {
+        let bytes = std::fs::read(path).unwrap();
+        let mut parsed = TorrentFile::parse(&bytes).unwrap();
+        assert_eq!(parsed.bencode().unwrap(), bytes, "{}", path);
+
+        // 编辑 tracker 和 comment 不影响其它字段
+        let info_hash = parsed.info.info_hash().unwrap();
+        parsed.set_comment(None);
+        parsed.add_tier(vec!["udp://tracker.example.org:6969/announce".to_owned()]);
+        let edited = TorrentFile::parse(parsed.bencode().unwrap()).unwrap();
+        assert_eq!(edited, parsed);
+        assert_eq!(edited.info.info_hash().unwrap(), info_hash);
+    }
+}
+
+#[test]
+fn test_parse_corrupted_torrent() {
+    // 这个文件保存时按 UTF8 转换过, pieces 里的很多字节变成了 U+FFFD, 长度对不上
+    let bytes = std::fs::read("tests/files/archlinux-2019.12.01-x86_64.iso.torrent").unwrap();
+    assert!(TorrentFile::parse(bytes).is_err());
+}
@@ -1,8 +1,8 @@
 use std::io::{BufReader, Read};
 use std::time::Duration;
-use tracker::Request;
+use tracker::{Request, Session, TransferStats};
 
-use torrent::{Info, TorrentFile};
+use torrent::{Info, InfoHash, TorrentFile};
 use tracker::Tracker;
 
 extern crate torrent;
@@ -27,31 +27,16 @@ async fn find_peers_from_single_file_torrent() {
     let expect_info_hash: [u8; 20] = [
         177, 17, 129, 60, 230, 15, 66, 145, 151, 52, 130, 61, 245, 236, 32, 189, 30, 4, 231, 247,
     ];
-    let info_hash = parsed.info.hash_bytes().unwrap();
-    assert_eq!(info_hash, expect_info_hash);
+    let info_hash = parsed.info.info_hash().unwrap();
+    assert_eq!(info_hash, InfoHash::V1(expect_info_hash));
+    let req = Request::for_torrent(&parsed, &Session::default(), TransferStats::default()).unwrap();
+    assert_eq!(req.left, 396361728);
     if let Info::SingleFile(single) = parsed.info {
         assert_eq!(single.name, "debian-11.3.0-amd64-netinst.iso".to_owned());
         assert_eq!(single.length, 396361728);
         assert_eq!(single.piece_length, 262144);
         let tracker = Tracker::new(parsed.announce.parse().unwrap());
-        let resp = tracker
-            .find_peers(Request {
-                info_hash,
-                peer_id: b"cbt-2022-03-03-00000".to_owned(),
-                port: 6881,
-                uploaded: 0,
-                downloaded: 0,
-                left: single.length,
-                compact: 1,
-                no_peer_id: None,
-                event: None,
-                numwant: None,
-                key: None,
-                tracker_id: None,
-                ip: None,
-            })
-            .await
-            .unwrap();
+        let resp = tracker.find_peers(req).await.unwrap();
         assert_eq!(resp.interval, Duration::from_secs(900));
 
         assert_eq!(resp.peers.len(), 50);
@@ -91,7 +76,7 @@ async fn find_peers_from_multiple_file_torrent() {
     //         vec!["http://res.nana.hdq.me:1313/announce".to_owned()],
     //     ])
     // );
-    let info_hash = parsed.info.hash_bytes().unwrap();
+    let req = Request::for_torrent(&parsed, &Session::default(), TransferStats::default()).unwrap();
     if let Info::MultipleFile(multiple) = parsed.info {
         // assert_eq!(multiple.piece_length, 8388608);
         // assert_eq!(multiple.name, "行尸走肉2".to_owned());
@@ -100,25 +85,9 @@ async fn find_peers_from_multiple_file_torrent() {
         for file in multiple.files {
             total_length += file.length;
         }
+        assert_eq!(req.left, total_length);
         let tracker = Tracker::new(parsed.announce.parse().unwrap());
-        let resp = tracker
-            .find_peers(Request {
-                info_hash,
-                peer_id: b"cbt-2022-03-03-00000".to_owned(),
-                port: 6881,
-                uploaded: 0,
-                downloaded: 0,
-                left: total_length,
-                compact: 1,
-                no_peer_id: None,
-                event: None,
-                numwant: None,
-                key: None,
-                tracker_id: None,
-                ip: None,
-            })
-            .await
-            .unwrap();
+        let resp = tracker.find_peers(req).await.unwrap();
         dbg!(&resp);
         assert_eq!(resp.interval, Duration::from_secs(900));
 
@@ -0,0 +1,294 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::net::{TcpListener, UdpSocket};
+use torrent::InfoHash;
+use tracker::error::TrackerError;
+use tracker::{Event, Request, Tracker, TrackerPeer};
+use tracker_server::{ServerConfig, TrackerServer, UserStats, Whitelist};
+
+extern crate torrent;
+extern crate tracker;
+extern crate tracker_server;
+
+// 在同一个端口上启动 HTTP 和 UDP tracker
+async fn start_server(config: ServerConfig) -> SocketAddr {
+    serve(TrackerServer::new(config)).await
+}
+
+async fn serve(server: TrackerServer) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let socket = UdpSocket::bind(addr).await.unwrap();
+    let udp = server.clone();
+    tokio::spawn(async move { server.serve_http(listener).await });
+    tokio::spawn(async move { udp.serve_udp(socket).await });
+    addr
+}
+
+fn request(peer: u8, port: usize, left: i64, event: Option<Event>) -> Request {
+    Request {
+        info_hash: InfoHash::V1([0xab; 20]),
+        peer_id: [peer; 20],
+        port,
+        uploaded: 0,
+        downloaded: 0,
+        left,
+        compact: 1,
+        no_peer_id: None,
+        event,
+        ip: None,
+        ipv4: None,
+        ipv6: None,
+        numwant: None,
+        key: None,
+        tracker_id: None,
+    }
+}
+
+#[tokio::test]
+async fn announce_and_scrape_over_http() {
+    let addr = start_server(ServerConfig::default().with_interval(Duration::from_secs(600))).await;
+    let tracker = Tracker::new(format!("http://{}/announce", addr).parse().unwrap());
+
+    let resp = tracker
+        .find_peers(request(1, 6881, 0, Some(Event::Started)))
+        .await
+        .unwrap();
+    assert_eq!(resp.interval, Duration::from_secs(600));
+    assert_eq!(resp.min_interval, Some(Duration::from_secs(300)));
+    assert_eq!((resp.complete, resp.incomplete), (Some(1), Some(0)));
+    assert!(resp.peers.is_empty());
+
+    let mut req = request(2, 6882, 100, Some(Event::Started));
+    req.ipv6 = Some("2001:db8::2".parse().unwrap());
+    tracker.find_peers(req).await.unwrap();
+
+    // compact 格式没有 peer id, IPv6 地址在 peers6 里
+    let resp = tracker.find_peers(request(1, 6881, 0, None)).await.unwrap();
+    assert_eq!((resp.complete, resp.incomplete), (Some(1), Some(1)));
+    assert_eq!(
+        resp.peers,
+        vec![
+            "127.0.0.1:6882".parse::<SocketAddr>().unwrap().into(),
+            "[2001:db8::2]:6882".parse::<SocketAddr>().unwrap().into(),
+        ]
+    );
+
+    // 非 compact 格式带着 peer id
+    let mut req = request(1, 6881, 0, None);
+    req.compact = 0;
+    let resp = tracker.find_peers(req).await.unwrap();
+    assert!(resp.peers.contains(&TrackerPeer {
+        addr: "127.0.0.1:6882".parse().unwrap(),
+        peer_id: Some([2; 20]),
+    }));
+    let mut req = request(1, 6881, 0, None);
+    req.compact = 0;
+    req.no_peer_id = Some(true);
+    let resp = tracker.find_peers(req).await.unwrap();
+    assert!(resp.peers.iter().all(|peer| peer.peer_id.is_none()));
+
+    tracker
+        .find_peers(request(2, 6882, 0, Some(Event::Completed)))
+        .await
+        .unwrap();
+    let files = tracker
+        .scrape(&[InfoHash::V1([0xab; 20]), InfoHash::V1([0xcd; 20])])
+        .await
+        .unwrap();
+    assert_eq!(files.len(), 1);
+    let file = &files[&InfoHash::V1([0xab; 20])];
+    assert_eq!((file.complete, file.downloaded, file.incomplete), (2, 1, 0));
+
+    tracker
+        .find_peers(request(2, 6882, 0, Some(Event::Stopped)))
+        .await
+        .unwrap();
+    let resp = tracker.find_peers(request(1, 6881, 0, None)).await.unwrap();
+    assert!(resp.peers.is_empty());
+}
+
+#[tokio::test]
+async fn numwant_limits_peers() {
+    let addr = start_server(ServerConfig::default().with_max_numwant(5)).await;
+    let tracker = Tracker::new(format!("http://{}/announce", addr).parse().unwrap());
+    for peer in 0..10 {
+        tracker
+            .find_peers(request(peer, 7000 + peer as usize, 100, None))
+            .await
+            .unwrap();
+    }
+    let mut req = request(0, 7000, 100, None);
+    req.numwant = Some(3);
+    assert_eq!(tracker.find_peers(req).await.unwrap().peers.len(), 3);
+    let mut req = request(0, 7000, 100, None);
+    req.numwant = Some(50);
+    assert_eq!(tracker.find_peers(req).await.unwrap().peers.len(), 5);
+}
+
+#[tokio::test]
+async fn invalid_announce_is_a_failure() {
+    let addr = start_server(ServerConfig::default()).await;
+    let url = format!(
+        "http://{}/announce?info_hash=short&peer_id=cbt-2022-03-03-00000&port=1",
+        addr
+    );
+    let body = reqwest::get(url).await.unwrap().bytes().await.unwrap();
+    assert_eq!(&body[..], b"d14:failure reason17:invalid info_hashe");
+
+    let resp = reqwest::get(format!("http://{}/other", addr))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn announce_and_scrape_over_udp() {
+    let addr = start_server(ServerConfig::default()).await;
+    let tracker = Tracker::new(format!("udp://{}/announce", addr).parse().unwrap());
+
+    tracker
+        .find_peers(request(1, 6881, 0, Some(Event::Started)))
+        .await
+        .unwrap();
+    let resp = tracker
+        .find_peers(request(2, 6882, 100, Some(Event::Started)))
+        .await
+        .unwrap();
+    assert_eq!(resp.interval, Duration::from_secs(1800));
+    assert_eq!((resp.complete, resp.incomplete), (Some(1), Some(1)));
+    assert_eq!(
+        resp.peers,
+        vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap().into()]
+    );
+
+    let files = tracker
+        .scrape(&[InfoHash::V1([0xab; 20]), InfoHash::V1([0xcd; 20])])
+        .await
+        .unwrap();
+    let file = &files[&InfoHash::V1([0xab; 20])];
+    assert_eq!((file.complete, file.incomplete), (1, 1));
+    assert_eq!(files[&InfoHash::V1([0xcd; 20])].complete, 0);
+
+    // HTTP 和 UDP 共用一个 swarm
+    let http = Tracker::new(format!("http://{}/announce", addr).parse().unwrap());
+    let resp = http.find_peers(request(3, 6883, 100, None)).await.unwrap();
+    assert_eq!(resp.peers.len(), 2);
+}
+
+#[tokio::test]
+async fn udp_rejects_unknown_connection_id() {
+    let addr = start_server(ServerConfig::default()).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(addr).await.unwrap();
+    let mut packet = Vec::new();
+    packet.extend_from_slice(&42u64.to_be_bytes());
+    packet.extend_from_slice(&2u32.to_be_bytes());
+    packet.extend_from_slice(&7u32.to_be_bytes());
+    packet.extend_from_slice(&[0xab; 20]);
+    socket.send(&packet).await.unwrap();
+
+    let mut buf = [0; 64];
+    let len = socket.recv(&mut buf).await.unwrap();
+    assert_eq!(&buf[..4], &3u32.to_be_bytes());
+    assert_eq!(&buf[4..8], &7u32.to_be_bytes());
+    assert_eq!(&buf[8..len], b"invalid connection id");
+}
+
+fn assert_failure<T: std::fmt::Debug>(result: Result<T, TrackerError>, reason: &str) {
+    match result {
+        Err(TrackerError::Failure(msg)) => assert_eq!(msg, reason),
+        other => panic!("expected failure {:?}, got {:?}", reason, other),
+    }
+}
+
+#[tokio::test]
+async fn private_tracker_requires_passkey() {
+    let server = TrackerServer::new(ServerConfig::default().with_passkeys(["alice"]));
+    server.accounts().add_user("bob");
+    let addr = serve(server.clone()).await;
+
+    let alice = Tracker::new(format!("http://{}/alice/announce", addr).parse().unwrap());
+    let mut req = request(1, 6881, 1000, Some(Event::Started));
+    alice.find_peers(req.clone()).await.unwrap();
+    req.event = None;
+    req.uploaded = 300;
+    req.downloaded = 200;
+    alice.find_peers(req.clone()).await.unwrap();
+    req.event = Some(Event::Stopped);
+    req.uploaded = 500;
+    req.downloaded = 1000;
+    alice.find_peers(req).await.unwrap();
+    assert_eq!(
+        server.accounts().stats("alice"),
+        Some(UserStats {
+            uploaded: 500,
+            downloaded: 1000,
+        })
+    );
+    assert_eq!(server.accounts().stats("bob"), Some(UserStats::default()));
+
+    let bob = Tracker::new(format!("http://{}/bob/announce", addr).parse().unwrap());
+    bob.find_peers(request(2, 6882, 0, None)).await.unwrap();
+    assert_eq!(
+        bob.scrape(&[InfoHash::V1([0xab; 20])]).await.unwrap().len(),
+        1
+    );
+
+    for url in [
+        format!("http://{}/mallory/announce", addr),
+        format!("http://{}/announce", addr),
+    ] {
+        let tracker = Tracker::new(url.parse().unwrap());
+        assert_failure(
+            tracker.find_peers(request(3, 6883, 0, None)).await,
+            "unknown passkey",
+        );
+        assert_failure(
+            tracker.scrape(&[InfoHash::V1([0xab; 20])]).await,
+            "unknown passkey",
+        );
+    }
+
+    let udp = Tracker::new(format!("udp://{}/announce", addr).parse().unwrap());
+    assert!(udp.find_peers(request(3, 6883, 0, None)).await.is_err());
+}
+
+#[tokio::test]
+async fn whitelist_rejects_unregistered_torrents() {
+    let torrent = torrent::TorrentFile::parse(include_bytes!(
+        "files/debian-11.3.0-amd64-netinst.iso.torrent"
+    ))
+    .unwrap();
+    let info_hash = torrent.info.info_hash().unwrap();
+    let whitelist: Whitelist = [info_hash].into_iter().collect();
+    let addr = start_server(ServerConfig::default().with_whitelist(whitelist)).await;
+
+    for (peer, url) in [
+        (1, format!("http://{}/announce", addr)),
+        (2, format!("udp://{}/announce", addr)),
+    ] {
+        let tracker = Tracker::new(url.parse().unwrap());
+        let mut req = request(peer, 6880 + peer as usize, 0, Some(Event::Started));
+        req.info_hash = info_hash;
+        tracker.find_peers(req).await.unwrap();
+
+        let result = tracker
+            .find_peers(request(peer, 6880 + peer as usize, 0, None))
+            .await;
+        if url.starts_with("http") {
+            assert_failure(result, "unregistered torrent");
+        } else {
+            assert!(result.is_err());
+        }
+    }
+
+    let http = Tracker::new(format!("http://{}/announce", addr).parse().unwrap());
+    let files = http
+        .scrape(&[info_hash, InfoHash::V1([0xab; 20])])
+        .await
+        .unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[&info_hash].complete, 2);
+}
@@ -0,0 +1,314 @@
+use super::*;
+
+/// Reports what an edit did to the info hash.
+///
+/// Editing the info dict makes a different torrent as far as trackers and
+/// peers are concerned: it has to be re-announced and cannot join the old swarm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InfoHashChange {
+    Unchanged,
+    Changed { old: InfoHash, new: InfoHash },
+}
+
+impl InfoHashChange {
+    pub fn is_changed(&self) -> bool {
+        matches!(self, Self::Changed { .. })
+    }
+}
+
+impl Info {
+    pub fn is_private(&self) -> bool {
+        self.private_flag().unwrap_or(0) == 1
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        match self {
+            Self::SingleFile(single) => single.source.as_deref(),
+            Self::MultipleFile(multiple) => multiple.source.as_deref(),
+        }
+    }
+
+    fn private_flag(&self) -> Option<i64> {
+        match self {
+            Self::SingleFile(single) => single.private,
+            Self::MultipleFile(multiple) => multiple.private,
+        }
+    }
+
+    fn private_mut(&mut self) -> &mut Option<i64> {
+        match self {
+            Self::SingleFile(single) => &mut single.private,
+            Self::MultipleFile(multiple) => &mut multiple.private,
+        }
+    }
+
+    fn source_mut(&mut self) -> &mut Option<String> {
+        match self {
+            Self::SingleFile(single) => &mut single.source,
+            Self::MultipleFile(multiple) => &mut multiple.source,
+        }
+    }
+}
+
+// 编辑 tracker、comment、created by 不会改变 info hash, 编辑 info 里的字段会
+impl TorrentFile {
+    /// The announce tiers (BEP 12). Torrents without `announce-list` have a single tier holding `announce`.
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        match &self.announce_list {
+            Some(list) => list.clone(),
+            None if self.announce.is_empty() => Vec::new(),
+            None => vec![vec![self.announce.clone()]],
+        }
+    }
+
+    /// Appends a tier with the lowest priority.
+    pub fn add_tier(&mut self, tier: Vec<String>) {
+        let mut tiers = self.tiers();
+        tiers.push(tier);
+        self.set_tiers(tiers);
+    }
+
+    /// Inserts a tier at `index`, `0` being the tier tried first.
+    pub fn insert_tier(&mut self, index: usize, tier: Vec<String>) {
+        let mut tiers = self.tiers();
+        tiers.insert(index.min(tiers.len()), tier);
+        self.set_tiers(tiers);
+    }
+
+    pub fn remove_tier(&mut self, index: usize) -> Option<Vec<String>> {
+        let mut tiers = self.tiers();
+        if index >= tiers.len() {
+            return None;
+        }
+        let removed = tiers.remove(index);
+        self.set_tiers(tiers);
+        Some(removed)
+    }
+
+    pub fn move_tier(&mut self, from: usize, to: usize) {
+        let mut tiers = self.tiers();
+        if from >= tiers.len() {
+            return;
+        }
+        let tier = tiers.remove(from);
+        tiers.insert(to.min(tiers.len()), tier);
+        self.set_tiers(tiers);
+    }
+
+    /// Adds a tracker to the tier at `tier`, creating a new last tier when it does not exist.
+    pub fn add_tracker(&mut self, tier: usize, url: String) {
+        let mut tiers = self.tiers();
+        match tiers.get_mut(tier) {
+            Some(urls) if !urls.contains(&url) => urls.push(url),
+            Some(_) => return,
+            None => tiers.push(vec![url]),
+        }
+        self.set_tiers(tiers);
+    }
+
+    /// Removes a tracker from every tier. Returns whether it was present.
+    pub fn remove_tracker(&mut self, url: &str) -> bool {
+        let mut tiers = self.tiers();
+        let mut removed = false;
+        for tier in tiers.iter_mut() {
+            let len = tier.len();
+            tier.retain(|u| u != url);
+            removed |= tier.len() != len;
+        }
+        if removed {
+            self.set_tiers(tiers);
+        }
+        removed
+    }
+
+    pub fn set_comment(&mut self, comment: Option<String>) {
+        self.comment = comment;
+    }
+
+    pub fn set_created_by(&mut self, created_by: Option<String>) {
+        self.created_by = created_by;
+    }
+
+    /// Sets the `source` key of the info dict, used by private trackers to tell cross-seeded copies apart.
+    pub fn set_source(&mut self, source: Option<String>) -> Result<InfoHashChange, TorrentError> {
+        self.edit_info(|info| *info.source_mut() = source)
+    }
+
+    /// Sets or clears the `private` flag (BEP 27).
+    pub fn set_private(&mut self, private: bool) -> Result<InfoHashChange, TorrentError> {
+        self.edit_info(|info| *info.private_mut() = if private { Some(1) } else { None })
+    }
+
+    fn edit_info<F>(&mut self, edit: F) -> Result<InfoHashChange, TorrentError>
+    where
+        F: FnOnce(&mut Info),
+    {
+        let old = self.info.info_hash()?;
+        edit(&mut self.info);
+        let new = self.info.info_hash()?;
+        if old == new {
+            Ok(InfoHashChange::Unchanged)
+        } else {
+            Ok(InfoHashChange::Changed { old, new })
+        }
+    }
+
+    // announce 总是第一个 tier 的第一个 tracker, 只剩一个 tracker 时不再写 announce-list
+    fn set_tiers(&mut self, mut tiers: Vec<Vec<String>>) {
+        tiers.retain(|tier| !tier.is_empty());
+        self.announce = tiers
+            .first()
+            .map(|tier| tier[0].clone())
+            .unwrap_or_default();
+        self.announce_list = match tiers.as_slice() {
+            [] => None,
+            [tier] if tier.len() == 1 => None,
+            _ => Some(tiers),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torrent() -> TorrentFile {
+        TorrentFile {
+            info: Info::SingleFile(SingleFile {
+                piece_length: 262144,
+                pieces: vec![0xff; 20],
+                private: None,
+                name: "startdusk".to_owned(),
+                source: None,
+                length: 100,
+                md5sum: None,
+                extra: Dict::new(),
+            }),
+            announce: "http://a.example/announce".to_owned(),
+            announce_list: None,
+            creation_date: Some(1648300186),
+            comment: Some("comment".to_owned()),
+            created_by: None,
+            encoding: None,
+            extra: Dict::from([(
+                "url-list".to_owned(),
+                BenObject::String("http://seed.example/".to_owned()),
+            )]),
+        }
+    }
+
+    fn urls(tiers: &[&[&str]]) -> Vec<Vec<String>> {
+        tiers
+            .iter()
+            .map(|tier| tier.iter().map(|url| url.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_tiers() {
+        let mut torrent = torrent();
+        assert_eq!(torrent.tiers(), urls(&[&["http://a.example/announce"]]));
+
+        torrent.add_tier(vec!["udp://b.example:80".to_owned()]);
+        torrent.add_tracker(1, "udp://c.example:80".to_owned());
+        torrent.add_tracker(1, "udp://c.example:80".to_owned());
+        assert_eq!(
+            torrent.announce_list,
+            Some(urls(&[
+                &["http://a.example/announce"],
+                &["udp://b.example:80", "udp://c.example:80"],
+            ]))
+        );
+
+        torrent.move_tier(1, 0);
+        assert_eq!(torrent.announce, "udp://b.example:80");
+
+        torrent.insert_tier(1, vec!["http://d.example/announce".to_owned()]);
+        assert_eq!(
+            torrent.tiers(),
+            urls(&[
+                &["udp://b.example:80", "udp://c.example:80"],
+                &["http://d.example/announce"],
+                &["http://a.example/announce"],
+            ])
+        );
+
+        assert!(torrent.remove_tracker("udp://b.example:80"));
+        assert!(!torrent.remove_tracker("udp://b.example:80"));
+        assert_eq!(torrent.announce, "udp://c.example:80");
+
+        assert_eq!(
+            torrent.remove_tier(0),
+            Some(vec!["udp://c.example:80".to_owned()])
+        );
+        assert_eq!(torrent.remove_tier(5), None);
+        assert_eq!(torrent.announce, "http://d.example/announce");
+
+        assert!(torrent.remove_tracker("http://d.example/announce"));
+        assert_eq!(torrent.announce, "http://a.example/announce");
+        assert_eq!(torrent.announce_list, None);
+
+        assert!(torrent.remove_tracker("http://a.example/announce"));
+        assert!(torrent.tiers().is_empty());
+    }
+
+    #[test]
+    fn test_edits_keep_info_hash() {
+        let mut torrent = torrent();
+        let hash = torrent.info.info_hash().unwrap();
+        torrent.set_comment(None);
+        torrent.set_created_by(Some("rs-torrent".to_owned()));
+        torrent.add_tier(vec!["udp://b.example:80".to_owned()]);
+        assert_eq!(torrent.info.info_hash().unwrap(), hash);
+        assert_eq!(
+            torrent.set_private(false).unwrap(),
+            InfoHashChange::Unchanged
+        );
+    }
+
+    #[test]
+    fn test_info_edits_change_info_hash() {
+        let mut torrent = torrent();
+        let original = torrent.info.info_hash().unwrap();
+
+        let change = torrent.set_private(true).unwrap();
+        assert!(torrent.info.is_private());
+        let private = torrent.info.info_hash().unwrap();
+        assert_eq!(
+            change,
+            InfoHashChange::Changed {
+                old: original,
+                new: private
+            }
+        );
+
+        let change = torrent.set_source(Some("TRACKER".to_owned())).unwrap();
+        assert!(change.is_changed());
+        assert_eq!(torrent.info.source(), Some("TRACKER"));
+        assert_eq!(
+            torrent.set_source(Some("TRACKER".to_owned())).unwrap(),
+            InfoHashChange::Unchanged
+        );
+
+        torrent.set_source(None).unwrap();
+        let change = torrent.set_private(false).unwrap();
+        assert_eq!(
+            change,
+            InfoHashChange::Changed {
+                old: private,
+                new: original
+            }
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut torrent = torrent();
+        torrent.add_tier(vec!["udp://b.example:80".to_owned()]);
+        torrent.set_source(Some("TRACKER".to_owned())).unwrap();
+        let bytes = torrent.bencode().unwrap();
+        let parsed = TorrentFile::parse(&bytes).unwrap();
+        assert_eq!(parsed, torrent);
+        assert_eq!(parsed.bencode().unwrap(), bytes);
+    }
+}
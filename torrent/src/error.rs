@@ -8,6 +8,8 @@ pub enum TorrentError {
 	InvalidTorrent,
 	#[error("torrent info error: {0}")]
 	InvalidTorrentInfo(::std::borrow::Cow<'static, str>),
+	#[error("invalid info hash: {0}")]
+	InvalidInfoHash(::std::borrow::Cow<'static, str>),
 	#[error(transparent)]
 	Utf8Error(#[from] ::std::string::FromUtf8Error),
 	#[error(transparent)]
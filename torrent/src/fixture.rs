@@ -0,0 +1,38 @@
+//! Torrents built in code, for the tests of this and the dependent crates.
+//! Enabled by the `fixture` feature.
+
+use std::path::PathBuf;
+
+use bencode::Dict;
+
+use crate::{File, Info, MultipleFile};
+
+/// A multi-file torrent named `name` made of `files` (path and length).
+///
+/// The piece hashes are all zero, replace `pieces` when the content is verified.
+pub fn multiple_file<P: Into<PathBuf>>(
+    name: &str,
+    piece_length: i64,
+    files: impl IntoIterator<Item = (P, i64)>,
+) -> Info {
+    let files: Vec<File> = files
+        .into_iter()
+        .map(|(path, length)| File {
+            length,
+            md5sum: None,
+            path: path.into(),
+            extra: Dict::new(),
+        })
+        .collect();
+    let length: i64 = files.iter().map(|file| file.length).sum();
+    let count = (length as u64).div_ceil(piece_length as u64) as usize;
+    Info::MultipleFile(MultipleFile {
+        piece_length,
+        pieces: vec![0; count * 20],
+        private: None,
+        name: name.to_owned(),
+        source: None,
+        files,
+        extra: Dict::new(),
+    })
+}
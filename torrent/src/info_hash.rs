@@ -0,0 +1,260 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+use data_encoding::{BASE32, BASE32_NOPAD, HEXLOWER_PERMISSIVE, HEXUPPER};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::TorrentError;
+
+pub type Sha1Hash = [u8; 20];
+pub type Sha256Hash = [u8; 32];
+
+/// The identity of a torrent.
+///
+/// v1 torrents (BEP 3) are identified by the SHA-1 of the bencoded info dict,
+/// v2 torrents (BEP 52) by its SHA-256, and hybrid torrents carry both.
+/// Wherever the protocol only has room for 20 bytes (tracker announces, the
+/// peer handshake, the DHT) a v2 hash is truncated, see [`InfoHash::truncated`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum InfoHash {
+    V1(Sha1Hash),
+    V2(Sha256Hash),
+    Hybrid { v1: Sha1Hash, v2: Sha256Hash },
+}
+
+impl InfoHash {
+    pub fn v1(&self) -> Option<&Sha1Hash> {
+        match self {
+            Self::V1(v1) | Self::Hybrid { v1, .. } => Some(v1),
+            Self::V2(_) => None,
+        }
+    }
+
+    pub fn v2(&self) -> Option<&Sha256Hash> {
+        match self {
+            Self::V2(v2) | Self::Hybrid { v2, .. } => Some(v2),
+            Self::V1(_) => None,
+        }
+    }
+
+    /// The 20 bytes used on the wire: the SHA-1 hash when there is one,
+    /// otherwise the first 20 bytes of the SHA-256 hash.
+    pub fn truncated(&self) -> Sha1Hash {
+        match self {
+            Self::V1(v1) | Self::Hybrid { v1, .. } => *v1,
+            Self::V2(v2) => {
+                let mut truncated = [0; 20];
+                truncated.copy_from_slice(&v2[..20]);
+                truncated
+            }
+        }
+    }
+
+    /// Base32 form used by `urn:btih:` magnet links.
+    ///
+    /// This is lossy for hybrid torrents: only the v1 hash is encoded, so it
+    /// parses back as [`InfoHash::V1`].
+    pub fn to_base32(&self) -> String {
+        match self {
+            Self::V1(v1) | Self::Hybrid { v1, .. } => BASE32.encode(v1),
+            Self::V2(v2) => BASE32_NOPAD.encode(v2),
+        }
+    }
+
+    fn bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            Self::V1(v1) => Cow::Borrowed(v1),
+            Self::V2(v2) => Cow::Borrowed(v2),
+            Self::Hybrid { v1, v2 } => Cow::Owned([&v1[..], &v2[..]].concat()),
+        }
+    }
+}
+
+impl From<Sha1Hash> for InfoHash {
+    fn from(v1: Sha1Hash) -> InfoHash {
+        InfoHash::V1(v1)
+    }
+}
+
+impl From<Sha256Hash> for InfoHash {
+    fn from(v2: Sha256Hash) -> InfoHash {
+        InfoHash::V2(v2)
+    }
+}
+
+// 十六进制: v1 40 个字符, v2 64 个字符, hybrid 是 v1 和 v2 拼起来的 104 个字符
+impl fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&HEXLOWER_PERMISSIVE.encode(&self.bytes()))
+    }
+}
+
+impl fmt::UpperHex for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&HEXUPPER.encode(&self.bytes()))
+    }
+}
+
+impl FromStr for InfoHash {
+    type Err = TorrentError;
+
+    /// Accepts the hex forms written by `Display`, and base32 v1 (32 chars) or v2 (52 chars) hashes.
+    fn from_str(s: &str) -> Result<InfoHash, TorrentError> {
+        let decoded = match s.len() {
+            40 | 64 | 104 => HEXLOWER_PERMISSIVE.decode(s.as_bytes()),
+            32 => BASE32.decode(s.to_ascii_uppercase().as_bytes()),
+            52 => BASE32_NOPAD.decode(s.to_ascii_uppercase().as_bytes()),
+            _ => {
+                return Err(TorrentError::InvalidInfoHash(Cow::Owned(format!(
+                    "unexpected length {}",
+                    s.len()
+                ))))
+            }
+        }
+        .map_err(|e| TorrentError::InvalidInfoHash(Cow::Owned(e.to_string())))?;
+
+        let mut v1 = [0; 20];
+        let mut v2 = [0; 32];
+        match decoded.len() {
+            20 => {
+                v1.copy_from_slice(&decoded);
+                Ok(InfoHash::V1(v1))
+            }
+            32 => {
+                v2.copy_from_slice(&decoded);
+                Ok(InfoHash::V2(v2))
+            }
+            52 => {
+                v1.copy_from_slice(&decoded[..20]);
+                v2.copy_from_slice(&decoded[20..]);
+                Ok(InfoHash::Hybrid { v1, v2 })
+            }
+            _ => Err(TorrentError::InvalidInfoHash(Cow::Borrowed(
+                "decoded hash has an unexpected length",
+            ))),
+        }
+    }
+}
+
+impl Serialize for InfoHash {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for InfoHash {
+    fn deserialize<D>(deserializer: D) -> Result<InfoHash, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = Cow::<str>::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeSet, HashMap};
+
+    fn v1() -> InfoHash {
+        InfoHash::V1([
+            177, 17, 129, 60, 230, 15, 66, 145, 151, 52, 130, 61, 245, 236, 32, 189, 30, 4, 231,
+            247,
+        ])
+    }
+
+    fn v2() -> InfoHash {
+        let mut v2 = [0; 32];
+        for (i, b) in v2.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        InfoHash::V2(v2)
+    }
+
+    #[test]
+    fn test_hex_round_trip() {
+        let hash = v1();
+        assert_eq!(hash.to_string(), "b111813ce60f42919734823df5ec20bd1e04e7f7");
+        assert_eq!(
+            format!("{:X}", hash),
+            "B111813CE60F42919734823DF5EC20BD1E04E7F7"
+        );
+        assert_eq!(hash.to_string().parse::<InfoHash>().unwrap(), hash);
+        assert_eq!(format!("{:X}", hash).parse::<InfoHash>().unwrap(), hash);
+
+        let hash = v2();
+        assert_eq!(hash.to_string().len(), 64);
+        assert_eq!(hash.to_string().parse::<InfoHash>().unwrap(), hash);
+
+        let hybrid = InfoHash::Hybrid {
+            v1: *v1().v1().unwrap(),
+            v2: *v2().v2().unwrap(),
+        };
+        assert_eq!(hybrid.to_string().len(), 104);
+        assert_eq!(hybrid.to_string().parse::<InfoHash>().unwrap(), hybrid);
+    }
+
+    #[test]
+    fn test_base32_round_trip() {
+        let hash = v1();
+        let encoded = hash.to_base32();
+        assert_eq!(encoded.len(), 32);
+        assert_eq!(encoded.parse::<InfoHash>().unwrap(), hash);
+        assert_eq!(encoded.to_lowercase().parse::<InfoHash>().unwrap(), hash);
+
+        let hash = v2();
+        let encoded = hash.to_base32();
+        assert_eq!(encoded.len(), 52);
+        assert_eq!(encoded.parse::<InfoHash>().unwrap(), hash);
+
+        let hybrid = InfoHash::Hybrid {
+            v1: *v1().v1().unwrap(),
+            v2: *v2().v2().unwrap(),
+        };
+        assert_eq!(hybrid.to_base32().parse::<InfoHash>().unwrap(), v1());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!("".parse::<InfoHash>().is_err());
+        assert!("b111813ce60f42919734823df5ec20bd1e04e7f"
+            .parse::<InfoHash>()
+            .is_err());
+        assert!("zz11813ce60f42919734823df5ec20bd1e04e7f7"
+            .parse::<InfoHash>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_truncated() {
+        assert_eq!(&v1().truncated(), v1().v1().unwrap());
+        assert_eq!(v2().truncated()[..], v2().v2().unwrap()[..20]);
+        let hybrid = InfoHash::Hybrid {
+            v1: *v1().v1().unwrap(),
+            v2: *v2().v2().unwrap(),
+        };
+        assert_eq!(&hybrid.truncated(), v1().v1().unwrap());
+    }
+
+    #[test]
+    fn test_map_keys() {
+        let mut map = HashMap::new();
+        map.insert(v1(), 1);
+        map.insert(v2(), 2);
+        assert_eq!(map[&v1()], 1);
+        let set: BTreeSet<_> = vec![v2(), v1(), v1()].into_iter().collect();
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn test_serde() {
+        let json = serde_json::to_string(&v1()).unwrap();
+        assert_eq!(json, format!(r#""{}""#, v1()));
+        assert_eq!(serde_json::from_str::<InfoHash>(&json).unwrap(), v1());
+    }
+}
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{ops::Range, path::PathBuf};
 
 use bencode::{BenObject, Dict};
 use sha1::{Digest, Sha1};
@@ -6,11 +6,18 @@ use sha1::{Digest, Sha1};
 // #[macro_use]
 // extern crate bencode;
 
+mod edit;
 mod error;
+#[cfg(any(test, feature = "fixture"))]
+pub mod fixture;
+mod info_hash;
 mod marshal;
+pub mod merkle;
 mod parser;
 
+pub use crate::edit::InfoHashChange;
 pub use crate::error::TorrentError;
+pub use crate::info_hash::{InfoHash, Sha1Hash, Sha256Hash};
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct TorrentFile {
@@ -21,6 +28,9 @@ pub struct TorrentFile {
     pub comment: Option<String>,
     pub created_by: Option<String>,
     pub encoding: Option<String>,
+    /// Top level keys this crate does not interpret (`httpseeds`, `url-list`, ...),
+    /// kept so the torrent can be written back unchanged.
+    pub extra: Dict,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -29,8 +39,11 @@ pub struct SingleFile {
     pub pieces: Vec<u8>,
     pub private: Option<i64>,
     pub name: String,
+    pub source: Option<String>,
     pub length: i64,
     pub md5sum: Option<String>,
+    /// Info keys this crate does not interpret. They are part of the info hash.
+    pub extra: Dict,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -39,7 +52,10 @@ pub struct MultipleFile {
     pub pieces: Vec<u8>,
     pub private: Option<i64>,
     pub name: String,
+    pub source: Option<String>,
     pub files: Vec<File>,
+    /// Info keys this crate does not interpret. They are part of the info hash.
+    pub extra: Dict,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -47,6 +63,9 @@ pub struct File {
     pub length: i64,
     pub md5sum: Option<String>,
     pub path: PathBuf,
+    /// File keys this crate does not interpret (`attr`, `sha1`, `path.utf-8`, ...).
+    /// They are part of the info hash.
+    pub extra: Dict,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -55,8 +74,6 @@ pub enum Info {
     MultipleFile(MultipleFile),
 }
 
-pub type Sha1Hash = [u8; 20];
-
 impl Info {
     pub fn hash_bytes(&self) -> Result<Sha1Hash, TorrentError> {
         let output = self.marshal()?;
@@ -66,13 +83,95 @@ impl Info {
         Ok(info_hash)
     }
     pub fn hash_string(&self) -> Result<String, TorrentError> {
-        let output = self.marshal()?;
-        Ok(format!("{:X}", Sha1::digest(output)))
+        Ok(format!("{:X}", self.info_hash()?))
+    }
+
+    pub fn info_hash(&self) -> Result<InfoHash, TorrentError> {
+        Ok(InfoHash::V1(self.hash_bytes()?))
+    }
+
+    /// The total size of the torrent's content in bytes.
+    pub fn length(&self) -> i64 {
+        match self {
+            Self::SingleFile(single) => single.length,
+            Self::MultipleFile(multiple) => multiple.files.iter().map(|file| file.length).sum(),
+        }
+    }
+
+    /// The byte range of each file within the content, in the order of the files.
+    pub fn file_ranges(&self) -> Vec<Range<i64>> {
+        let lengths = match self {
+            Self::SingleFile(single) => vec![single.length],
+            Self::MultipleFile(multiple) => multiple.files.iter().map(|file| file.length).collect(),
+        };
+        let mut begin = 0;
+        lengths
+            .into_iter()
+            .map(|length| {
+                begin += length;
+                begin - length..begin
+            })
+            .collect()
+    }
+
+    /// The pieces holding any of the bytes of the content in `bytes`.
+    pub fn piece_range(&self, bytes: Range<i64>) -> Range<usize> {
+        let piece_length = self.piece_length();
+        let count = self.piece_count();
+        let start = ((bytes.start / piece_length) as usize).min(count);
+        if bytes.is_empty() {
+            return start..start;
+        }
+        let end = (((bytes.end - 1) / piece_length) as usize + 1).min(count);
+        start..end
+    }
+
+    /// The number of pieces, one per 20 byte SHA-1 hash in `pieces`.
+    pub fn piece_count(&self) -> usize {
+        match self {
+            Self::SingleFile(single) => single.pieces.len() / 20,
+            Self::MultipleFile(multiple) => multiple.pieces.len() / 20,
+        }
+    }
+
+    /// The number of bytes in each piece but the last.
+    pub fn piece_length(&self) -> i64 {
+        match self {
+            Self::SingleFile(single) => single.piece_length,
+            Self::MultipleFile(multiple) => multiple.piece_length,
+        }
+    }
+
+    /// The size of the piece, which is shorter than `piece_length` for the last one.
+    pub fn piece_size(&self, index: usize) -> Option<i64> {
+        if index >= self.piece_count() {
+            return None;
+        }
+        let begin = index as i64 * self.piece_length();
+        Some(self.piece_length().min(self.length() - begin))
+    }
+
+    /// The SHA-1 hash of the piece.
+    pub fn piece_hash(&self, index: usize) -> Option<Sha1Hash> {
+        let pieces = match self {
+            Self::SingleFile(single) => &single.pieces,
+            Self::MultipleFile(multiple) => &multiple.pieces,
+        };
+        let hash = pieces.get(index * 20..(index + 1) * 20)?;
+        Some(hash.try_into().unwrap())
     }
 
     fn marshal(&self) -> Result<Vec<u8>, TorrentError> {
-        let mut map: Dict = HashMap::new();
-        let output = match *self {
+        Ok(BenObject::Dict(self.to_dict()).bencode()?)
+    }
+
+    pub(crate) fn to_dict(&self) -> Dict {
+        // 先放入没有解析的字段, 保证 info hash 不变
+        let mut map: Dict = match *self {
+            Self::SingleFile(ref single) => single.extra.clone(),
+            Self::MultipleFile(ref multiple) => multiple.extra.clone(),
+        };
+        match *self {
             Self::SingleFile(ref single) => {
                 map.insert(
                     "piece length".to_owned(),
@@ -83,11 +182,13 @@ impl Info {
                     map.insert("private".to_owned(), BenObject::Int(private));
                 }
                 map.insert("name".to_owned(), BenObject::String(single.name.clone()));
+                if let Some(source) = &single.source {
+                    map.insert("source".to_owned(), BenObject::String(source.clone()));
+                }
                 map.insert("length".to_owned(), BenObject::Int(single.length));
                 if let Some(md5sum) = &single.md5sum {
                     map.insert("md5sum".to_owned(), BenObject::String(md5sum.clone()));
                 }
-                BenObject::Dict(map).bencode()?
             }
             Self::MultipleFile(ref multiple) => {
                 map.insert(
@@ -102,9 +203,12 @@ impl Info {
                     map.insert("private".to_owned(), BenObject::Int(private));
                 }
                 map.insert("name".to_owned(), BenObject::String(multiple.name.clone()));
+                if let Some(source) = &multiple.source {
+                    map.insert("source".to_owned(), BenObject::String(source.clone()));
+                }
                 let mut files = Vec::new();
                 for file in &multiple.files {
-                    let mut fmap: Dict = HashMap::new();
+                    let mut fmap: Dict = file.extra.clone();
                     fmap.insert("length".to_owned(), BenObject::Int(file.length));
                     if let Some(md5sum) = &file.md5sum {
                         fmap.insert("md5sum".to_owned(), BenObject::String(md5sum.clone()));
@@ -124,10 +228,9 @@ impl Info {
                     files.push(BenObject::Dict(fmap));
                 }
                 map.insert("files".to_owned(), BenObject::List(files));
-                BenObject::Dict(map).bencode()?
             }
-        };
-        Ok(output)
+        }
+        map
     }
 }
 
@@ -142,8 +245,10 @@ mod test_info {
             pieces: vec![1, 2],
             private: Some(0),
             name: "startdusk".to_owned(),
+            source: None,
             length: 100,
             md5sum: Some("todo!()".to_owned()),
+            extra: Dict::new(),
         });
 
         assert_eq!(
@@ -153,27 +258,61 @@ mod test_info {
     }
     #[test]
     fn test_into_multiple_file_hash() {
-        let shash = Info::MultipleFile(MultipleFile {
+        let info = Info::MultipleFile(MultipleFile {
             piece_length: 262144,
             pieces: vec![1, 2],
             private: Some(1),
             name: "debian-10.2.0-amd64-netinst.iso".to_owned(),
+            source: None,
             files: vec![
                 File {
                     length: 512,
                     md5sum: Some("14e1b600b1fd579f47433b88e8d85291132".to_owned()),
                     path: PathBuf::from(r"a/b/c/d.txt"),
+                    extra: Dict::new(),
                 },
                 File {
                     length: 1024,
                     md5sum: Some("1d4bbcfed31c6e01e90d8e4099e39eb7".to_owned()),
                     path: PathBuf::from(r"a/b/c/f.txt"),
+                    extra: Dict::new(),
                 },
             ],
-        })
-        .hash_string()
-        .unwrap();
+            extra: Dict::new(),
+        });
+        assert_eq!(info.length(), 1536);
+        assert_eq!(info.piece_length(), 262144);
+        assert_eq!(info.file_ranges(), vec![0..512, 512..1536]);
+        // pieces 不是 20 字节的倍数时忽略最后不完整的部分
+        assert_eq!(info.piece_count(), 0);
+        assert_eq!(info.piece_hash(0), None);
+        let shash = info.hash_string().unwrap();
 
         assert_eq!(shash, "57EFD09D0E3C07FC983DFC2A7303A81556272A21".to_owned());
     }
+
+    #[test]
+    fn test_file_extra_round_trip() {
+        // 没有 announce 的种子, 文件里有 attr 和 path.utf-8
+        let bytes = [
+            &b"d4:infod5:filesld4:attr1:h6:lengthi3e4:pathl1:ae10:path.utf-8l1:aeed6:lengthi4e4:pathl1:beee"[..],
+            &b"4:name3:dir12:piece lengthi16384e6:pieces20:"[..],
+            &[0xff; 20][..],
+            &b"ee"[..],
+        ]
+        .concat();
+        let torrent = TorrentFile::parse(&bytes).unwrap();
+        assert_eq!(torrent.announce, "");
+        if let Info::MultipleFile(ref multiple) = torrent.info {
+            assert_eq!(multiple.files[0].extra.len(), 2);
+            assert_eq!(
+                multiple.files[0].extra["attr"],
+                BenObject::String("h".to_owned())
+            );
+            assert!(multiple.files[1].extra.is_empty());
+        } else {
+            panic!("not a multiple file torrent")
+        }
+        assert_eq!(torrent.bencode().unwrap(), bytes);
+    }
 }
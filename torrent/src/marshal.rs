@@ -0,0 +1,52 @@
+use super::*;
+
+impl TorrentFile {
+    /// Encodes the torrent back into a `.torrent` file.
+    ///
+    /// Keys that were not interpreted while parsing are written back as they were,
+    /// so `TorrentFile::parse(bytes)?.bencode()?` reproduces `bytes` for well-formed files.
+    pub fn bencode(&self) -> Result<Vec<u8>, TorrentError> {
+        let mut map: Dict = self.extra.clone();
+        // 没有 tracker 的种子(只靠 DHT)不写 announce
+        if !self.announce.is_empty() {
+            map.insert(
+                "announce".to_owned(),
+                BenObject::String(self.announce.clone()),
+            );
+        }
+        if let Some(announce_list) = &self.announce_list {
+            map.insert(
+                "announce-list".to_owned(),
+                BenObject::List(
+                    announce_list
+                        .iter()
+                        .map(|tier| {
+                            BenObject::List(
+                                tier.iter()
+                                    .map(|url| BenObject::String(url.clone()))
+                                    .collect(),
+                            )
+                        })
+                        .collect(),
+                ),
+            );
+        }
+        if let Some(creation_date) = self.creation_date {
+            map.insert("creation date".to_owned(), BenObject::Int(creation_date));
+        }
+        if let Some(comment) = &self.comment {
+            map.insert("comment".to_owned(), BenObject::String(comment.clone()));
+        }
+        if let Some(created_by) = &self.created_by {
+            map.insert(
+                "created by".to_owned(),
+                BenObject::String(created_by.clone()),
+            );
+        }
+        if let Some(encoding) = &self.encoding {
+            map.insert("encoding".to_owned(), BenObject::String(encoding.clone()));
+        }
+        map.insert("info".to_owned(), BenObject::Dict(self.info.to_dict()));
+        Ok(BenObject::Dict(map).bencode()?)
+    }
+}
@@ -0,0 +1,306 @@
+use std::borrow::Cow;
+
+use sha2::{Digest, Sha256};
+
+use crate::{Sha256Hash, TorrentError};
+
+/// v2 torrents hash files in 16 KiB blocks, the leaves of each file's merkle tree.
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+/// A SHA-256 merkle tree over the blocks of a single file (BEP 52).
+///
+/// The leaf layer is padded with zero hashes up to a power of two, so every
+/// layer above it has an even number of nodes and `layers.last()` holds the root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    layers: Vec<Vec<Sha256Hash>>,
+    // 填充之前的叶子数量
+    leaf_count: usize,
+}
+
+impl MerkleTree {
+    /// Hashes `data` in [`BLOCK_SIZE`] chunks and builds the tree. The last block may be short.
+    pub fn from_data(data: &[u8]) -> MerkleTree {
+        Self::from_leaves(data.chunks(BLOCK_SIZE).map(hash_block).collect())
+    }
+
+    pub fn from_leaves(mut leaves: Vec<Sha256Hash>) -> MerkleTree {
+        let leaf_count = leaves.len();
+        leaves.resize(leaf_count.max(1).next_power_of_two(), [0; 32]);
+        let mut layers = vec![leaves];
+        while layers[layers.len() - 1].len() > 1 {
+            let next = parent_layer(&layers[layers.len() - 1]);
+            layers.push(next);
+        }
+        MerkleTree { layers, leaf_count }
+    }
+
+    /// The `pieces root` of the file. Empty files have none.
+    pub fn root(&self) -> Option<Sha256Hash> {
+        if self.leaf_count == 0 {
+            return None;
+        }
+        Some(self.layers[self.layers.len() - 1][0])
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.leaf_count
+    }
+
+    /// The hashes of a given layer, counted from the leaves (layer 0), padding included.
+    pub fn layer(&self, height: usize) -> Option<&[Sha256Hash]> {
+        self.layers.get(height).map(|layer| layer.as_slice())
+    }
+
+    /// The entry of the `piece layers` dict for this file: one hash per piece,
+    /// without the padding nodes past the end of the file.
+    ///
+    /// Files that fit in a single piece have no piece layer, only a `pieces root`.
+    pub fn piece_layer(
+        &self,
+        piece_length: usize,
+    ) -> Result<Option<Vec<Sha256Hash>>, TorrentError> {
+        let height = piece_layer_height(piece_length)?;
+        let blocks_per_piece = 1 << height;
+        if self.leaf_count <= blocks_per_piece {
+            return Ok(None);
+        }
+        let pieces = self.leaf_count.div_ceil(blocks_per_piece);
+        Ok(Some(self.layers[height][..pieces].to_vec()))
+    }
+
+    /// The uncle hashes needed to verify node `index` of layer `height`,
+    /// from its sibling up to (but not including) the layer `to_height`.
+    ///
+    /// These are the `proof layers` a peer sends back for a hash request.
+    pub fn proof(&self, height: usize, mut index: usize, to_height: usize) -> Vec<Sha256Hash> {
+        let to_height = to_height.min(self.layers.len() - 1);
+        let mut proof = Vec::new();
+        for layer in &self.layers[height.min(to_height)..to_height] {
+            proof.push(layer[index ^ 1]);
+            index /= 2;
+        }
+        proof
+    }
+}
+
+pub fn hash_block(block: &[u8]) -> Sha256Hash {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&Sha256::digest(block));
+    hash
+}
+
+/// The hash of a subtree of the given height whose leaves are all zero hashes.
+/// Used to pad piece layers up to a power of two when rebuilding the root.
+pub fn pad_hash(height: usize) -> Sha256Hash {
+    let mut hash = [0; 32];
+    for _ in 0..height {
+        hash = hash_pair(&hash, &hash);
+    }
+    hash
+}
+
+/// Rebuilds the `pieces root` from a file's piece layer, so a received
+/// `piece layers` entry can be checked against the `file tree`.
+pub fn root_from_piece_layer(
+    layer: &[Sha256Hash],
+    piece_length: usize,
+) -> Result<Sha256Hash, TorrentError> {
+    if layer.is_empty() {
+        return Err(TorrentError::InvalidTorrentInfo(Cow::Borrowed(
+            "piece layer is empty",
+        )));
+    }
+    let pad = pad_hash(piece_layer_height(piece_length)?);
+    let mut nodes = layer.to_vec();
+    nodes.resize(layer.len().next_power_of_two(), pad);
+    while nodes.len() > 1 {
+        nodes = parent_layer(&nodes);
+    }
+    Ok(nodes[0])
+}
+
+/// Checks that `hash`, the node at `index` in its layer, leads to `root` using the uncle hashes in `proof`.
+pub fn verify(root: &Sha256Hash, mut index: usize, hash: Sha256Hash, proof: &[Sha256Hash]) -> bool {
+    let mut node = hash;
+    for uncle in proof {
+        node = if index.is_multiple_of(2) {
+            hash_pair(&node, uncle)
+        } else {
+            hash_pair(uncle, &node)
+        };
+        index /= 2;
+    }
+    index == 0 && &node == root
+}
+
+/// Verifies one received block against its piece's hash from the piece layer.
+///
+/// `block_index` is the index of the block inside the piece and `proof` holds
+/// the uncle hashes from the block's sibling up to the piece layer.
+pub fn verify_block(
+    piece_hash: &Sha256Hash,
+    piece_length: usize,
+    block_index: usize,
+    block: &[u8],
+    proof: &[Sha256Hash],
+) -> Result<bool, TorrentError> {
+    let height = piece_layer_height(piece_length)?;
+    if block.len() > BLOCK_SIZE || proof.len() != height || block_index >= 1 << height {
+        return Ok(false);
+    }
+    Ok(verify(piece_hash, block_index, hash_block(block), proof))
+}
+
+// piece length 必须是 2 的幂且不小于 16 KiB, 返回 piece 层距离叶子层的高度
+fn piece_layer_height(piece_length: usize) -> Result<usize, TorrentError> {
+    if piece_length < BLOCK_SIZE || !piece_length.is_power_of_two() {
+        return Err(TorrentError::InvalidTorrentInfo(Cow::Borrowed(
+            "`piece length` must be a power of two and at least 16 KiB",
+        )));
+    }
+    Ok((piece_length / BLOCK_SIZE).trailing_zeros() as usize)
+}
+
+fn parent_layer(layer: &[Sha256Hash]) -> Vec<Sha256Hash> {
+    layer
+        .chunks(2)
+        .map(|pair| hash_pair(&pair[0], &pair[1]))
+        .collect()
+}
+
+fn hash_pair(left: &Sha256Hash, right: &Sha256Hash) -> Sha256Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&hasher.finalize());
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_encoding::HEXLOWER;
+
+    fn hex(hash: &Sha256Hash) -> String {
+        HEXLOWER.encode(hash)
+    }
+
+    // 5 个完整的 block (第 i 个 block 全是字节 i) 加上 100 个字节的 5
+    fn sample() -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..5 {
+            data.extend_from_slice(&[i; BLOCK_SIZE]);
+        }
+        data.extend_from_slice(&[5; 100]);
+        data
+    }
+
+    #[test]
+    fn test_empty_file() {
+        let tree = MerkleTree::from_data(&[]);
+        assert_eq!(tree.leaf_count(), 0);
+        assert_eq!(tree.root(), None);
+    }
+
+    #[test]
+    fn test_single_block() {
+        let tree = MerkleTree::from_data(b"abc");
+        assert_eq!(
+            hex(&tree.root().unwrap()),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(tree.piece_layer(BLOCK_SIZE).unwrap(), None);
+    }
+
+    #[test]
+    fn test_padded_tree() {
+        let data = sample();
+        let tree = MerkleTree::from_data(&data);
+        assert_eq!(tree.leaf_count(), 6);
+        assert_eq!(tree.layer(0).unwrap().len(), 8);
+        assert_eq!(tree.layer(0).unwrap()[6], [0; 32]);
+        assert_eq!(
+            hex(&tree.root().unwrap()),
+            "cd7af236b1451e8716380dfdc4be79a534257be97eab6cf265951771014b5123"
+        );
+    }
+
+    #[test]
+    fn test_piece_layers() {
+        let tree = MerkleTree::from_data(&sample());
+
+        let layer = tree.piece_layer(2 * BLOCK_SIZE).unwrap().unwrap();
+        assert_eq!(
+            layer.iter().map(hex).collect::<Vec<_>>(),
+            vec![
+                "bb3d7ed87517034230008512a51a4bdc428c7cc128ef619425e01d124030c7b6",
+                "ca93ba2c3a43211ef471fc52d1e6bac03c02b31ece8e8ba560cb487253d5e0e9",
+                "c7a4e2cf09839d1c15d0824491c975b5de4852a0ffa0ea259dd18cd387ac6092",
+            ]
+        );
+        // 第 4 个 piece 完全在文件之外, 用全零子树的哈希填充
+        assert_eq!(
+            root_from_piece_layer(&layer, 2 * BLOCK_SIZE).unwrap(),
+            tree.root().unwrap()
+        );
+        assert_eq!(tree.layer(1).unwrap()[3], pad_hash(1));
+
+        let layer = tree.piece_layer(4 * BLOCK_SIZE).unwrap().unwrap();
+        assert_eq!(
+            layer.iter().map(hex).collect::<Vec<_>>(),
+            vec![
+                "c8370a13184041b3299b5822a2f92ccac9d87427d736b0e7dfe433d28387e7ab",
+                "b609fcfe2db12f37a8642ab9301b5c4bbb7ad93c7f24d931a4c0b34bbc0cd9cf",
+            ]
+        );
+        assert_eq!(
+            root_from_piece_layer(&layer, 4 * BLOCK_SIZE).unwrap(),
+            tree.root().unwrap()
+        );
+
+        // 整个文件都在一个 piece 里
+        assert_eq!(tree.piece_layer(8 * BLOCK_SIZE).unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalid_piece_length() {
+        let tree = MerkleTree::from_data(&sample());
+        assert!(tree.piece_layer(BLOCK_SIZE / 2).is_err());
+        assert!(tree.piece_layer(3 * BLOCK_SIZE).is_err());
+        assert!(root_from_piece_layer(&[], BLOCK_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_verify_block() {
+        let data = sample();
+        let tree = MerkleTree::from_data(&data);
+        let piece_length = 4 * BLOCK_SIZE;
+        let layer = tree.piece_layer(piece_length).unwrap().unwrap();
+
+        // piece 1 的第 1 个 block 是文件里的第 5 个 block (100 字节)
+        let block = &data[5 * BLOCK_SIZE..];
+        let proof = tree.proof(0, 5, 2);
+        assert_eq!(proof.len(), 2);
+        assert!(verify_block(&layer[1], piece_length, 1, block, &proof).unwrap());
+        assert!(!verify_block(&layer[1], piece_length, 0, block, &proof).unwrap());
+        assert!(!verify_block(&layer[0], piece_length, 1, block, &proof).unwrap());
+        assert!(!verify_block(&layer[1], piece_length, 1, &data[..100], &proof).unwrap());
+        assert!(!verify_block(&layer[1], piece_length, 1, block, &proof[..1]).unwrap());
+
+        // 从叶子一直证明到根
+        let proof = tree.proof(0, 2, usize::MAX);
+        assert_eq!(proof.len(), 3);
+        assert!(verify(
+            &tree.root().unwrap(),
+            2,
+            hash_block(&data[2 * BLOCK_SIZE..3 * BLOCK_SIZE]),
+            &proof
+        ));
+
+        // piece 层的节点也可以直接证明到根
+        let proof = tree.proof(2, 1, usize::MAX);
+        assert!(verify(&tree.root().unwrap(), 1, layer[1], &proof));
+    }
+}
@@ -5,6 +5,7 @@ use super::*;
 use bencode::{BenObject, Dict};
 
 impl TorrentFile {
+	#[allow(clippy::needless_return)]
 	pub fn parse<T>(bytes: T) -> Result<TorrentFile, TorrentError>
 	where
 		T: AsRef<[u8]>,
@@ -19,11 +20,14 @@ impl TorrentFile {
 				comment: Self::comment(dict)?,
 				created_by: Self::created_by(dict)?,
 				encoding: Self::encoding(dict)?,
+				// 剩下的都是没有解析的字段
+				extra: std::mem::take(dict),
 			}),
 			_ => return Err(TorrentError::InvalidTorrent),
 		}
 	}
 
+	#[allow(clippy::needless_return)]
 	fn announce(dict: &mut Dict) -> Result<String, TorrentError> {
 		match dict.remove("announce") {
 			Some(BenObject::String(url)) => Ok(url),
@@ -32,14 +36,12 @@ impl TorrentFile {
 					"`announce` does not map to string (or maps to invalid UTF8).",
 				)))
 			}
-			None => {
-				return Err(TorrentError::ParseError(Cow::Borrowed(
-					"`announce` does not exist.",
-				)))
-			}
+			// 只靠 DHT 的种子没有 announce
+			None => Ok(String::new()),
 		}
 	}
 
+	#[allow(clippy::needless_return)]
 	fn creation_date(dict: &mut Dict) -> Result<Option<i64>, TorrentError> {
 		match dict.remove("creation date") {
 			Some(BenObject::Int(date)) => Ok(Some(date)),
@@ -52,6 +54,7 @@ impl TorrentFile {
 		}
 	}
 
+	#[allow(clippy::needless_return)]
 	fn comment(dict: &mut Dict) -> Result<Option<String>, TorrentError> {
 		match dict.remove("comment") {
 			Some(BenObject::String(comment)) => Ok(Some(comment)),
@@ -64,6 +67,7 @@ impl TorrentFile {
 		}
 	}
 
+	#[allow(clippy::needless_return)]
 	fn created_by(dict: &mut Dict) -> Result<Option<String>, TorrentError> {
 		match dict.remove("created by") {
 			Some(BenObject::String(created)) => Ok(Some(created)),
@@ -76,6 +80,7 @@ impl TorrentFile {
 		}
 	}
 
+	#[allow(clippy::needless_return)]
 	fn encoding(dict: &mut Dict) -> Result<Option<String>, TorrentError> {
 		match dict.remove("encoding") {
 			Some(BenObject::String(encoding)) => Ok(Some(encoding)),
@@ -88,6 +93,19 @@ impl TorrentFile {
 		}
 	}
 
+	fn source(dict: &mut Dict) -> Result<Option<String>, TorrentError> {
+		match dict.remove("source") {
+			Some(BenObject::String(source)) => Ok(Some(source)),
+			Some(_) => {
+				Err(TorrentError::ParseError(Cow::Borrowed(
+					"`source` does not map to string (or maps to invalid UTF8).",
+				)))
+			}
+			None => Ok(None),
+		}
+	}
+
+	#[allow(clippy::needless_return)]
 	fn announce_list(dict: &mut Dict) -> Result<Option<Vec<Vec<String>>>, TorrentError> {
 		match dict.remove("announce-list") {
 			Some(BenObject::List(list)) => {
@@ -106,6 +124,7 @@ impl TorrentFile {
 		}
 	}
 
+	#[allow(clippy::needless_return)]
 	fn announce_list_sub(obj: BenObject) -> Result<Vec<String>, TorrentError> {
 		match obj {
 			BenObject::List(list) => {
@@ -130,6 +149,7 @@ impl TorrentFile {
 		}
 	}
 
+	#[allow(clippy::needless_return)]
 	fn name(dict: &mut Dict) -> Result<String, TorrentError> {
 		match dict.remove("name") {
 			Some(BenObject::String(name)) => Ok(name),
@@ -146,6 +166,7 @@ impl TorrentFile {
 		}
 	}
 
+	#[allow(clippy::needless_return)]
 	fn piece_length(dict: &mut Dict) -> Result<i64, TorrentError> {
 		match dict.remove("piece length") {
 			Some(BenObject::Int(len)) => Ok(len),
@@ -162,6 +183,7 @@ impl TorrentFile {
 		}
 	}
 
+	#[allow(clippy::needless_return)]
 	fn length(dict: &mut Dict) -> Result<i64, TorrentError> {
 		match dict.remove("length") {
 			Some(BenObject::Int(len)) => Ok(len),
@@ -178,6 +200,7 @@ impl TorrentFile {
 		}
 	}
 
+	#[allow(clippy::needless_return)]
 	fn md5sum(dict: &mut Dict) -> Result<Option<String>, TorrentError> {
 		match dict.remove("md5sum") {
 			Some(BenObject::String(sum)) => Ok(Some(sum)),
@@ -190,6 +213,7 @@ impl TorrentFile {
 		}
 	}
 
+	#[allow(clippy::needless_return)]
 	fn path(dict: &mut Dict) -> Result<PathBuf, TorrentError> {
 		match dict.remove("path") {
 			Some(BenObject::List(ps)) => {
@@ -216,6 +240,7 @@ impl TorrentFile {
 		}
 	}
 
+	#[allow(clippy::needless_return)]
 	fn files(obj: &mut BenObject) -> Result<Vec<File>, TorrentError> {
 		match obj {
 			BenObject::List(ref mut list) => {
@@ -226,6 +251,7 @@ impl TorrentFile {
 							length: Self::length(dict)?,
 							md5sum: Self::md5sum(dict)?,
 							path: Self::path(dict)?,
+							extra: std::mem::take(dict),
 						});
 					} else {
 						return Err(TorrentError::ParseError(Cow::Borrowed(
@@ -243,6 +269,7 @@ impl TorrentFile {
 		}
 	}
 
+	#[allow(clippy::needless_return)]
 	fn pieces(dict: &mut Dict) -> Result<Vec<u8>, TorrentError> {
 		match dict.remove("pieces") {
 			Some(BenObject::Bytes(bytes)) => Ok(bytes),
@@ -259,6 +286,7 @@ impl TorrentFile {
 		}
 	}
 
+	#[allow(clippy::needless_return)]
 	fn private(dict: &mut Dict) -> Result<Option<i64>, TorrentError> {
 		match dict.remove("private") {
 			Some(BenObject::Int(p)) => Ok(Some(p)),
@@ -271,6 +299,7 @@ impl TorrentFile {
 		}
 	}
 
+	#[allow(clippy::needless_return)]
 	fn info(dict: &mut Dict) -> Result<Info, TorrentError> {
 		match dict.remove("info") {
 			Some(BenObject::Dict(ref mut info)) => {
@@ -278,13 +307,16 @@ impl TorrentFile {
 				let piece_length = Self::piece_length(info)?;
 				let pieces = Self::pieces(info)?;
 				let private = Self::private(info)?;
+				let source = Self::source(info)?;
 				if let Some(ref mut files) = info.remove("files") {
 					Ok(Info::MultipleFile(MultipleFile {
 						piece_length,
 						pieces,
 						private,
 						name,
+						source,
 						files: Self::files(files)?,
+						extra: std::mem::take(info),
 					}))
 				} else {
 					Ok(Info::SingleFile(SingleFile {
@@ -292,8 +324,10 @@ impl TorrentFile {
 						pieces,
 						private,
 						name,
+						source,
 						length: Self::length(info)?,
 						md5sum: Self::md5sum(info)?,
+						extra: std::mem::take(info),
 					}))
 				}
 			}
@@ -0,0 +1,246 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use torrent::{InfoHash, Sha1Hash, TorrentFile};
+use tracker::{Event, PeerId};
+
+use crate::swarm::Announce;
+use crate::ServerError;
+
+/// The torrents a private tracker accepts announces for.
+///
+/// Clients announce the 20 byte form of the info hash, so hashes are matched
+/// by [`InfoHash::truncated`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Whitelist {
+    info_hashes: HashSet<Sha1Hash>,
+}
+
+impl Whitelist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads every `.torrent` file of a directory (not recursing into subdirectories).
+    /// Files that cannot be read or parsed are skipped and returned with their error;
+    /// only a failure to list the directory itself is fatal.
+    pub fn from_dir<P: AsRef<Path>>(
+        dir: P,
+    ) -> Result<(Self, Vec<(PathBuf, ServerError)>), ServerError> {
+        let mut whitelist = Self::new();
+        let mut errors = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().is_some_and(|ext| ext == "torrent") {
+                match Self::load(&path) {
+                    Ok(info_hash) => {
+                        whitelist.insert(info_hash);
+                    }
+                    Err(e) => errors.push((path, e)),
+                }
+            }
+        }
+        Ok((whitelist, errors))
+    }
+
+    fn load(path: &Path) -> Result<InfoHash, ServerError> {
+        let torrent = TorrentFile::parse(std::fs::read(path)?)?;
+        Ok(torrent.info.info_hash()?)
+    }
+
+    pub fn insert(&mut self, info_hash: InfoHash) -> bool {
+        self.info_hashes.insert(info_hash.truncated())
+    }
+
+    pub fn remove(&mut self, info_hash: &InfoHash) -> bool {
+        self.info_hashes.remove(&info_hash.truncated())
+    }
+
+    pub fn contains(&self, info_hash: &InfoHash) -> bool {
+        self.info_hashes.contains(&info_hash.truncated())
+    }
+
+    pub fn len(&self) -> usize {
+        self.info_hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.info_hashes.is_empty()
+    }
+}
+
+impl FromIterator<InfoHash> for Whitelist {
+    fn from_iter<I: IntoIterator<Item = InfoHash>>(iter: I) -> Self {
+        Self {
+            info_hashes: iter.into_iter().map(|hash| hash.truncated()).collect(),
+        }
+    }
+}
+
+/// Bytes a user has transferred, summed over all their torrents and clients.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UserStats {
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+/// Passkeys and the transfer accounting of their users.
+///
+/// Clients report the totals of the current session, so the difference to the
+/// previous announce of the same peer is what gets added to the user.
+#[derive(Debug, Default)]
+pub struct Accounts {
+    inner: Mutex<AccountsInner>,
+}
+
+#[derive(Debug, Default)]
+struct AccountsInner {
+    users: HashMap<String, UserStats>,
+    // 每个 (passkey, 种子, peer) 上一次 announce 报告的 uploaded 和 downloaded
+    sessions: HashMap<(String, InfoHash, PeerId), (u64, u64)>,
+}
+
+impl Accounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_user<S: Into<String>>(&self, passkey: S) {
+        self.inner
+            .lock()
+            .unwrap()
+            .users
+            .entry(passkey.into())
+            .or_default();
+    }
+
+    /// Removes the user, returning their final statistics.
+    pub fn remove_user(&self, passkey: &str) -> Option<UserStats> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sessions.retain(|(key, _, _), _| key != passkey);
+        inner.users.remove(passkey)
+    }
+
+    pub fn contains(&self, passkey: &str) -> bool {
+        self.inner.lock().unwrap().users.contains_key(passkey)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.lock().unwrap().users.is_empty()
+    }
+
+    pub fn stats(&self, passkey: &str) -> Option<UserStats> {
+        self.inner.lock().unwrap().users.get(passkey).copied()
+    }
+
+    /// Adds the traffic reported by an announce to the user. Returns `false` for unknown passkeys.
+    pub fn record(&self, passkey: &str, req: &Announce) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.users.contains_key(passkey) {
+            return false;
+        }
+        let key = (passkey.to_owned(), req.info_hash, req.peer_id);
+        let (last_uploaded, last_downloaded) = if req.event == Some(Event::Started) {
+            (0, 0)
+        } else {
+            inner.sessions.get(&key).copied().unwrap_or_default()
+        };
+        // 计数比上次小说明客户端重启了, 从 0 开始算
+        let delta = |now: u64, last: u64| if now >= last { now - last } else { now };
+        let uploaded = delta(req.uploaded, last_uploaded);
+        let downloaded = delta(req.downloaded, last_downloaded);
+        if req.event == Some(Event::Stopped) {
+            inner.sessions.remove(&key);
+        } else {
+            inner.sessions.insert(key, (req.uploaded, req.downloaded));
+        }
+        let user = inner.users.get_mut(passkey).unwrap();
+        user.uploaded += uploaded;
+        user.downloaded += downloaded;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    fn announce(uploaded: u64, downloaded: u64, event: Option<Event>) -> Announce {
+        Announce {
+            info_hash: InfoHash::V1([1; 20]),
+            peer_id: [2; 20],
+            addr: SocketAddr::from(([10, 0, 0, 1], 6881)),
+            alt_addr: None,
+            uploaded,
+            downloaded,
+            left: 0,
+            event,
+            numwant: 50,
+        }
+    }
+
+    #[test]
+    fn test_accounting() {
+        let accounts = Accounts::new();
+        accounts.add_user("alice");
+        assert!(!accounts.record("bob", &announce(10, 10, None)));
+
+        assert!(accounts.record("alice", &announce(0, 0, Some(Event::Started))));
+        accounts.record("alice", &announce(100, 1000, None));
+        accounts.record("alice", &announce(300, 1000, Some(Event::Stopped)));
+        assert_eq!(
+            accounts.stats("alice"),
+            Some(UserStats {
+                uploaded: 300,
+                downloaded: 1000,
+            })
+        );
+
+        // 新的会话从 0 开始报告
+        accounts.record("alice", &announce(50, 0, Some(Event::Started)));
+        accounts.record("alice", &announce(70, 0, None));
+        // 客户端崩溃后重新开始, 没有发送 started
+        accounts.record("alice", &announce(5, 0, None));
+        assert_eq!(accounts.stats("alice").unwrap().uploaded, 375);
+
+        assert_eq!(accounts.remove_user("alice").unwrap().uploaded, 375);
+        assert!(accounts.is_empty());
+    }
+
+    #[test]
+    fn test_whitelist_from_dir() {
+        let files = concat!(env!("CARGO_MANIFEST_DIR"), "/../tests/tests/files");
+        let dir = std::env::temp_dir().join(format!("whitelist-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "debian-11.3.0-amd64-netinst.iso.torrent",
+            "debian-iso.torrent",
+        ] {
+            std::fs::copy(format!("{}/{}", files, name), dir.join(name)).unwrap();
+        }
+        // 其他扩展名的文件被忽略
+        std::fs::write(dir.join("README"), "not a torrent").unwrap();
+        let (whitelist, errors) = Whitelist::from_dir(&dir).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(whitelist.len(), 2);
+        // debian-11.3.0-amd64-netinst.iso.torrent
+        assert!(whitelist.contains(&InfoHash::V1([
+            177, 17, 129, 60, 230, 15, 66, 145, 151, 52, 130, 61, 245, 236, 32, 189, 30, 4, 231,
+            247,
+        ])));
+        assert!(!whitelist.contains(&InfoHash::V1([0; 20])));
+
+        std::fs::write(dir.join("broken.torrent"), "d4:infoi1ee").unwrap();
+        // 单个坏文件只被跳过并报告, 不影响其他文件
+        let (whitelist, errors) = Whitelist::from_dir(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(whitelist.len(), 2);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, dir.join("broken.torrent"));
+        assert!(matches!(errors[0].1, ServerError::TorrentError(_)));
+        assert!(Whitelist::from_dir("/nonexistent").is_err());
+    }
+}
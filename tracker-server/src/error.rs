@@ -0,0 +1,13 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ServerError {
+    #[error(transparent)]
+    IOError(#[from] ::std::io::Error),
+    #[error(transparent)]
+    HttpError(#[from] ::hyper::Error),
+    #[error(transparent)]
+    BencodeError(#[from] bencode::BencodeError),
+    #[error(transparent)]
+    TorrentError(#[from] torrent::TorrentError),
+}
@@ -0,0 +1,313 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use bencode::{BenObject, BytesDict, Dict};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use percent_encoding::percent_decode;
+use torrent::InfoHash;
+use tracker::{Event, TrackerPeer};
+
+use crate::swarm::{Announce, AnnounceResult};
+use crate::{ServerConfig, TrackerServer};
+
+// query 里的二进制参数 (info_hash, peer_id) 按字节解码, 不能当作 UTF8 字符串
+struct Query(Vec<(String, Vec<u8>)>);
+
+impl Query {
+    fn parse(query: &str) -> Self {
+        Query(
+            query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    (
+                        percent_decode(key.as_bytes())
+                            .decode_utf8_lossy()
+                            .into_owned(),
+                        percent_decode(value.as_bytes()).collect(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    fn get(&self, key: &str) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.0
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn hash(&self, key: &'static str) -> Result<[u8; 20], Cow<'static, str>> {
+        self.get(key)
+            .ok_or_else(|| Cow::Owned(format!("missing {}", key)))?
+            .try_into()
+            .map_err(|_| Cow::Owned(format!("invalid {}", key)))
+    }
+
+    fn parse_value<T: std::str::FromStr>(
+        &self,
+        key: &'static str,
+    ) -> Result<Option<T>, Cow<'static, str>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(value) => std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .map(Some)
+                .ok_or_else(|| Cow::Owned(format!("invalid {}", key))),
+        }
+    }
+
+    fn required<T: std::str::FromStr>(&self, key: &'static str) -> Result<T, Cow<'static, str>> {
+        self.parse_value(key)?
+            .ok_or_else(|| Cow::Owned(format!("missing {}", key)))
+    }
+}
+
+pub(crate) async fn handle(
+    server: TrackerServer,
+    remote: SocketAddr,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    if req.method() != Method::GET {
+        return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    let query = Query::parse(req.uri().query().unwrap_or(""));
+    let mut segments = req.uri().path().rsplit('/');
+    let action = segments.next();
+    // 私有 tracker 的路径是 /<passkey>/announce
+    let passkey = match segments.next() {
+        Some(passkey) if server.config().is_private() => {
+            Some(percent_decode(passkey.as_bytes()).decode_utf8_lossy())
+        }
+        _ => None,
+    };
+    let body = match action {
+        Some("announce" | "scrape")
+            if server.config().is_private()
+                && !passkey
+                    .as_ref()
+                    .is_some_and(|key| server.accounts().contains(key)) =>
+        {
+            failure("unknown passkey")
+        }
+        Some("announce") => match announce(server.config(), remote, &query) {
+            Ok(announce) if !server.config().is_allowed(&announce.info_hash) => {
+                failure("unregistered torrent")
+            }
+            Ok(announce) => {
+                if let Some(passkey) = &passkey {
+                    server.accounts().record(passkey, &announce);
+                }
+                let result = server.swarms().announce(&announce);
+                announce_response(server.config(), &query, result)
+            }
+            Err(reason) => failure(reason),
+        },
+        Some("scrape") => scrape_response(&server, &query),
+        _ => return Ok(status(StatusCode::NOT_FOUND)),
+    };
+    match body.bencode() {
+        Ok(bytes) => Ok(Response::new(Body::from(bytes))),
+        Err(_) => Ok(status(StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = status;
+    resp
+}
+
+/// A response that only carries a `failure reason`.
+pub(crate) fn failure<S: Into<String>>(reason: S) -> BenObject {
+    BenObject::Dict(Dict::from([(
+        "failure reason".to_owned(),
+        BenObject::String(reason.into()),
+    )]))
+}
+
+fn announce(
+    config: &ServerConfig,
+    remote: SocketAddr,
+    query: &Query,
+) -> Result<Announce, Cow<'static, str>> {
+    let port: u16 = query.required("port")?;
+    // IPv4 映射的 IPv6 地址当作 IPv4
+    let ip = remote.ip().to_canonical();
+    let alt_ip = match ip {
+        IpAddr::V4(_) => query.parse_value::<Ipv6Addr>("ipv6")?.map(IpAddr::V6),
+        IpAddr::V6(_) => query.parse_value::<Ipv4Addr>("ipv4")?.map(IpAddr::V4),
+    };
+    let event = match query.get("event") {
+        None | Some(b"") | Some(b"empty") => None,
+        Some(b"started") => Some(Event::Started),
+        Some(b"completed") => Some(Event::Completed),
+        Some(b"stopped") => Some(Event::Stopped),
+        Some(_) => return Err(Cow::Borrowed("invalid event")),
+    };
+    let numwant = query
+        .parse_value::<usize>("numwant")?
+        .unwrap_or(config.default_numwant)
+        .min(config.max_numwant);
+    Ok(Announce {
+        info_hash: InfoHash::V1(query.hash("info_hash")?),
+        peer_id: query.hash("peer_id")?,
+        addr: SocketAddr::new(ip, port),
+        alt_addr: alt_ip.map(|ip| SocketAddr::new(ip, port)),
+        uploaded: query.required("uploaded")?,
+        downloaded: query.required("downloaded")?,
+        left: query.required("left")?,
+        event,
+        numwant,
+    })
+}
+
+fn announce_response(config: &ServerConfig, query: &Query, result: AnnounceResult) -> BenObject {
+    let mut dict = Dict::from([
+        (
+            "interval".to_owned(),
+            BenObject::Int(config.interval.as_secs() as i64),
+        ),
+        (
+            "min interval".to_owned(),
+            BenObject::Int(config.min_interval.as_secs() as i64),
+        ),
+        ("complete".to_owned(), BenObject::Int(result.complete)),
+        ("incomplete".to_owned(), BenObject::Int(result.incomplete)),
+    ]);
+    if query.get("compact") == Some(b"0") {
+        let no_peer_id = query.get("no_peer_id").is_some();
+        let peers = result
+            .peers
+            .iter()
+            .map(|peer| peer_dict(peer, no_peer_id))
+            .collect();
+        dict.insert("peers".to_owned(), BenObject::List(peers));
+    } else {
+        let (v4, v6) = compact_peers(&result.peers);
+        dict.insert("peers".to_owned(), BenObject::Bytes(v4));
+        if !v6.is_empty() {
+            dict.insert("peers6".to_owned(), BenObject::Bytes(v6));
+        }
+    }
+    BenObject::Dict(dict)
+}
+
+fn peer_dict(peer: &TrackerPeer, no_peer_id: bool) -> BenObject {
+    let mut dict = Dict::from([
+        (
+            "ip".to_owned(),
+            BenObject::String(peer.addr.ip().to_string()),
+        ),
+        ("port".to_owned(), BenObject::Int(peer.addr.port() as i64)),
+    ]);
+    if let (Some(peer_id), false) = (peer.peer_id, no_peer_id) {
+        dict.insert("peer id".to_owned(), BenObject::Bytes(peer_id.to_vec()));
+    }
+    BenObject::Dict(dict)
+}
+
+/// Splits peers into the compact IPv4 (`peers`) and IPv6 (`peers6`) strings.
+pub(crate) fn compact_peers(peers: &[TrackerPeer]) -> (Vec<u8>, Vec<u8>) {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for peer in peers {
+        match peer.addr {
+            SocketAddr::V4(addr) => {
+                v4.extend_from_slice(&addr.ip().octets());
+                v4.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            SocketAddr::V6(addr) => {
+                v6.extend_from_slice(&addr.ip().octets());
+                v6.extend_from_slice(&addr.port().to_be_bytes());
+            }
+        }
+    }
+    (v4, v6)
+}
+
+fn scrape_response(server: &TrackerServer, query: &Query) -> BenObject {
+    let info_hashes: Vec<InfoHash> = query
+        .get_all("info_hash")
+        .filter_map(|hash| hash.try_into().ok().map(InfoHash::V1))
+        .collect();
+    // 没有指定 info_hash 时返回所有种子
+    let mut info_hashes = if info_hashes.is_empty() {
+        server.swarms().info_hashes()
+    } else {
+        info_hashes
+    };
+    info_hashes.retain(|info_hash| server.config().is_allowed(info_hash));
+    let files: BytesDict = server
+        .swarms()
+        .scrape(&info_hashes)
+        .into_iter()
+        .map(|(info_hash, file)| {
+            let stats = Dict::from([
+                ("complete".to_owned(), BenObject::Int(file.complete)),
+                ("downloaded".to_owned(), BenObject::Int(file.downloaded)),
+                ("incomplete".to_owned(), BenObject::Int(file.incomplete)),
+            ]);
+            (info_hash.truncated().to_vec(), BenObject::Dict(stats))
+        })
+        .collect();
+    BenObject::Dict(HashMap::from([(
+        "files".to_owned(),
+        BenObject::BytesDict(files),
+    )]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_query() {
+        let query = Query::parse("info_hash=%124Vx%9A%BC%DE%F1%23Eg%89%AB%CD%EF%124Vx%9A&port=6881&no_peer_id&info_hash=abc");
+        assert_eq!(
+            query.hash("info_hash").unwrap(),
+            [
+                0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf1, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd,
+                0xef, 0x12, 0x34, 0x56, 0x78, 0x9a
+            ]
+        );
+        assert_eq!(query.get_all("info_hash").count(), 2);
+        assert_eq!(query.required::<u16>("port").unwrap(), 6881);
+        assert_eq!(query.get("no_peer_id"), Some(&b""[..]));
+        assert!(query.hash("peer_id").is_err());
+        assert!(query.required::<u16>("no_peer_id").is_err());
+    }
+
+    #[test]
+    fn test_announce_params() {
+        let config = ServerConfig::default().with_max_numwant(100);
+        let remote: SocketAddr = "[::ffff:10.0.0.1]:50000".parse().unwrap();
+        let query = Query::parse(
+            "info_hash=aaaaaaaaaaaaaaaaaaaa&peer_id=bbbbbbbbbbbbbbbbbbbb&port=6881&uploaded=1\
+            &downloaded=2&left=3&event=started&numwant=500&ipv6=2001%3Adb8%3A%3A1",
+        );
+        let req = announce(&config, remote, &query).unwrap();
+        assert_eq!(req.addr, "10.0.0.1:6881".parse().unwrap());
+        assert_eq!(req.alt_addr, Some("[2001:db8::1]:6881".parse().unwrap()));
+        assert_eq!(req.event, Some(Event::Started));
+        assert_eq!(req.numwant, 100);
+        assert_eq!(req.left, 3);
+
+        let query = Query::parse("info_hash=aaaaaaaaaaaaaaaaaaaa&peer_id=bbbbbbbbbbbbbbbbbbbb");
+        assert_eq!(
+            announce(&config, remote, &query).unwrap_err(),
+            "missing port"
+        );
+    }
+}
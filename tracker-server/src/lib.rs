@@ -0,0 +1,255 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use tokio::net::{TcpListener, UdpSocket};
+
+mod auth;
+mod error;
+mod http;
+mod swarm;
+mod udp;
+
+pub use crate::auth::{Accounts, UserStats, Whitelist};
+pub use crate::error::ServerError;
+pub use crate::swarm::{Announce, AnnounceResult, SwarmTable};
+
+/// How long to wait after a failed `accept` before accepting again.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Settings of a [`TrackerServer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerConfig {
+    interval: Duration,
+    min_interval: Duration,
+    peer_timeout: Duration,
+    default_numwant: usize,
+    max_numwant: usize,
+    passkeys: Option<HashSet<String>>,
+    whitelist: Option<Whitelist>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30 * 60),
+            min_interval: Duration::from_secs(5 * 60),
+            peer_timeout: Duration::from_secs(45 * 60),
+            default_numwant: 50,
+            max_numwant: 200,
+            passkeys: None,
+            whitelist: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The `interval` sent to clients, `30` minutes by default.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// The `min interval` sent to HTTP clients, `5` minutes by default.
+    pub fn with_min_interval(mut self, min_interval: Duration) -> Self {
+        self.min_interval = min_interval;
+        self
+    }
+
+    /// Peers that have not announced for this long are dropped, `45` minutes by default.
+    pub fn with_peer_timeout(mut self, peer_timeout: Duration) -> Self {
+        self.peer_timeout = peer_timeout;
+        self
+    }
+
+    /// Peers returned when the client does not send `numwant`, `50` by default.
+    pub fn with_default_numwant(mut self, numwant: usize) -> Self {
+        self.default_numwant = numwant;
+        self
+    }
+
+    /// Upper bound on the peers returned per announce, `200` by default.
+    pub fn with_max_numwant(mut self, numwant: usize) -> Self {
+        self.max_numwant = numwant;
+        self
+    }
+
+    /// Makes the tracker private: HTTP clients have to announce and scrape at
+    /// `/<passkey>/announce` and `/<passkey>/scrape` with one of these passkeys,
+    /// and UDP requests are refused. More users can be added with [`TrackerServer::accounts`].
+    pub fn with_passkeys<I, S>(mut self, passkeys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.passkeys = Some(passkeys.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Only torrents in the whitelist are tracked, by default every torrent is.
+    pub fn with_whitelist(mut self, whitelist: Whitelist) -> Self {
+        self.whitelist = Some(whitelist);
+        self
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.passkeys.is_some()
+    }
+
+    /// Whether announces for the torrent are accepted.
+    pub fn is_allowed(&self, info_hash: &torrent::InfoHash) -> bool {
+        self.whitelist
+            .as_ref()
+            .is_none_or(|whitelist| whitelist.contains(info_hash))
+    }
+}
+
+/// A BitTorrent tracker serving HTTP (`/announce`, `/scrape`) and UDP (BEP 15)
+/// clients from the same swarm table.
+///
+/// Cloning is cheap and clones share their state, so one server can listen on
+/// several sockets:
+///
+/// ```no_run
+/// # async fn run() -> Result<(), tracker_server::ServerError> {
+/// use tokio::net::{TcpListener, UdpSocket};
+/// use tracker_server::{ServerConfig, TrackerServer};
+///
+/// let server = TrackerServer::new(ServerConfig::default());
+/// let udp = server.clone();
+/// let socket = UdpSocket::bind("0.0.0.0:6969").await?;
+/// tokio::spawn(async move { udp.serve_udp(socket).await });
+/// server.serve_http(TcpListener::bind("0.0.0.0:6969").await?).await
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct TrackerServer {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: ServerConfig,
+    swarms: SwarmTable,
+    accounts: Accounts,
+    // 计算 UDP connection id 用的随机密钥
+    secret: RandomState,
+    started: Instant,
+}
+
+impl TrackerServer {
+    pub fn new(config: ServerConfig) -> Self {
+        let accounts = Accounts::new();
+        for passkey in config.passkeys.iter().flatten() {
+            accounts.add_user(passkey.as_str());
+        }
+        Self {
+            inner: Arc::new(Inner {
+                swarms: SwarmTable::new(config.peer_timeout),
+                accounts,
+                config,
+                secret: RandomState::new(),
+                started: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.inner.config
+    }
+
+    pub fn swarms(&self) -> &SwarmTable {
+        &self.inner.swarms
+    }
+
+    /// The users of a private tracker and what they have transferred.
+    /// Empty unless the config has passkeys.
+    pub fn accounts(&self) -> &Accounts {
+        &self.inner.accounts
+    }
+
+    /// Serves HTTP tracker requests until the listener fails for good.
+    /// Any path ending in `/announce` or `/scrape` is answered, for a private
+    /// tracker the segment before it is the passkey.
+    ///
+    /// Transient `accept` errors, such as running out of file descriptors or a
+    /// client aborting the connection, are logged and retried after a short pause.
+    pub async fn serve_http(&self, listener: TcpListener) -> Result<(), ServerError> {
+        loop {
+            let (stream, remote) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) if is_transient(&e) => {
+                    eprintln!("tracker-server: accepting a connection failed: {}", e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req| http::handle(server.clone(), remote, req));
+                // 连接出错只影响这一个客户端
+                let _ = Http::new()
+                    .http1_only(true)
+                    .serve_connection(stream, service)
+                    .await;
+            });
+        }
+    }
+
+    /// Serves UDP tracker requests until receiving from the socket fails.
+    pub async fn serve_udp(&self, socket: UdpSocket) -> Result<(), ServerError> {
+        udp::serve(self, socket).await
+    }
+
+    fn secret(&self) -> &RandomState {
+        &self.inner.secret
+    }
+
+    fn started(&self) -> Instant {
+        self.inner.started
+    }
+}
+
+// accept 失败后可以继续的错误: 文件描述符用完, 或者只是这一个连接出错
+fn is_transient(e: &io::Error) -> bool {
+    // Unix 上的 EMFILE 和 ENFILE
+    const TOO_MANY_FILES: [i32; 2] = [24, 23];
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::OutOfMemory
+    ) || (cfg!(unix)
+        && e.raw_os_error()
+            .is_some_and(|code| TOO_MANY_FILES.contains(&code)))
+}
+
+impl std::fmt::Debug for TrackerServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TrackerServer")
+            .field("config", &self.inner.config)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_transient_accept_errors() {
+        assert!(is_transient(&io::Error::from_raw_os_error(24)));
+        assert!(is_transient(&io::Error::from_raw_os_error(23)));
+        assert!(is_transient(&io::ErrorKind::ConnectionAborted.into()));
+        assert!(!is_transient(&io::ErrorKind::InvalidInput.into()));
+        assert!(!is_transient(&io::ErrorKind::PermissionDenied.into()));
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::seq::IteratorRandom;
+use torrent::InfoHash;
+use tracker::{Event, PeerId, ScrapeFile, TrackerPeer};
+
+/// How often the whole table is swept for expired peers.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An announce as received by the HTTP or UDP frontend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+    /// The address the request came from, with the port the peer listens on.
+    pub addr: SocketAddr,
+    /// The address of the other IP version given with `ipv4=` or `ipv6=` (BEP 7).
+    pub alt_addr: Option<SocketAddr>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<Event>,
+    pub numwant: usize,
+}
+
+/// What the frontends answer an announce with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceResult {
+    pub complete: i64,
+    pub incomplete: i64,
+    /// Up to `numwant` other peers, each listed once per address it has.
+    pub peers: Vec<TrackerPeer>,
+}
+
+#[derive(Debug, Clone)]
+struct PeerEntry {
+    v4: Option<SocketAddr>,
+    v6: Option<SocketAddr>,
+    left: u64,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Swarm {
+    peers: HashMap<PeerId, PeerEntry>,
+    downloaded: i64,
+}
+
+impl Swarm {
+    fn expire(&mut self, deadline: Instant) {
+        self.peers.retain(|_, peer| peer.last_seen >= deadline);
+    }
+
+    fn stats(&self) -> ScrapeFile {
+        let complete = self.peers.values().filter(|peer| peer.left == 0).count() as i64;
+        ScrapeFile {
+            complete,
+            downloaded: self.downloaded,
+            incomplete: self.peers.len() as i64 - complete,
+            name: None,
+        }
+    }
+}
+
+/// The peers of every torrent the tracker has seen, keyed by the 20 byte info hash clients announce with.
+///
+/// Peers that have not announced within the peer timeout are dropped.
+pub struct SwarmTable {
+    swarms: Mutex<Swarms>,
+    peer_timeout: Duration,
+}
+
+struct Swarms {
+    by_hash: HashMap<InfoHash, Swarm>,
+    last_sweep: Instant,
+}
+
+impl SwarmTable {
+    pub fn new(peer_timeout: Duration) -> Self {
+        Self {
+            swarms: Mutex::new(Swarms {
+                by_hash: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+            peer_timeout,
+        }
+    }
+
+    pub fn announce(&self, req: &Announce) -> AnnounceResult {
+        self.announce_at(req, Instant::now())
+    }
+
+    /// Statistics of the given torrents. Torrents without peers are left out.
+    pub fn scrape(&self, info_hashes: &[InfoHash]) -> HashMap<InfoHash, ScrapeFile> {
+        self.scrape_at(info_hashes, Instant::now())
+    }
+
+    /// The info hashes that currently have peers.
+    pub fn info_hashes(&self) -> Vec<InfoHash> {
+        self.swarms
+            .lock()
+            .unwrap()
+            .by_hash
+            .iter()
+            .filter(|(_, swarm)| !swarm.peers.is_empty())
+            .map(|(info_hash, _)| *info_hash)
+            .collect()
+    }
+
+    fn announce_at(&self, req: &Announce, now: Instant) -> AnnounceResult {
+        let mut swarms = self.swarms.lock().unwrap();
+        let deadline = now.checked_sub(self.peer_timeout);
+        if let Some(deadline) = deadline {
+            // 隔一段时间清理一次所有种子, 没有人 announce 的种子也会被清理
+            if now.duration_since(swarms.last_sweep) >= SWEEP_INTERVAL {
+                swarms.last_sweep = now;
+                swarms.by_hash.retain(|_, swarm| {
+                    swarm.expire(deadline);
+                    !swarm.peers.is_empty() || swarm.downloaded > 0
+                });
+            }
+        }
+
+        let swarm = swarms.by_hash.entry(req.info_hash).or_default();
+        if let Some(deadline) = deadline {
+            swarm.expire(deadline);
+        }
+        if req.event == Some(Event::Stopped) {
+            swarm.peers.remove(&req.peer_id);
+        } else {
+            if req.event == Some(Event::Completed) {
+                swarm.downloaded += 1;
+            }
+            let mut entry = PeerEntry {
+                v4: None,
+                v6: None,
+                left: req.left,
+                last_seen: now,
+            };
+            for addr in std::iter::once(req.addr).chain(req.alt_addr) {
+                match addr {
+                    SocketAddr::V4(_) => entry.v4 = Some(addr),
+                    SocketAddr::V6(_) => entry.v6 = Some(addr),
+                }
+            }
+            swarm.peers.insert(req.peer_id, entry);
+        }
+
+        let stats = swarm.stats();
+        let mut rng = rand::thread_rng();
+        let peers = swarm
+            .peers
+            .iter()
+            .filter(|(peer_id, _)| **peer_id != req.peer_id)
+            .choose_multiple(&mut rng, req.numwant)
+            .into_iter()
+            .flat_map(|(peer_id, peer)| {
+                [peer.v4, peer.v6]
+                    .into_iter()
+                    .flatten()
+                    .map(|addr| TrackerPeer {
+                        addr,
+                        peer_id: Some(*peer_id),
+                    })
+            })
+            .collect();
+        AnnounceResult {
+            complete: stats.complete,
+            incomplete: stats.incomplete,
+            peers,
+        }
+    }
+
+    fn scrape_at(&self, info_hashes: &[InfoHash], now: Instant) -> HashMap<InfoHash, ScrapeFile> {
+        let mut swarms = self.swarms.lock().unwrap();
+        let deadline = now.checked_sub(self.peer_timeout);
+        let mut files = HashMap::with_capacity(info_hashes.len());
+        for info_hash in info_hashes {
+            if let Some(swarm) = swarms.by_hash.get_mut(info_hash) {
+                if let Some(deadline) = deadline {
+                    swarm.expire(deadline);
+                }
+                files.insert(*info_hash, swarm.stats());
+            }
+        }
+        files
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announce(peer: u8, left: u64, event: Option<Event>) -> Announce {
+        Announce {
+            info_hash: InfoHash::V1([1; 20]),
+            peer_id: [peer; 20],
+            addr: SocketAddr::from(([10, 0, 0, peer], 6881)),
+            alt_addr: None,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event,
+            numwant: 50,
+        }
+    }
+
+    #[test]
+    fn test_announce_and_stop() {
+        let table = SwarmTable::new(Duration::from_secs(3600));
+        let result = table.announce(&announce(1, 100, Some(Event::Started)));
+        assert_eq!((result.complete, result.incomplete), (0, 1));
+        assert!(result.peers.is_empty());
+
+        let mut req = announce(2, 0, Some(Event::Started));
+        req.alt_addr = Some("[2001:db8::2]:6881".parse().unwrap());
+        table.announce(&req);
+
+        let result = table.announce(&announce(1, 0, Some(Event::Completed)));
+        assert_eq!((result.complete, result.incomplete), (2, 0));
+        assert_eq!(
+            result.peers,
+            vec![
+                TrackerPeer {
+                    addr: "10.0.0.2:6881".parse().unwrap(),
+                    peer_id: Some([2; 20]),
+                },
+                TrackerPeer {
+                    addr: "[2001:db8::2]:6881".parse().unwrap(),
+                    peer_id: Some([2; 20]),
+                },
+            ]
+        );
+
+        table.announce(&announce(2, 0, Some(Event::Stopped)));
+        let files = table.scrape(&[InfoHash::V1([1; 20]), InfoHash::V1([2; 20])]);
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            files[&InfoHash::V1([1; 20])],
+            ScrapeFile {
+                complete: 1,
+                downloaded: 1,
+                incomplete: 0,
+                name: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_numwant() {
+        let table = SwarmTable::new(Duration::from_secs(3600));
+        for peer in 0..10 {
+            table.announce(&announce(peer, 100, None));
+        }
+        let mut req = announce(0, 100, None);
+        req.numwant = 3;
+        let result = table.announce(&req);
+        assert_eq!(result.peers.len(), 3);
+        assert!(result
+            .peers
+            .iter()
+            .all(|peer| peer.peer_id != Some([0; 20])));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let table = SwarmTable::new(Duration::from_secs(3600));
+        let start = Instant::now();
+        table.announce_at(&announce(1, 100, None), start);
+        table.announce_at(&announce(2, 100, None), start + Duration::from_secs(1800));
+
+        let later = start + Duration::from_secs(4000);
+        let files = table.scrape_at(&[InfoHash::V1([1; 20])], later);
+        assert_eq!(files[&InfoHash::V1([1; 20])].incomplete, 1);
+
+        // 另一个种子的 announce 会触发清理
+        let mut other = announce(3, 100, None);
+        other.info_hash = InfoHash::V1([2; 20]);
+        table.announce_at(&other, start + Duration::from_secs(6000));
+        assert_eq!(table.info_hashes(), vec![InfoHash::V1([2; 20])]);
+    }
+}
@@ -0,0 +1,163 @@
+use std::hash::BuildHasher;
+use std::net::{IpAddr, SocketAddr};
+
+use bytes::{Buf, BufMut};
+use tokio::net::UdpSocket;
+use torrent::InfoHash;
+use tracker::Event;
+
+use crate::http::compact_peers;
+use crate::swarm::Announce;
+use crate::{ServerError, TrackerServer};
+
+// UDP tracker 协议 (BEP 15): http://bittorrent.org/beps/bep_0015.html
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// Clients may scrape at most about 74 info hashes per packet.
+const MAX_SCRAPE_HASHES: usize = 74;
+const MAX_PACKET_LEN: usize = 2048;
+
+pub(crate) async fn serve(server: &TrackerServer, socket: UdpSocket) -> Result<(), ServerError> {
+    let mut buf = vec![0; MAX_PACKET_LEN];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        if let Some(resp) = handle(server, from, &buf[..len]) {
+            // 发送失败只影响这一个客户端
+            let _ = socket.send_to(&resp, from).await;
+        }
+    }
+}
+
+// 无法解析的包直接丢弃, 客户端会重传
+fn handle(server: &TrackerServer, from: SocketAddr, mut req: &[u8]) -> Option<Vec<u8>> {
+    if req.len() < 16 {
+        return None;
+    }
+    let connection_id = req.get_u64();
+    let action = req.get_u32();
+    let transaction_id = req.get_u32();
+
+    let mut resp = Vec::new();
+    if action == ACTION_CONNECT {
+        if connection_id != PROTOCOL_ID {
+            return None;
+        }
+        resp.put_u32(ACTION_CONNECT);
+        resp.put_u32(transaction_id);
+        resp.put_u64(server.connection_id(from, 0));
+        return Some(resp);
+    }
+    // connection id 在发出后的 1 到 2 分钟内有效
+    if connection_id != server.connection_id(from, 0)
+        && connection_id != server.connection_id(from, 1)
+    {
+        return Some(error(transaction_id, "invalid connection id"));
+    }
+    // UDP 请求里没有 passkey, 私有 tracker 只能用 HTTP
+    if server.config().is_private() {
+        return Some(error(transaction_id, "passkey required"));
+    }
+
+    match action {
+        ACTION_ANNOUNCE => {
+            if req.len() < 82 {
+                return Some(error(transaction_id, "announce packet is too short"));
+            }
+            let mut info_hash = [0; 20];
+            req.copy_to_slice(&mut info_hash);
+            let info_hash = InfoHash::V1(info_hash);
+            let mut peer_id = [0; 20];
+            req.copy_to_slice(&mut peer_id);
+            let downloaded = req.get_u64();
+            let left = req.get_u64();
+            let uploaded = req.get_u64();
+            let event = match req.get_u32() {
+                1 => Some(Event::Completed),
+                2 => Some(Event::Started),
+                3 => Some(Event::Stopped),
+                _ => None,
+            };
+            // 忽略客户端声明的 ip 和 key
+            req.advance(8);
+            let config = server.config();
+            let numwant = match req.get_i32() {
+                n if n < 0 => config.default_numwant,
+                n => n as usize,
+            }
+            .min(config.max_numwant);
+            let port = req.get_u16();
+            if !config.is_allowed(&info_hash) {
+                return Some(error(transaction_id, "unregistered torrent"));
+            }
+
+            let ip = from.ip().to_canonical();
+            let result = server.swarms().announce(&Announce {
+                info_hash,
+                peer_id,
+                addr: SocketAddr::new(ip, port),
+                alt_addr: None,
+                uploaded,
+                downloaded,
+                left,
+                event,
+                numwant,
+            });
+            resp.put_u32(ACTION_ANNOUNCE);
+            resp.put_u32(transaction_id);
+            resp.put_u32(config.interval.as_secs() as u32);
+            resp.put_u32(result.incomplete as u32);
+            resp.put_u32(result.complete as u32);
+            // 只返回和请求同一个 IP 版本的 peer
+            let (v4, v6) = compact_peers(&result.peers);
+            match ip {
+                IpAddr::V4(_) => resp.put_slice(&v4),
+                IpAddr::V6(_) => resp.put_slice(&v6),
+            }
+            Some(resp)
+        }
+        ACTION_SCRAPE => {
+            let info_hashes: Vec<InfoHash> = req
+                .chunks_exact(20)
+                .take(MAX_SCRAPE_HASHES)
+                .map(|hash| InfoHash::V1(hash.try_into().unwrap()))
+                .collect();
+            let files = server.swarms().scrape(&info_hashes);
+            resp.put_u32(ACTION_SCRAPE);
+            resp.put_u32(transaction_id);
+            for info_hash in &info_hashes {
+                let (complete, downloaded, incomplete) = files
+                    .get(info_hash)
+                    .map(|file| (file.complete, file.downloaded, file.incomplete))
+                    .unwrap_or_default();
+                resp.put_u32(complete as u32);
+                resp.put_u32(downloaded as u32);
+                resp.put_u32(incomplete as u32);
+            }
+            Some(resp)
+        }
+        _ => Some(error(transaction_id, "unknown action")),
+    }
+}
+
+fn error(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut resp = Vec::with_capacity(8 + message.len());
+    resp.put_u32(ACTION_ERROR);
+    resp.put_u32(transaction_id);
+    resp.put_slice(message.as_bytes());
+    resp
+}
+
+impl TrackerServer {
+    // connection id 由 IP 和时间窗口计算出来, 服务器不需要保存
+    // 客户端每次请求可能换一个端口, 所以不包含端口
+    // age 为 0 表示当前的一分钟, 1 表示上一分钟
+    fn connection_id(&self, from: SocketAddr, age: u64) -> u64 {
+        let window = (self.started().elapsed().as_secs() / 60).wrapping_sub(age);
+        self.secret().hash_one((from.ip().to_canonical(), window))
+    }
+}
@@ -0,0 +1,435 @@
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+
+use crate::error::TrackerError;
+use crate::{Event, Request, Response, Tracker, TrackerManager, UdpTracker};
+
+/// Delay before retrying the first failed announce, doubled after every further failure.
+const RETRY_BASE: Duration = Duration::from_secs(15);
+/// Retries are never delayed longer than this.
+const RETRY_MAX: Duration = Duration::from_secs(30 * 60);
+/// Announces are never more frequent than this, whatever interval the tracker asks for.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Something an [`Announcer`] can announce to.
+pub trait Announce {
+    fn announce(
+        &self,
+        req: &Request,
+    ) -> impl Future<Output = Result<Response, TrackerError>> + Send;
+}
+
+impl Announce for Tracker {
+    fn announce(
+        &self,
+        req: &Request,
+    ) -> impl Future<Output = Result<Response, TrackerError>> + Send {
+        self.find_peers(req.clone())
+    }
+}
+
+impl Announce for TrackerManager {
+    fn announce(
+        &self,
+        req: &Request,
+    ) -> impl Future<Output = Result<Response, TrackerError>> + Send {
+        TrackerManager::announce(self, req)
+    }
+}
+
+impl Announce for UdpTracker {
+    fn announce(
+        &self,
+        req: &Request,
+    ) -> impl Future<Output = Result<Response, TrackerError>> + Send {
+        UdpTracker::announce(self, req)
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Progress {
+        uploaded: usize,
+        downloaded: usize,
+        left: i64,
+    },
+    Force,
+    Completed,
+    Stop,
+}
+
+/// Controls a running [`Announcer`] and receives the tracker responses.
+///
+/// Dropping the handle stops the announcer as if [`AnnouncerHandle::stop`] was called.
+pub struct AnnouncerHandle {
+    commands: mpsc::UnboundedSender<Command>,
+    responses: mpsc::UnboundedReceiver<Response>,
+}
+
+impl AnnouncerHandle {
+    /// Updates the transfer statistics sent with the next announce.
+    pub fn progress(&self, uploaded: usize, downloaded: usize, left: i64) {
+        self.send(Command::Progress {
+            uploaded,
+            downloaded,
+            left,
+        });
+    }
+
+    /// Announces as soon as the tracker's `min interval` allows, e.g. when more peers are needed.
+    pub fn announce_now(&self) {
+        self.send(Command::Force);
+    }
+
+    /// Sends the `completed` event right away. Call it once, when the download finishes.
+    pub fn completed(&self) {
+        self.send(Command::Completed);
+    }
+
+    /// Sends the `stopped` event and ends the announcer.
+    pub fn stop(&self) {
+        self.send(Command::Stop);
+    }
+
+    /// The next successful tracker response, `None` once the announcer has ended.
+    pub async fn next_response(&mut self) -> Option<Response> {
+        self.responses.recv().await
+    }
+
+    fn send(&self, command: Command) {
+        // announcer 已经结束时忽略命令
+        let _ = self.commands.send(command);
+    }
+}
+
+/// The announce loop of one torrent.
+///
+/// It sends `started` first, then re-announces every `interval` the tracker asked
+/// for, echoing the `trackerid` it was given. Failed announces are retried after
+/// 15 seconds, doubling up to 30 minutes.
+pub struct Announcer<A> {
+    tracker: A,
+    req: Request,
+    commands: mpsc::UnboundedReceiver<Command>,
+    responses: mpsc::UnboundedSender<Response>,
+    // 下一次 announce 要发送的事件
+    event: Option<Event>,
+    started: bool,
+    failures: u32,
+    last_announce: Option<Instant>,
+    min_interval: Option<Duration>,
+}
+
+impl<A: Announce> Announcer<A> {
+    /// `req` is used as a template: `event` and `tracker_id` are managed by the announcer.
+    pub fn new(tracker: A, req: Request) -> (Self, AnnouncerHandle) {
+        let (commands_tx, commands) = mpsc::unbounded_channel();
+        let (responses, responses_rx) = mpsc::unbounded_channel();
+        let announcer = Self {
+            tracker,
+            req: Request {
+                event: None,
+                tracker_id: None,
+                ..req
+            },
+            commands,
+            responses,
+            event: Some(Event::Started),
+            started: false,
+            failures: 0,
+            last_announce: None,
+            min_interval: None,
+        };
+        let handle = AnnouncerHandle {
+            commands: commands_tx,
+            responses: responses_rx,
+        };
+        (announcer, handle)
+    }
+
+    /// Runs until stopped. Returns the result of the final `stopped` announce,
+    /// which is only sent when the tracker has seen `started`.
+    pub async fn run(mut self) -> Result<(), TrackerError> {
+        let mut next = Instant::now();
+        loop {
+            tokio::select! {
+                // 先处理到期的 announce, 保证 completed 在 stopped 之前发出
+                biased;
+                _ = time::sleep_until(next) => {
+                    next = self.announce_once().await;
+                }
+                command = self.commands.recv() => match command {
+                    Some(Command::Progress { uploaded, downloaded, left }) => {
+                        self.req.uploaded = uploaded;
+                        self.req.downloaded = downloaded;
+                        self.req.left = left;
+                    }
+                    Some(Command::Force) => {
+                        next = next.min(self.earliest_announce());
+                    }
+                    Some(Command::Completed) => {
+                        self.req.left = 0;
+                        // 还没有发出 started 时, completed 没有意义
+                        if self.started {
+                            self.event = Some(Event::Completed);
+                            next = Instant::now();
+                        }
+                    }
+                    Some(Command::Stop) | None => return self.stop().await,
+                },
+            }
+        }
+    }
+
+    // announce 一次, 返回下一次 announce 的时间
+    async fn announce_once(&mut self) -> Instant {
+        self.req.event = self.event.clone();
+        let result = self.tracker.announce(&self.req).await;
+        let now = Instant::now();
+        match result {
+            Ok(resp) => {
+                self.failures = 0;
+                self.started = true;
+                self.event = None;
+                self.last_announce = Some(now);
+                self.min_interval = resp.min_interval;
+                if resp.tracker_id.is_some() {
+                    self.req.tracker_id = resp.tracker_id.clone();
+                }
+                // interval 为 0 或过小时不能连续不断地 announce
+                let interval = resp
+                    .interval
+                    .max(resp.min_interval.unwrap_or_default())
+                    .max(MIN_ANNOUNCE_INTERVAL);
+                let _ = self.responses.send(resp);
+                now + interval
+            }
+            Err(_) => {
+                self.failures += 1;
+                now + retry_delay(self.failures)
+            }
+        }
+    }
+
+    fn earliest_announce(&self) -> Instant {
+        match (self.last_announce, self.min_interval) {
+            (Some(last), Some(min_interval)) => (last + min_interval).max(Instant::now()),
+            _ => Instant::now(),
+        }
+    }
+
+    async fn stop(mut self) -> Result<(), TrackerError> {
+        if !self.started {
+            return Ok(());
+        }
+        self.req.event = Some(Event::Stopped);
+        self.tracker.announce(&self.req).await.map(|_| ())
+    }
+}
+
+fn retry_delay(failures: u32) -> Duration {
+    RETRY_BASE
+        .saturating_mul(1 << (failures - 1).min(16))
+        .min(RETRY_MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::udp::tests::request;
+
+    // announce 的时间, 事件和 tracker id
+    type Announced = (Duration, Option<Event>, Option<String>);
+
+    // 记录每次 announce 的时间和请求, 按顺序返回预先设定的结果
+    #[derive(Clone, Default)]
+    struct Script {
+        announces: Arc<Mutex<Vec<Announced>>>,
+        results: Arc<Mutex<VecDeque<Result<Response, TrackerError>>>>,
+    }
+
+    struct MockTracker {
+        start: Instant,
+        script: Script,
+    }
+
+    impl Announce for MockTracker {
+        fn announce(
+            &self,
+            req: &Request,
+        ) -> impl Future<Output = Result<Response, TrackerError>> + Send {
+            self.script.announces.lock().unwrap().push((
+                self.start.elapsed(),
+                req.event.clone(),
+                req.tracker_id.clone(),
+            ));
+            let result = self
+                .script
+                .results
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or_else(|| Ok(response(None)));
+            async move { result }
+        }
+    }
+
+    fn response(tracker_id: Option<&str>) -> Response {
+        Response {
+            warning_message: None,
+            interval: Duration::from_secs(1800),
+            min_interval: Some(Duration::from_secs(60)),
+            tracker_id: tracker_id.map(|id| id.to_owned()),
+            complete: None,
+            incomplete: None,
+            peers: Vec::new(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announce_loop() {
+        let script = Script::default();
+        script.results.lock().unwrap().extend([
+            Ok(response(Some("abc"))),
+            Err(TrackerError::Timeout),
+            Err(TrackerError::Timeout),
+        ]);
+        let tracker = MockTracker {
+            start: Instant::now(),
+            script: script.clone(),
+        };
+        let (announcer, mut handle) = Announcer::new(tracker, request());
+        let task = tokio::spawn(announcer.run());
+
+        // started
+        assert_eq!(
+            handle.next_response().await.unwrap().tracker_id.as_deref(),
+            Some("abc")
+        );
+        // interval 之后失败两次, 分别等待 15 秒和 30 秒重试
+        handle.next_response().await.unwrap();
+        // 距离上一次 announce 不到 min interval, 推迟到 60 秒之后
+        handle.announce_now();
+        handle.next_response().await.unwrap();
+        handle.progress(100, 200, 0);
+        handle.completed();
+        handle.stop();
+        task.await.unwrap().unwrap();
+
+        let secs = Duration::from_secs;
+        let abc = Some("abc".to_owned());
+        assert_eq!(
+            *script.announces.lock().unwrap(),
+            vec![
+                (secs(0), Some(Event::Started), None),
+                (secs(1800), None, abc.clone()),
+                (secs(1815), None, abc.clone()),
+                (secs(1845), None, abc.clone()),
+                (secs(1905), None, abc.clone()),
+                (secs(1905), Some(Event::Completed), abc.clone()),
+                (secs(1905), Some(Event::Stopped), abc),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_force_after_min_interval() {
+        let script = Script::default();
+        let tracker = MockTracker {
+            start: Instant::now(),
+            script: script.clone(),
+        };
+        let (announcer, mut handle) = Announcer::new(tracker, request());
+        let task = tokio::spawn(announcer.run());
+
+        handle.next_response().await.unwrap();
+        time::sleep(Duration::from_secs(100)).await;
+        handle.announce_now();
+        handle.next_response().await.unwrap();
+        drop(handle);
+        task.await.unwrap().unwrap();
+
+        let announces = script.announces.lock().unwrap();
+        assert_eq!(announces.len(), 3);
+        assert_eq!(announces[1].0, Duration::from_secs(100));
+        assert_eq!(announces[2].1, Some(Event::Stopped));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stop_before_started() {
+        let script = Script::default();
+        script
+            .results
+            .lock()
+            .unwrap()
+            .push_back(Err(TrackerError::Timeout));
+        let tracker = MockTracker {
+            start: Instant::now(),
+            script: script.clone(),
+        };
+        let (announcer, handle) = Announcer::new(tracker, request());
+        let task = tokio::spawn(announcer.run());
+
+        time::sleep(Duration::from_secs(1)).await;
+        handle.completed();
+        handle.stop();
+        task.await.unwrap().unwrap();
+        // 只有失败的 started, 不发送 completed 和 stopped
+        assert_eq!(
+            *script.announces.lock().unwrap(),
+            vec![(Duration::ZERO, Some(Event::Started), None)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_zero_interval() {
+        let script = Script::default();
+        let zero = |min_interval| Response {
+            interval: Duration::ZERO,
+            min_interval,
+            ..response(None)
+        };
+        script.results.lock().unwrap().extend([
+            Ok(zero(None)),
+            Ok(zero(Some(Duration::from_secs(120)))),
+            Ok(zero(None)),
+        ]);
+        let tracker = MockTracker {
+            start: Instant::now(),
+            script: script.clone(),
+        };
+        let (announcer, mut handle) = Announcer::new(tracker, request());
+        let task = tokio::spawn(announcer.run());
+
+        for _ in 0..3 {
+            handle.next_response().await.unwrap();
+        }
+        drop(handle);
+        task.await.unwrap().unwrap();
+
+        // 至少间隔 60 秒, tracker 给出的 min interval 更长时按它来
+        let announces: Vec<Duration> = script
+            .announces
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|announce| announce.0)
+            .collect();
+        let secs = Duration::from_secs;
+        assert_eq!(announces, vec![secs(0), secs(60), secs(180), secs(180)]);
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), Duration::from_secs(15));
+        assert_eq!(retry_delay(2), Duration::from_secs(30));
+        assert_eq!(retry_delay(5), Duration::from_secs(240));
+        assert_eq!(retry_delay(100), RETRY_MAX);
+    }
+}
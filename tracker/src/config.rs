@@ -0,0 +1,287 @@
+use std::borrow::Cow;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::{redirect, Client, Proxy};
+
+use crate::error::TrackerError;
+use crate::session::peer_id_prefix;
+
+/// Settings of the clients talking to trackers.
+///
+/// Build it once per session and share the resulting [`TrackerClient`] between
+/// every tracker, so they reuse the same connection pool.
+///
+/// ```
+/// use std::time::Duration;
+/// use tracker::TrackerConfig;
+///
+/// let client = TrackerConfig::default()
+///     .with_timeout(Duration::from_secs(20))
+///     .with_proxy("socks5h://127.0.0.1:9050")
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerConfig {
+    connect_timeout: Duration,
+    timeout: Duration,
+    announce_timeout: Duration,
+    proxy: Option<String>,
+    user_agent: String,
+    peer_id_prefix: String,
+    gzip: bool,
+    max_redirects: usize,
+    bind_address: Option<IpAddr>,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            announce_timeout: Duration::from_secs(60),
+            proxy: None,
+            user_agent: concat!("rs-torrent/", env!("CARGO_PKG_VERSION")).to_owned(),
+            peer_id_prefix: peer_id_prefix("RS", env!("CARGO_PKG_VERSION")),
+            gzip: true,
+            max_redirects: 5,
+            bind_address: None,
+        }
+    }
+}
+
+impl TrackerConfig {
+    /// How long to wait for the TCP (and TLS) connection, `10` seconds by default.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// How long a whole HTTP announce or scrape may take, `30` seconds by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How long a [`TrackerManager`](crate::TrackerManager) waits for one tracker
+    /// before trying the next one of its tier, `60` seconds by default. It bounds
+    /// UDP trackers, whose retransmissions can take hours.
+    pub fn with_announce_timeout(mut self, timeout: Duration) -> Self {
+        self.announce_timeout = timeout;
+        self
+    }
+
+    /// Sends HTTP tracker requests through a proxy: `http://`, `https://`, `socks5://`
+    /// or `socks5h://` (resolving host names on the proxy). UDP trackers are not proxied.
+    pub fn with_proxy<S: Into<String>>(mut self, proxy: S) -> Self {
+        self.proxy = Some(proxy.into());
+        self
+    }
+
+    /// The `User-Agent` header, `rs-torrent/<version>` by default.
+    /// Private trackers often only allow a list of known clients.
+    pub fn with_user_agent<S: Into<String>>(mut self, user_agent: S) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// The Azureus-style prefix of generated peer ids, `-RS<version>-` by default.
+    /// See [`peer_id_prefix`](crate::peer_id_prefix) to build one for another client.
+    pub fn with_peer_id_prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.peer_id_prefix = prefix.into();
+        self
+    }
+
+    /// Asks for and decodes gzip compressed responses, enabled by default.
+    pub fn with_gzip(mut self, gzip: bool) -> Self {
+        self.gzip = gzip;
+        self
+    }
+
+    /// How many redirects to follow, `5` by default. `0` disables redirects.
+    pub fn with_max_redirects(mut self, max_redirects: usize) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// The local address to send tracker requests from, for both HTTP and UDP trackers.
+    pub fn with_bind_address(mut self, addr: IpAddr) -> Self {
+        self.bind_address = Some(addr);
+        self
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        self.connect_timeout
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn announce_timeout(&self) -> Duration {
+        self.announce_timeout
+    }
+
+    pub fn proxy(&self) -> Option<&str> {
+        self.proxy.as_deref()
+    }
+
+    pub fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+
+    pub fn peer_id_prefix(&self) -> &str {
+        &self.peer_id_prefix
+    }
+
+    pub fn gzip(&self) -> bool {
+        self.gzip
+    }
+
+    pub fn max_redirects(&self) -> usize {
+        self.max_redirects
+    }
+
+    pub fn bind_address(&self) -> Option<IpAddr> {
+        self.bind_address
+    }
+
+    /// Builds the HTTP client. Fails when the proxy url is invalid.
+    pub fn build(self) -> Result<TrackerClient, TrackerError> {
+        let redirect = if self.max_redirects == 0 {
+            redirect::Policy::none()
+        } else {
+            redirect::Policy::limited(self.max_redirects)
+        };
+        let mut builder = Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.timeout)
+            .user_agent(self.user_agent.clone())
+            .gzip(self.gzip)
+            .redirect(redirect)
+            .local_address(self.bind_address);
+        if let Some(proxy) = &self.proxy {
+            let proxy = Proxy::all(proxy.as_str())
+                .map_err(|e| TrackerError::InvalidUrl(Cow::Owned(format!("proxy: {}", e))))?;
+            builder = builder.proxy(proxy);
+        } else {
+            // 没有配置代理时不读取 HTTP_PROXY 等环境变量
+            builder = builder.no_proxy();
+        }
+        Ok(TrackerClient {
+            http: builder.build()?,
+            config: Arc::new(self),
+        })
+    }
+}
+
+/// A configured tracker client, shared by every tracker of a session. Cloning is cheap.
+#[derive(Debug, Clone)]
+pub struct TrackerClient {
+    http: Client,
+    config: Arc<TrackerConfig>,
+}
+
+impl TrackerClient {
+    pub fn config(&self) -> &TrackerConfig {
+        &self.config
+    }
+
+    pub(crate) fn http(&self) -> &Client {
+        &self.http
+    }
+}
+
+impl Default for TrackerClient {
+    fn default() -> Self {
+        TrackerConfig::default()
+            .build()
+            .expect("the default tracker config is valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::{mock, Matcher};
+
+    use super::*;
+    use crate::udp::tests::request;
+    use crate::Tracker;
+
+    #[test]
+    fn test_default_config() {
+        let config = TrackerConfig::default();
+        assert_eq!(config.timeout(), Duration::from_secs(30));
+        assert!(config.user_agent().starts_with("rs-torrent/"));
+        assert_eq!(config.peer_id_prefix().len(), 8);
+        assert!(config.gzip());
+        assert_eq!(config.proxy(), None);
+    }
+
+    #[test]
+    fn test_invalid_proxy() {
+        let result = TrackerConfig::default().with_proxy("not a url").build();
+        assert!(matches!(result, Err(TrackerError::InvalidUrl(_))));
+        TrackerConfig::default()
+            .with_proxy("socks5h://127.0.0.1:9050")
+            .build()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_user_agent_and_gzip() {
+        let client = TrackerConfig::default()
+            .with_user_agent("qBittorrent/4.4.2")
+            .build()
+            .unwrap();
+        let addr = format!("{}/config/announce", mockito::server_url());
+        let tracker = Tracker::with_client(addr.parse().unwrap(), &client);
+
+        // d8:intervali900e5:peers0:e 的 gzip 压缩
+        let body: &[u8] = &[
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0xff, 0x4b, 0xb1, 0xb0, 0xca,
+            0xcc, 0x2b, 0x49, 0x2d, 0x2a, 0x4b, 0xcc, 0xc9, 0xb4, 0x34, 0x30, 0x48, 0x35, 0xb5,
+            0x2a, 0x48, 0x4d, 0x2d, 0x2a, 0x36, 0xb0, 0x4a, 0x05, 0x00, 0xe6, 0x63, 0x03, 0xd7,
+            0x1a, 0x00, 0x00, 0x00,
+        ];
+        let _m = mock("GET", "/config/announce")
+            .match_query(Matcher::Any)
+            .match_header("user-agent", "qBittorrent/4.4.2")
+            .match_header("accept-encoding", Matcher::Regex("gzip".into()))
+            .with_status(200)
+            .with_header("content-encoding", "gzip")
+            .with_body(body)
+            .create();
+
+        let resp = tracker.find_peers(request()).await.unwrap();
+        assert_eq!(resp.interval, Duration::from_secs(900));
+    }
+
+    #[tokio::test]
+    async fn test_redirects() {
+        let addr = format!("{}/redirect/announce", mockito::server_url());
+        let _m1 = mock("GET", "/redirect/announce")
+            .match_query(Matcher::Any)
+            .with_status(302)
+            .with_header("location", "/redirected/announce")
+            .create();
+        let _m2 = mock("GET", "/redirected/announce")
+            .match_query(Matcher::Any)
+            .with_status(200)
+            .with_body("d8:intervali900e5:peers0:e")
+            .create();
+
+        let tracker = Tracker::with_client(addr.parse().unwrap(), &TrackerClient::default());
+        assert!(tracker.find_peers(request()).await.is_ok());
+
+        let client = TrackerConfig::default()
+            .with_max_redirects(0)
+            .build()
+            .unwrap();
+        let tracker = Tracker::with_client(addr.parse().unwrap(), &client);
+        // 不跟随重定向时拿到的是 302 的空响应
+        assert!(tracker.find_peers(request()).await.is_err());
+    }
+}
@@ -10,7 +10,21 @@ pub enum TrackerError {
     #[error(transparent)]
     BenObjectParseError(#[from] bencode::BencodeError),
     #[error(transparent)]
+    TorrentError(#[from] torrent::TorrentError),
+    #[error(transparent)]
     RequestError(#[from] ::reqwest::Error),
+    #[error("tracker failure: {0}")]
+    Failure(String),
+    #[error("invalid tracker url: {0}")]
+    InvalidUrl(::std::borrow::Cow<'static, str>),
+    #[error("tracker does not support scrape")]
+    ScrapeNotSupported,
+    #[error("torrent has no trackers")]
+    NoTrackers,
+    #[error("tracker did not respond")]
+    Timeout,
+    #[error(transparent)]
+    IOError(#[from] ::std::io::Error),
     #[error("unknown torrent error")]
     Unknown,
 }
@@ -1,16 +1,28 @@
 use bytes::Buf;
-use std::net::{IpAddr, Ipv4Addr};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::time::Duration;
 use std::{borrow::Cow, net::SocketAddr};
 
 use bencode::{BenObject, Dict};
 use error::TrackerError;
-use torrent::Sha1Hash;
+use torrent::{InfoHash, TorrentFile};
 
 use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
 use reqwest::{Client, Url};
 
+mod announcer;
+mod config;
 pub mod error;
+mod manager;
+mod session;
+mod udp;
+
+pub use crate::announcer::{Announce, Announcer, AnnouncerHandle};
+pub use crate::config::{TrackerClient, TrackerConfig};
+pub use crate::manager::TrackerManager;
+pub use crate::session::{generate_peer_id, peer_id_prefix, Session, TransferStats};
+pub use crate::udp::UdpTracker;
 
 // encode url的时候，保留一些字符 info_hash 和 peer_id encode需要保留下面的字符
 // https://en.wikipedia.org/wiki/Percent-encoding#Types_of_URI_characters
@@ -27,14 +39,25 @@ const URL_ENCODE_RESERVED: &AsciiSet = &NON_ALPHANUMERIC
 
 pub type PeerId = [u8; 20];
 
-#[derive(Debug)]
-pub struct Peer {
-    pub peer_id: String,
-    pub ip: IpAddr,
-    pub port: usize,
+/// A peer returned by a tracker.
+///
+/// Only trackers answering in the non-compact (dictionary) form send peer ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TrackerPeer {
+    pub addr: SocketAddr,
+    pub peer_id: Option<PeerId>,
+}
+
+impl From<SocketAddr> for TrackerPeer {
+    fn from(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            peer_id: None,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {
     Started,
     Completed,
@@ -51,9 +74,9 @@ impl Event {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Request {
-    pub info_hash: Sha1Hash,
+    pub info_hash: InfoHash,
     pub peer_id: PeerId,
     pub port: usize,
     pub uploaded: usize,
@@ -63,38 +86,106 @@ pub struct Request {
     pub no_peer_id: Option<bool>,
     pub event: Option<Event>,
     pub ip: Option<IpAddr>,
+    /// Our IPv4 address, sent when announcing over IPv6 so the tracker learns both (BEP 7).
+    pub ipv4: Option<Ipv4Addr>,
+    /// Our IPv6 address, sent when announcing over IPv4 (BEP 7).
+    pub ipv6: Option<Ipv6Addr>,
     pub numwant: Option<usize>,
     pub key: Option<String>,
     pub tracker_id: Option<String>,
 }
 
+impl Request {
+    /// A compact announce of the torrent with the session's peer id, key and port.
+    /// `left` is the torrent's length minus the verified bytes.
+    pub fn for_torrent(
+        torrent: &TorrentFile,
+        session: &Session,
+        stats: TransferStats,
+    ) -> Result<Self, TrackerError> {
+        Ok(Self {
+            info_hash: torrent.info.info_hash()?,
+            peer_id: *session.peer_id(),
+            port: session.port() as usize,
+            uploaded: stats.uploaded,
+            downloaded: stats.downloaded,
+            left: (torrent.info.length() - stats.verified).max(0),
+            compact: 1,
+            no_peer_id: None,
+            event: None,
+            ip: None,
+            ipv4: None,
+            ipv6: None,
+            numwant: None,
+            key: Some(session.key().to_owned()),
+            tracker_id: None,
+        })
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Response {
-    pub failure_reason: Option<String>,
+    /// A message the tracker wants shown to the user. The announce still succeeded.
     pub warning_message: Option<String>,
     pub interval: Duration,
     pub min_interval: Option<Duration>,
     pub tracker_id: Option<String>,
     pub complete: Option<i64>,
     pub incomplete: Option<i64>,
-    pub peers: Vec<SocketAddr>,
+    /// IPv4 peers from `peers` followed by IPv6 peers from `peers6`.
+    pub peers: Vec<TrackerPeer>,
+}
+
+/// Scrape statistics of one torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrapeFile {
+    /// Number of seeders.
+    pub complete: i64,
+    /// Number of times the torrent has been downloaded to completion.
+    pub downloaded: i64,
+    /// Number of leechers.
+    pub incomplete: i64,
+    pub name: Option<String>,
 }
 
 pub struct Tracker {
     client: Client,
     url: Url,
+    udp: Option<UdpTracker>,
 }
 
 impl Tracker {
     pub fn new(url: Url) -> Self {
+        Self::with_client(url, &TrackerClient::default())
+    }
+
+    /// Creates a tracker using the shared client of a session.
+    pub fn with_client(url: Url, client: &TrackerClient) -> Self {
+        let udp = if url.scheme() == "udp" {
+            let mut udp = UdpTracker::new(&url);
+            if let Some(addr) = client.config().bind_address() {
+                udp = udp.with_bind_address(addr);
+            }
+            Some(udp)
+        } else {
+            None
+        };
         Self {
-            // TODO: attation timeout
-            client: Client::new(),
+            client: client.http().clone(),
             url,
+            udp,
         }
     }
 
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    #[allow(clippy::unnecessary_to_owned)]
     pub async fn find_peers(&self, req: Request) -> Result<Response, TrackerError> {
+        if let Some(udp) = &self.udp {
+            return udp.announce(&req).await;
+        }
         let query = self.build_query(&req);
         let url = self.build_url(&req);
         // send request
@@ -110,25 +201,148 @@ impl Tracker {
         self.parse_bytes(resp.to_vec())
     }
 
+    /// Asks the tracker for the swarm statistics of the given torrents.
+    ///
+    /// Hashes the tracker does not know about are missing from the returned map.
+    pub async fn scrape(
+        &self,
+        info_hashes: &[InfoHash],
+    ) -> Result<HashMap<InfoHash, ScrapeFile>, TrackerError> {
+        if let Some(udp) = &self.udp {
+            return udp.scrape(info_hashes).await;
+        }
+        let url = self.scrape_url(info_hashes)?;
+        let resp = self
+            .client
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        self.parse_scrape(info_hashes, &resp)
+    }
+
+    // 按照惯例, 把 announce url 最后一段路径开头的 `announce` 换成 `scrape`:
+    // http://example.com/x/announce.php?passkey=1 -> http://example.com/x/scrape.php?passkey=1
+    // 最后一段不以 `announce` 开头的 tracker 不支持 scrape
+    fn scrape_url(&self, info_hashes: &[InfoHash]) -> Result<String, TrackerError> {
+        let mut url = self.url.clone();
+        let path = url.path().to_owned();
+        let (dir, last) = path.rsplit_once('/').unwrap_or(("", &path));
+        let rest = last
+            .strip_prefix("announce")
+            .ok_or(TrackerError::ScrapeNotSupported)?;
+        url.set_path(&format!("{}/scrape{}", dir, rest));
+
+        let mut url = url.to_string();
+        for (i, info_hash) in info_hashes.iter().enumerate() {
+            let sep = if i == 0 && self.url.query().is_none() {
+                '?'
+            } else {
+                '&'
+            };
+            url.push(sep);
+            url.push_str("info_hash=");
+            url.extend(percent_encoding::percent_encode(
+                &info_hash.truncated(),
+                URL_ENCODE_RESERVED,
+            ));
+        }
+        Ok(url)
+    }
+
+    // files 的 key 是 20 字节的 info hash, 不一定是合法的 UTF8
+    fn parse_scrape(
+        &self,
+        info_hashes: &[InfoHash],
+        bytes: &[u8],
+    ) -> Result<HashMap<InfoHash, ScrapeFile>, TrackerError> {
+        let mut dict = match BenObject::from_bytes(bytes)? {
+            BenObject::Dict(dict) => dict,
+            _ => return Err(TrackerError::InvalidResponse),
+        };
+        if let Some(reason) = self.failure_reason(&mut dict)? {
+            return Err(TrackerError::Failure(reason));
+        }
+        let files = match dict.remove("files").map(BenObject::into_bytes_dict) {
+            Some(Some(files)) => files,
+            Some(None) => {
+                return Err(TrackerError::ParseResponseError(Cow::Borrowed(
+                    "`files` does not map to dict.",
+                )))
+            }
+            None => {
+                return Err(TrackerError::ParseResponseError(Cow::Borrowed(
+                    "`files` does not exist.",
+                )))
+            }
+        };
+
+        let mut stats = HashMap::with_capacity(files.len());
+        for (key, obj) in files {
+            if key.len() != 20 {
+                return Err(TrackerError::ParseResponseError(Cow::Borrowed(
+                    "`files` key is not a 20 byte info hash.",
+                )));
+            }
+            // 把截断的 hash 对应回请求里的 v2/hybrid hash
+            let info_hash = info_hashes
+                .iter()
+                .find(|info_hash| info_hash.truncated()[..] == key[..])
+                .copied()
+                .unwrap_or_else(|| {
+                    let mut hash = [0; 20];
+                    hash.copy_from_slice(&key);
+                    InfoHash::V1(hash)
+                });
+            let mut file = match obj {
+                BenObject::Dict(file) => file,
+                _ => {
+                    return Err(TrackerError::ParseResponseError(Cow::Borrowed(
+                        "`files` entry does not map to dict.",
+                    )))
+                }
+            };
+            stats.insert(
+                info_hash,
+                ScrapeFile {
+                    complete: self.complete(&mut file)?.unwrap_or(0),
+                    downloaded: self.downloaded(&mut file)?.unwrap_or(0),
+                    incomplete: self.incomplete(&mut file)?.unwrap_or(0),
+                    name: self.name(&mut file)?,
+                },
+            );
+        }
+        Ok(stats)
+    }
+
+    #[allow(clippy::needless_return)]
     fn parse_bytes<T>(&self, bytes: T) -> Result<Response, TrackerError>
     where
         T: AsRef<[u8]>,
     {
         let mut obj = BenObject::from_bytes(bytes)?;
         match obj {
-            BenObject::Dict(ref mut dict) => Ok(Response {
-                failure_reason: self.failure_reason(dict)?,
-                warning_message: self.warning_message(dict)?,
-                interval: self.interval(dict)?,
-                min_interval: self.min_interval(dict)?,
-                tracker_id: self.tracker_id(dict)?,
-                complete: self.complete(dict)?,
-                incomplete: self.incomplete(dict)?,
-                peers: self.peers(dict)?,
-            }),
+            BenObject::Dict(ref mut dict) => {
+                // 失败的响应里只有 failure reason, 没有 interval 和 peers
+                if let Some(reason) = self.failure_reason(dict)? {
+                    return Err(TrackerError::Failure(reason));
+                }
+                Ok(Response {
+                    warning_message: self.warning_message(dict)?,
+                    interval: self.interval(dict)?,
+                    min_interval: self.min_interval(dict)?,
+                    tracker_id: self.tracker_id(dict)?,
+                    complete: self.complete(dict)?,
+                    incomplete: self.incomplete(dict)?,
+                    peers: self.peers(dict)?,
+                })
+            }
             _ => return Err(TrackerError::InvalidResponse),
         }
     }
+    #[allow(clippy::needless_return)]
     fn port(&self, dict: &mut Dict) -> Result<u16, TrackerError> {
         match dict.remove("port") {
             Some(BenObject::Int(port)) => Ok(port as u16),
@@ -144,6 +358,7 @@ impl Tracker {
             }
         }
     }
+    #[allow(clippy::needless_return)]
     fn ip(&self, dict: &mut Dict) -> Result<IpAddr, TrackerError> {
         match dict.remove("ip") {
             Some(BenObject::String(ip)) => {
@@ -169,55 +384,66 @@ impl Tracker {
         }
     }
 
-    // peers值可以是一个由 6 个字节的倍数组成的字符串。
-    // 前 4 个字节是 IP 地址，后 2 个字节是端口号。全部采用网络（大端）表示法
-    fn peers_from_bytes(&self, mut bytes: &[u8]) -> Result<Vec<SocketAddr>, TrackerError> {
-        const ENTRY_LEN: usize = 6;
-        let buf_len = bytes.len();
-        if buf_len % ENTRY_LEN != 0 {
-            return Err(TrackerError::ParseResponseError(Cow::Borrowed(
-                "peers compact string must be a multiple of 6",
-            )));
-        }
-
-        let buf_len = bytes.len();
-        let mut peers = Vec::with_capacity(buf_len * ENTRY_LEN);
-        for _ in (0..buf_len).step_by(ENTRY_LEN) {
-            let addr = Ipv4Addr::from(bytes.get_u32());
-            let port = bytes.get_u16();
-            let peer = SocketAddr::new(IpAddr::V4(addr), port);
-            peers.push(peer);
-        }
-        Ok(peers)
+    fn peer_id(&self, dict: &mut Dict) -> Result<Option<PeerId>, TrackerError> {
+        let bytes = match dict.remove("peer id") {
+            Some(BenObject::Bytes(bytes)) => bytes,
+            Some(BenObject::String(id)) => id.into_bytes(),
+            Some(_) => {
+                return Err(TrackerError::ParseResponseError(Cow::Borrowed(
+                    "`peer id` does not map to bytes.",
+                )))
+            }
+            None => return Ok(None),
+        };
+        // 长度不对的 peer id 当作没有
+        Ok(bytes.try_into().ok())
     }
 
-    fn peers(&self, dict: &mut Dict) -> Result<Vec<SocketAddr>, TrackerError> {
-        match dict.remove("peers") {
-            Some(BenObject::Bytes(ref bytes)) => self.peers_from_bytes(bytes),
+    // compact 的字符串可能恰好是合法的 UTF8, 被解析成 String
+    fn peers(&self, dict: &mut Dict) -> Result<Vec<TrackerPeer>, TrackerError> {
+        let mut peers = match dict.remove("peers") {
+            Some(BenObject::Bytes(ref bytes)) => peers_v4(bytes)?,
+            Some(BenObject::String(ref string)) => peers_v4(string.as_bytes())?,
             Some(BenObject::List(ref mut list)) => {
                 let mut peers = Vec::with_capacity(list.len());
                 for obj in list {
                     if let BenObject::Dict(dict) = obj {
                         let ip = self.ip(dict)?;
                         let port = self.port(dict)?;
-                        peers.push(SocketAddr::new(ip, port));
+                        peers.push(TrackerPeer {
+                            addr: SocketAddr::new(ip, port),
+                            peer_id: self.peer_id(dict)?,
+                        });
                     }
                 }
-                Ok(peers)
+                peers
             }
             Some(_) => {
                 return Err(TrackerError::ParseResponseError(Cow::Borrowed(
                     "`peers` does not map to bytes or list.",
                 )))
             }
+            None if dict.contains_key("peers6") => Vec::new(),
             None => {
                 return Err(TrackerError::ParseResponseError(Cow::Borrowed(
                     "`peers` does not exist.",
                 )))
             }
+        };
+        match dict.remove("peers6") {
+            Some(BenObject::Bytes(ref bytes)) => peers.extend(peers_v6(bytes)?),
+            Some(BenObject::String(ref string)) => peers.extend(peers_v6(string.as_bytes())?),
+            Some(_) => {
+                return Err(TrackerError::ParseResponseError(Cow::Borrowed(
+                    "`peers6` does not map to bytes.",
+                )))
+            }
+            None => {}
         }
+        Ok(peers)
     }
 
+    #[allow(clippy::needless_return)]
     fn incomplete(&self, dict: &mut Dict) -> Result<Option<i64>, TrackerError> {
         match dict.remove("incomplete") {
             Some(BenObject::Int(incomplete)) => Ok(Some(incomplete)),
@@ -229,6 +455,7 @@ impl Tracker {
             None => Ok(None),
         }
     }
+    #[allow(clippy::needless_return)]
     fn complete(&self, dict: &mut Dict) -> Result<Option<i64>, TrackerError> {
         match dict.remove("complete") {
             Some(BenObject::Int(complete)) => Ok(Some(complete)),
@@ -240,6 +467,29 @@ impl Tracker {
             None => Ok(None),
         }
     }
+    fn downloaded(&self, dict: &mut Dict) -> Result<Option<i64>, TrackerError> {
+        match dict.remove("downloaded") {
+            Some(BenObject::Int(downloaded)) => Ok(Some(downloaded)),
+            Some(_) => {
+                Err(TrackerError::ParseResponseError(Cow::Borrowed(
+                    "`downloaded` does not map to int.",
+                )))
+            }
+            None => Ok(None),
+        }
+    }
+    fn name(&self, dict: &mut Dict) -> Result<Option<String>, TrackerError> {
+        match dict.remove("name") {
+            Some(BenObject::String(name)) => Ok(Some(name)),
+            Some(_) => {
+                Err(TrackerError::ParseResponseError(Cow::Borrowed(
+                    "`name` does not map to string (or maps to invalid UTF8).",
+                )))
+            }
+            None => Ok(None),
+        }
+    }
+    #[allow(clippy::needless_return)]
     fn tracker_id(&self, dict: &mut Dict) -> Result<Option<String>, TrackerError> {
         match dict.remove("tracker id") {
             Some(BenObject::String(id)) => Ok(Some(id)),
@@ -252,6 +502,7 @@ impl Tracker {
         }
     }
 
+    #[allow(clippy::needless_return)]
     fn min_interval(&self, dict: &mut Dict) -> Result<Option<Duration>, TrackerError> {
         match dict.remove("min interval") {
             Some(BenObject::Int(min_interval)) => {
@@ -266,6 +517,7 @@ impl Tracker {
         }
     }
 
+    #[allow(clippy::needless_return)]
     fn interval(&self, dict: &mut Dict) -> Result<Duration, TrackerError> {
         match dict.remove("interval") {
             Some(BenObject::Int(interval)) => Ok(Duration::from_secs(interval as u64)),
@@ -283,22 +535,29 @@ impl Tracker {
     }
 
     fn warning_message(&self, dict: &mut Dict) -> Result<Option<String>, TrackerError> {
-        match dict.remove("warning_message") {
+        match dict.remove("warning message") {
             Some(BenObject::String(warn)) => Ok(Some(warn)),
+            Some(BenObject::Bytes(warn)) => {
+                Ok(Some(String::from_utf8_lossy(&warn).into_owned()))
+            }
             Some(_) => {
-                return Err(TrackerError::ParseResponseError(Cow::Borrowed(
-                    "`warning_message` does not map to string (or maps to invalid UTF8).",
+                Err(TrackerError::ParseResponseError(Cow::Borrowed(
+                    "`warning message` does not map to string.",
                 )))
             }
             None => Ok(None),
         }
     }
+    // 有的 tracker 返回的消息不是 UTF8 编码, 尽量显示出来
     fn failure_reason(&self, dict: &mut Dict) -> Result<Option<String>, TrackerError> {
-        match dict.remove("failure_reason") {
+        match dict.remove("failure reason") {
             Some(BenObject::String(reason)) => Ok(Some(reason)),
+            Some(BenObject::Bytes(reason)) => {
+                Ok(Some(String::from_utf8_lossy(&reason).into_owned()))
+            }
             Some(_) => {
-                return Err(TrackerError::ParseResponseError(Cow::Borrowed(
-                    "`failure_reason` does not map to string (or maps to invalid UTF8).",
+                Err(TrackerError::ParseResponseError(Cow::Borrowed(
+                    "`failure reason` does not map to string.",
                 )))
             }
             None => Ok(None),
@@ -306,12 +565,15 @@ impl Tracker {
     }
 
     fn build_url(&self, req: &Request) -> String {
+        // 私有 tracker 的 announce URL 可能已经带有查询参数, 比如 passkey
+        let sep = if self.url.query().is_none() { '?' } else { '&' };
         format!(
             "{url}\
-            ?info_hash={info_hash}\
+            {sep}info_hash={info_hash}\
             &peer_id={peer_id}",
             url = self.url,
-            info_hash = percent_encoding::percent_encode(&req.info_hash, URL_ENCODE_RESERVED),
+            info_hash =
+                percent_encoding::percent_encode(&req.info_hash.truncated(), URL_ENCODE_RESERVED),
             peer_id = percent_encoding::percent_encode(&req.peer_id, URL_ENCODE_RESERVED),
         )
     }
@@ -324,8 +586,9 @@ impl Tracker {
             ("left", req.left.to_string()),
             ("compact", req.compact.to_string()),
         ];
-        if req.compact != 1 {
-            query.push(("no_peer_id", "".to_string()));
+        // 只在调用方要求时才让 tracker 省略 peer id
+        if req.no_peer_id == Some(true) {
+            query.push(("no_peer_id", "1".to_string()));
         }
         if let Some(event) = &req.event {
             query.push(("event", event.event()));
@@ -333,6 +596,12 @@ impl Tracker {
         if let Some(ip) = req.ip {
             query.push(("ip", ip.to_string()))
         }
+        if let Some(ipv4) = req.ipv4 {
+            query.push(("ipv4", ipv4.to_string()))
+        }
+        if let Some(ipv6) = req.ipv6 {
+            query.push(("ipv6", ipv6.to_string()))
+        }
         if let Some(numwant) = req.numwant {
             query.push(("numwant", numwant.to_string()))
         }
@@ -346,6 +615,36 @@ impl Tracker {
     }
 }
 
+// compact 格式 (BEP 23): 每个 peer 6 个字节, 4 个字节的 IP 地址加 2 个字节的端口, 都是大端
+pub(crate) fn peers_v4(mut bytes: &[u8]) -> Result<Vec<TrackerPeer>, TrackerError> {
+    if !bytes.len().is_multiple_of(6) {
+        return Err(TrackerError::ParseResponseError(Cow::Borrowed(
+            "peers must be a multiple of 6 bytes",
+        )));
+    }
+    let mut peers = Vec::with_capacity(bytes.len() / 6);
+    while bytes.has_remaining() {
+        let ip = Ipv4Addr::from(bytes.get_u32());
+        peers.push(SocketAddr::new(IpAddr::V4(ip), bytes.get_u16()).into());
+    }
+    Ok(peers)
+}
+
+// IPv6 的 compact 格式 (BEP 7): 每个 peer 18 个字节, 16 个字节的 IP 地址加 2 个字节的端口
+pub(crate) fn peers_v6(mut bytes: &[u8]) -> Result<Vec<TrackerPeer>, TrackerError> {
+    if !bytes.len().is_multiple_of(18) {
+        return Err(TrackerError::ParseResponseError(Cow::Borrowed(
+            "IPv6 peers must be a multiple of 18 bytes",
+        )));
+    }
+    let mut peers = Vec::with_capacity(bytes.len() / 18);
+    while bytes.has_remaining() {
+        let ip = Ipv6Addr::from(bytes.get_u128());
+        peers.push(SocketAddr::new(IpAddr::V6(ip), bytes.get_u16()).into());
+    }
+    Ok(peers)
+}
+
 #[cfg(test)]
 mod tests {
     use mockito::{mock, Matcher};
@@ -367,7 +666,7 @@ mod tests {
         peer_id.copy_from_slice(peer_id_str.as_bytes());
 
         let req = Request {
-            info_hash,
+            info_hash: InfoHash::V1(info_hash),
             peer_id,
             port: 16,
             downloaded: 1234,
@@ -377,6 +676,8 @@ mod tests {
             no_peer_id: None,
             numwant: Some(1),
             ip: None,
+            ipv4: None,
+            ipv6: None,
             event: None,
             key: None,
             tracker_id: None,
@@ -385,13 +686,12 @@ mod tests {
         let peer_port = 49123;
         let expected = Response {
             tracker_id: None,
-            failure_reason: None,
             warning_message: None,
             interval: Duration::from_secs(15),
             min_interval: Some(Duration::from_secs(10)),
             complete: Some(5),
             incomplete: Some(3),
-            peers: vec![SocketAddr::new(peer_ip.into(), peer_port)],
+            peers: vec![SocketAddr::new(peer_ip.into(), peer_port).into()],
         };
 
         let mut encoded_resp = Vec::new();
@@ -428,6 +728,285 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[tokio::test]
+    async fn test_mock_tracker_peers6() {
+        let addr = format!("{}/ipv6/announce", mockito::server_url());
+        let tracker = Tracker::new(addr.parse().unwrap());
+
+        let mut req = crate::udp::tests::request();
+        req.ipv4 = Some(Ipv4Addr::new(192, 0, 2, 1));
+        req.ipv6 = Some("2001:db8::2".parse().unwrap());
+
+        let peer6: SocketAddr = "[2001:db8::1]:51413".parse().unwrap();
+        let mut encoded_resp = Vec::new();
+        encoded_resp.extend_from_slice(b"d8:intervali15e5:peers0:6:peers618:");
+        encoded_resp.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        encoded_resp.extend_from_slice(&51413u16.to_be_bytes());
+        encoded_resp.push(b'e');
+
+        let _m = mock("GET", "/ipv6/announce")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("ipv4".into(), "192.0.2.1".into()),
+                Matcher::UrlEncoded("ipv6".into(), "2001:db8::2".into()),
+            ]))
+            .with_status(200)
+            .with_body(encoded_resp)
+            .create();
+
+        let actual = tracker.find_peers(req).await.unwrap();
+        assert_eq!(actual.peers, vec![peer6.into()]);
+    }
+
+    #[tokio::test]
+    async fn test_mock_tracker_failure() {
+        let addr = format!("{}/failure/announce", mockito::server_url());
+        let tracker = Tracker::new(addr.parse().unwrap());
+
+        // opentracker 对不在白名单里的种子的响应
+        let _m = mock("GET", "/failure/announce")
+            .match_query(Matcher::Any)
+            .with_status(200)
+            .with_body(
+                "d14:failure reason63:Requested download is not authorized for use with this tracker.e",
+            )
+            .create();
+
+        match tracker.find_peers(crate::udp::tests::request()).await {
+            Err(TrackerError::Failure(reason)) => assert_eq!(
+                reason,
+                "Requested download is not authorized for use with this tracker."
+            ),
+            other => panic!("expect failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_failure_and_warning() {
+        let tracker = Tracker::new("http://example.com/announce".parse().unwrap());
+
+        // 失败的响应里也可能带着 interval, 仍然是失败
+        let resp =
+            tracker.parse_bytes(b"d14:failure reason20:unregistered torrent8:intervali1800ee");
+        assert!(
+            matches!(resp, Err(TrackerError::Failure(reason)) if reason == "unregistered torrent")
+        );
+
+        // GBK 编码的消息
+        let resp = tracker.parse_bytes(b"d14:failure reason4:\xb4\xed\xce\xf3e");
+        assert!(matches!(resp, Err(TrackerError::Failure(_))));
+
+        let resp = tracker
+            .parse_bytes(
+                b"d8:completei2e10:incompletei0e8:intervali1800e12:min intervali1800e\
+                5:peers0:15:warning message39:Your client is outdated, please upgradee",
+            )
+            .unwrap();
+        assert_eq!(
+            resp.warning_message.as_deref(),
+            Some("Your client is outdated, please upgrade")
+        );
+        assert_eq!(resp.interval, Duration::from_secs(1800));
+        assert!(resp.peers.is_empty());
+
+        let resp = tracker.parse_scrape(&[], b"d14:failure reason20:unregistered torrente");
+        assert!(
+            matches!(resp, Err(TrackerError::Failure(reason)) if reason == "unregistered torrent")
+        );
+
+        assert!(matches!(
+            tracker.parse_bytes(b"d14:failure reasoni1ee"),
+            Err(TrackerError::ParseResponseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_peers() {
+        let tracker = Tracker::new("http://example.com/announce".parse().unwrap());
+
+        // 非 compact 格式, 带 peer id
+        let resp = tracker
+            .parse_bytes(
+                b"d8:intervali15e5:peersl\
+                d2:ip8:10.0.0.17:peer id20:-RS0001-0123456789ab4:porti6881ee\
+                d2:ip11:2001:db8::14:porti51413eeee",
+            )
+            .unwrap();
+        assert_eq!(
+            resp.peers,
+            vec![
+                TrackerPeer {
+                    addr: "10.0.0.1:6881".parse().unwrap(),
+                    peer_id: Some(*b"-RS0001-0123456789ab"),
+                },
+                TrackerPeer {
+                    addr: "[2001:db8::1]:51413".parse().unwrap(),
+                    peer_id: None,
+                },
+            ]
+        );
+
+        // compact 字符串恰好是合法的 UTF8, 同时有 peers 和 peers6
+        let mut bytes = b"d8:intervali15e5:peers6:AAAA\x1a\xe16:peers618:".to_vec();
+        bytes.extend_from_slice(&[0; 15]);
+        bytes.extend_from_slice(&[1, 0, 80, b'e']);
+        let resp = tracker.parse_bytes(bytes).unwrap();
+        assert_eq!(
+            resp.peers,
+            vec![
+                "65.65.65.65:6881".parse::<SocketAddr>().unwrap().into(),
+                "[::1]:80".parse::<SocketAddr>().unwrap().into(),
+            ]
+        );
+
+        assert!(tracker
+            .parse_bytes(b"d8:intervali15e6:peers65:AAAAAe")
+            .is_err());
+        assert!(tracker.parse_bytes(b"d8:intervali15ee").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mock_tracker_scrape() {
+        let addr = format!("{}/tracker/announce.php?passkey=abc", mockito::server_url());
+        let tracker = Tracker::new(addr.parse().unwrap());
+
+        let first_str = "abcdefghij1234567890";
+        let mut first = [0; 20];
+        first.copy_from_slice(first_str.as_bytes());
+        // 不是合法 UTF8 的 hash
+        let second = [0xff; 20];
+        let info_hashes = [InfoHash::V1(first), InfoHash::V1(second)];
+
+        let mut encoded_resp = Vec::new();
+        encoded_resp.extend_from_slice(b"d5:filesd20:");
+        encoded_resp.extend_from_slice(&first);
+        encoded_resp.extend_from_slice(
+            b"d8:completei5e10:downloadedi50e10:incompletei10e4:name9:startduske20:",
+        );
+        encoded_resp.extend_from_slice(&second);
+        encoded_resp.extend_from_slice(b"d8:completei1e10:downloadedi2e10:incompletei3eeee");
+
+        let _m = mock("GET", "/tracker/scrape.php")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("passkey".into(), "abc".into()),
+                // UrlEncoded 会把重复的 key 合并, 只能直接匹配原始的 query
+                Matcher::Regex(format!("info_hash={}&", first_str)),
+                Matcher::Regex("info_hash=(%FF){20}$".into()),
+            ]))
+            .with_status(200)
+            .with_body(encoded_resp)
+            .create();
+
+        let actual = tracker.scrape(&info_hashes).await.unwrap();
+        assert_eq!(actual.len(), 2);
+        assert_eq!(
+            actual[&info_hashes[0]],
+            ScrapeFile {
+                complete: 5,
+                downloaded: 50,
+                incomplete: 10,
+                name: Some("startdusk".to_owned()),
+            }
+        );
+        assert_eq!(
+            actual[&info_hashes[1]],
+            ScrapeFile {
+                complete: 1,
+                downloaded: 2,
+                incomplete: 3,
+                name: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_tracker_scrape_v2() {
+        let addr = format!("{}/announce", mockito::server_url());
+        let tracker = Tracker::new(addr.parse().unwrap());
+
+        let info_hash = InfoHash::V2([b'a'; 32]);
+        let mut encoded_resp = Vec::new();
+        encoded_resp.extend_from_slice(b"d5:filesd20:");
+        encoded_resp.extend_from_slice(&info_hash.truncated());
+        encoded_resp.extend_from_slice(b"d8:completei7eeee");
+
+        let _m = mock("GET", "/scrape")
+            .match_query(Matcher::UrlEncoded("info_hash".into(), "a".repeat(20)))
+            .with_status(200)
+            .with_body(encoded_resp)
+            .create();
+
+        let actual = tracker.scrape(&[info_hash]).await.unwrap();
+        assert_eq!(actual[&info_hash].complete, 7);
+        assert_eq!(actual[&info_hash].downloaded, 0);
+    }
+
+    #[test]
+    fn test_build_url() {
+        let mut req = crate::udp::tests::request();
+        req.info_hash = InfoHash::V1([b'a'; 20]);
+        req.peer_id = [b'b'; 20];
+        let build_url = |url: &str| Tracker::new(url.parse().unwrap()).build_url(&req);
+        let query = format!("info_hash={}&peer_id={}", "a".repeat(20), "b".repeat(20));
+
+        assert_eq!(
+            build_url("http://example.com/announce"),
+            format!("http://example.com/announce?{}", query)
+        );
+        assert_eq!(
+            build_url("http://example.com/announce.php?passkey=abc"),
+            format!("http://example.com/announce.php?passkey=abc&{}", query)
+        );
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        let info_hash = InfoHash::V1([b'a'; 20]);
+        let scrape_url = |url: &str| Tracker::new(url.parse().unwrap()).scrape_url(&[info_hash]);
+
+        assert_eq!(
+            scrape_url("http://example.com/announce").unwrap(),
+            format!("http://example.com/scrape?info_hash={}", "a".repeat(20))
+        );
+        assert_eq!(
+            scrape_url("http://example.com/x/announce.php?k=1").unwrap(),
+            format!(
+                "http://example.com/x/scrape.php?k=1&info_hash={}",
+                "a".repeat(20)
+            )
+        );
+        assert!(matches!(
+            scrape_url("http://example.com/a"),
+            Err(TrackerError::ScrapeNotSupported)
+        ));
+        assert!(matches!(
+            scrape_url("http://example.com/announce/x"),
+            Err(TrackerError::ScrapeNotSupported)
+        ));
+        assert!(matches!(
+            scrape_url("http://example.com/x%064announce"),
+            Err(TrackerError::ScrapeNotSupported)
+        ));
+    }
+
+    #[test]
+    fn test_no_peer_id_query() {
+        let tracker = Tracker::new("http://example.com/announce".parse().unwrap());
+        let mut req = crate::udp::tests::request();
+        req.compact = 0;
+        let has_no_peer_id = |req: &Request| {
+            tracker
+                .build_query(req)
+                .iter()
+                .any(|(key, value)| *key == "no_peer_id" && value == "1")
+        };
+        assert!(!has_no_peer_id(&req));
+        req.no_peer_id = Some(true);
+        assert!(has_no_peer_id(&req));
+        req.no_peer_id = Some(false);
+        assert!(!has_no_peer_id(&req));
+    }
+
+    #[allow(clippy::map_flatten)]
     fn encode_compact_peers_list(peers: &[(Ipv4Addr, u16)]) -> Vec<u8> {
         let encoded_peers: Vec<_> = peers
             .iter()
@@ -0,0 +1,253 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::future::join_all;
+use rand::seq::SliceRandom;
+use reqwest::Url;
+use tokio::time;
+use torrent::TorrentFile;
+
+use crate::error::TrackerError;
+use crate::{Request, Response, Tracker, TrackerClient};
+
+/// Announces to the trackers of a torrent's `announce-list` (BEP 12).
+///
+/// The trackers of each tier are shuffled once, then tried in order until one
+/// responds; the responding tracker is moved to the front of its tier so it is
+/// tried first next time. Every tier is announced to at the same time and the
+/// peers are merged, so the torrent keeps finding peers while its primary
+/// tracker is down. A tracker that does not respond within the
+/// [announce timeout](crate::TrackerConfig::with_announce_timeout) counts as failed.
+pub struct TrackerManager {
+    trackers: Vec<Tracker>,
+    // 每个 tier 里 tracker 的下标, 按尝试顺序排列
+    tiers: Mutex<Vec<Vec<usize>>>,
+    timeout: Duration,
+}
+
+impl TrackerManager {
+    /// Builds the manager from tiers of tracker urls, the first tier being tried first.
+    /// `http`, `https` and `udp` trackers can be mixed in any tier.
+    pub fn new(tiers: Vec<Vec<Url>>) -> Self {
+        Self::with_client(tiers, &TrackerClient::default())
+    }
+
+    /// Like [`TrackerManager::new`], with every tracker using the shared client of a session.
+    pub fn with_client(tiers: Vec<Vec<Url>>, client: &TrackerClient) -> Self {
+        let mut rng = rand::thread_rng();
+        let mut trackers = Vec::new();
+        let mut order = Vec::with_capacity(tiers.len());
+        for tier in tiers.into_iter().filter(|tier| !tier.is_empty()) {
+            let mut indices: Vec<usize> = (trackers.len()..trackers.len() + tier.len()).collect();
+            indices.shuffle(&mut rng);
+            trackers.extend(
+                tier.into_iter()
+                    .map(|url| Tracker::with_client(url, client)),
+            );
+            order.push(indices);
+        }
+        Self {
+            trackers,
+            tiers: Mutex::new(order),
+            timeout: client.config().announce_timeout(),
+        }
+    }
+
+    /// Uses the `announce-list` of the torrent, or `announce` when there is none.
+    /// Urls that do not parse are skipped.
+    pub fn from_torrent(torrent: &TorrentFile) -> Self {
+        Self::new(
+            torrent
+                .tiers()
+                .iter()
+                .map(|tier| tier.iter().filter_map(|url| url.parse().ok()).collect())
+                .collect(),
+        )
+    }
+
+    /// The tracker urls of every tier, in the order they will be tried.
+    pub fn tiers(&self) -> Vec<Vec<Url>> {
+        self.tiers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|tier| {
+                tier.iter()
+                    .map(|&i| self.trackers[i].url().clone())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Announces to one tracker of every tier and merges their peers.
+    ///
+    /// The rest of the response (interval, tracker id, ...) comes from the
+    /// first tier that responded. Fails with the last error when no tracker responded.
+    pub async fn announce(&self, req: &Request) -> Result<Response, TrackerError> {
+        let tier_count = self.tiers.lock().unwrap().len();
+        let results = join_all((0..tier_count).map(|tier| self.announce_tier(tier, req))).await;
+        let mut merged: Option<Response> = None;
+        let mut seen = HashSet::new();
+        let mut last_err = TrackerError::NoTrackers;
+        for result in results {
+            match result {
+                Ok(resp) => {
+                    let merged = merged.get_or_insert_with(|| Response {
+                        peers: Vec::new(),
+                        ..resp.clone()
+                    });
+                    for peer in resp.peers {
+                        if seen.insert(peer.addr) {
+                            merged.peers.push(peer);
+                        }
+                    }
+                }
+                Err(err) => last_err = err,
+            }
+        }
+        merged.ok_or(last_err)
+    }
+
+    async fn announce_tier(&self, tier: usize, req: &Request) -> Result<Response, TrackerError> {
+        // 不能在 await 的时候持有锁, 先复制一份顺序
+        let order = self.tiers.lock().unwrap()[tier].clone();
+        let mut last_err = TrackerError::NoTrackers;
+        for index in order {
+            let find_peers = self.trackers[index].find_peers(req.clone());
+            match time::timeout(self.timeout, find_peers).await {
+                Ok(Ok(resp)) => {
+                    self.promote(tier, index);
+                    return Ok(resp);
+                }
+                Ok(Err(err)) => last_err = err,
+                Err(_) => last_err = TrackerError::Timeout,
+            }
+        }
+        Err(last_err)
+    }
+
+    fn promote(&self, tier: usize, index: usize) {
+        let mut tiers = self.tiers.lock().unwrap();
+        let tier = &mut tiers[tier];
+        if let Some(pos) = tier.iter().position(|&i| i == index) {
+            tier.remove(pos);
+            tier.insert(0, index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+    use std::time::Duration;
+
+    use mockito::mock;
+
+    use super::*;
+    use crate::udp::tests::{request, stand_in};
+    use crate::TrackerConfig;
+
+    // 一个没有监听的端口, 连接会被立即拒绝
+    fn dead_url() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        format!("http://{}/announce", addr).parse().unwrap()
+    }
+
+    fn mock_tracker(path: &str, interval: i64, peers: &[u8]) -> mockito::Mock {
+        let mut body = format!("d8:intervali{}e5:peers{}:", interval, peers.len()).into_bytes();
+        body.extend_from_slice(peers);
+        body.push(b'e');
+        mock("GET", path)
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_body(body)
+            .create()
+    }
+
+    #[tokio::test]
+    async fn test_failover_and_promotion() {
+        let _m = mock_tracker("/manager/a", 900, &[200, 1, 1, 1, 0, 80]);
+        let alive: Url = format!("{}/manager/a", mockito::server_url())
+            .parse()
+            .unwrap();
+        let dead = dead_url();
+        let manager = TrackerManager::new(vec![vec![dead.clone(), alive.clone()]]);
+
+        let resp = manager.announce(&request()).await.unwrap();
+        assert_eq!(resp.interval, Duration::from_secs(900));
+        assert_eq!(resp.peers[0].addr, "200.1.1.1:80".parse().unwrap());
+        assert_eq!(manager.tiers(), vec![vec![alive, dead]]);
+    }
+
+    #[tokio::test]
+    async fn test_merge_tiers() {
+        // 10.0.0.1:6881 也会由 UDP tracker 返回
+        let _m = mock_tracker(
+            "/manager/b",
+            900,
+            &[200, 1, 1, 1, 0, 80, 10, 0, 0, 1, 0x1a, 0xe1],
+        );
+        let (udp, _) = stand_in("127.0.0.1:0", 0, None).await;
+        let http: Url = format!("{}/manager/b", mockito::server_url())
+            .parse()
+            .unwrap();
+        let manager = TrackerManager::new(vec![vec![dead_url()], vec![http], vec![], vec![udp]]);
+        assert_eq!(manager.tiers().len(), 3);
+
+        let resp = manager.announce(&request()).await.unwrap();
+        assert_eq!(resp.interval, Duration::from_secs(900));
+        assert_eq!(resp.peers.len(), 3);
+        let peers: HashSet<SocketAddr> = resp.peers.iter().map(|peer| peer.addr).collect();
+        assert_eq!(
+            peers,
+            HashSet::from([
+                "200.1.1.1:80".parse().unwrap(),
+                "2.156.201.254:49123".parse().unwrap(),
+                "10.0.0.1:6881".parse().unwrap(),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_announce_timeout() {
+        let _m = mock_tracker("/manager/c", 900, &[200, 1, 1, 1, 0, 80]);
+        let http: Url = format!("{}/manager/c", mockito::server_url())
+            .parse()
+            .unwrap();
+        // 不回应的 UDP tracker, 按默认的重传会等很久
+        let (silent, _) = stand_in("127.0.0.1:0", usize::MAX, None).await;
+        let client = TrackerConfig::default()
+            .with_announce_timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let manager = TrackerManager::with_client(vec![vec![silent.clone()], vec![http]], &client);
+
+        let started = std::time::Instant::now();
+        let resp = manager.announce(&request()).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(resp.peers.len(), 1);
+
+        let manager = TrackerManager::with_client(vec![vec![silent]], &client);
+        assert!(matches!(
+            manager.announce(&request()).await,
+            Err(TrackerError::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_no_tracker_responds() {
+        let manager = TrackerManager::new(vec![vec![dead_url()]]);
+        assert!(matches!(
+            manager.announce(&request()).await,
+            Err(TrackerError::RequestError(_))
+        ));
+        let manager = TrackerManager::new(Vec::new());
+        assert!(matches!(
+            manager.announce(&request()).await,
+            Err(TrackerError::NoTrackers)
+        ));
+    }
+}
@@ -0,0 +1,175 @@
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use crate::PeerId;
+
+/// Builds an Azureus-style peer id prefix such as `-RS0100-` from a two letter
+/// client code and a dotted version.
+///
+/// The client code is always two characters: a shorter one is padded with `-`,
+/// a longer one is cut, and anything but ASCII letters and digits becomes `-`.
+/// Each of the first four version components becomes one character (`0`-`9`,
+/// then `A`-`Z` for 10 to 35), missing components are `0`.
+///
+/// ```
+/// assert_eq!(tracker::peer_id_prefix("RS", "0.1.0"), "-RS0100-");
+/// assert_eq!(tracker::peer_id_prefix("qB", "4.4.12"), "-qB44C0-");
+/// assert_eq!(tracker::peer_id_prefix("R", "0.1.0"), "-R-0100-");
+/// ```
+pub fn peer_id_prefix(client: &str, version: &str) -> String {
+    // 前缀必须正好 8 字节, 所以客户端代码固定为两个 ASCII 字符
+    let client: String = client
+        .chars()
+        .chain(std::iter::repeat('-'))
+        .take(2)
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let mut digits: String = version
+        .split('.')
+        .take(4)
+        .map(|part| {
+            part.parse::<u32>()
+                .ok()
+                .and_then(|n| char::from_digit(n, 36))
+                .map_or('0', |c| c.to_ascii_uppercase())
+        })
+        .collect();
+    while digits.len() < 4 {
+        digits.push('0');
+    }
+    format!("-{}{}-", client, digits)
+}
+
+/// A peer id made of the prefix (cut to 20 bytes) followed by random letters and digits.
+pub fn generate_peer_id(prefix: &str) -> PeerId {
+    let mut peer_id = [0; 20];
+    let prefix = &prefix.as_bytes()[..prefix.len().min(20)];
+    peer_id[..prefix.len()].copy_from_slice(prefix);
+    let mut rng = rand::thread_rng();
+    for byte in &mut peer_id[prefix.len()..] {
+        *byte = rng.sample(Alphanumeric);
+    }
+    peer_id
+}
+
+/// What identifies this client to trackers for as long as it runs: the peer id,
+/// the `key` and the port peers connect to.
+///
+/// Create one per session and announce every torrent with it, so trackers can
+/// recognize the client when its IP address changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    peer_id: PeerId,
+    key: String,
+    port: u16,
+}
+
+impl Session {
+    pub fn new(peer_id_prefix: &str, port: u16) -> Self {
+        Self {
+            peer_id: generate_peer_id(peer_id_prefix),
+            // UDP tracker 要求 key 是 32 位整数, 所以用 8 位十六进制
+            key: format!("{:08X}", rand::random::<u32>()),
+            port,
+        }
+    }
+
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Default for Session {
+    /// A session with the default peer id prefix listening on port `6881`.
+    fn default() -> Self {
+        Self::new(crate::TrackerConfig::default().peer_id_prefix(), 6881)
+    }
+}
+
+/// The transfer totals of a torrent reported in announces.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferStats {
+    pub uploaded: usize,
+    pub downloaded: usize,
+    /// Bytes of the torrent already verified on disk. `left` is the rest.
+    pub verified: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use torrent::TorrentFile;
+
+    use super::*;
+    use crate::{Event, Request};
+
+    #[test]
+    fn test_peer_id_prefix() {
+        assert_eq!(peer_id_prefix("RS", "1.2"), "-RS1200-");
+        assert_eq!(peer_id_prefix("RS", "1.2.3.4.5"), "-RS1234-");
+        assert_eq!(peer_id_prefix("RS", "1.x.40"), "-RS1000-");
+        // 客户端代码总是两个字符, 前缀总是 8 字节
+        assert_eq!(peer_id_prefix("R", "1"), "-R-1000-");
+        assert_eq!(peer_id_prefix("", "1"), "---1000-");
+        assert_eq!(peer_id_prefix("RSX", "1"), "-RS1000-");
+        assert_eq!(peer_id_prefix("中文", "1"), "---1000-");
+    }
+
+    #[test]
+    fn test_generate_peer_id() {
+        let peer_id = generate_peer_id("-RS0100-");
+        assert_eq!(&peer_id[..8], b"-RS0100-");
+        assert!(peer_id[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_ne!(peer_id, generate_peer_id("-RS0100-"));
+
+        let long = generate_peer_id("abcdefghijklmnopqrstuvwxyz");
+        assert_eq!(&long, b"abcdefghijklmnopqrst");
+    }
+
+    #[test]
+    fn test_session() {
+        let session = Session::new("-RS0100-", 51413);
+        assert_eq!(session.key().len(), 8);
+        assert!(u32::from_str_radix(session.key(), 16).is_ok());
+        assert_eq!(&session.peer_id()[..8], b"-RS0100-");
+        // 每个 session 的 peer id 和 key 都不一样
+        assert_ne!(session, Session::new("-RS0100-", 51413));
+    }
+
+    #[test]
+    fn test_request_for_torrent() {
+        let torrent = TorrentFile::parse(include_bytes!(
+            "../../tests/tests/files/debian-11.3.0-amd64-netinst.iso.torrent"
+        ))
+        .unwrap();
+        let session = Session::new("-RS0100-", 51413);
+        let stats = TransferStats {
+            uploaded: 10,
+            downloaded: 2000,
+            verified: 1728,
+        };
+        let req = Request::for_torrent(&torrent, &session, stats).unwrap();
+        assert_eq!(req.info_hash, torrent.info.info_hash().unwrap());
+        assert_eq!(&req.peer_id, session.peer_id());
+        assert_eq!(req.key.as_deref(), Some(session.key()));
+        assert_eq!(req.port, 51413);
+        assert_eq!((req.uploaded, req.downloaded), (10, 2000));
+        assert_eq!(req.left, 396361728 - 1728);
+        assert_eq!(req.compact, 1);
+        assert_eq!(req.event, None);
+
+        let mut again = Request::for_torrent(&torrent, &session, TransferStats::default()).unwrap();
+        again.event = Some(Event::Started);
+        assert_eq!(again.peer_id, req.peer_id);
+        assert_eq!(again.key, req.key);
+        assert_eq!(again.left, 396361728);
+        assert_eq!(again.event, Some(Event::Started));
+    }
+}
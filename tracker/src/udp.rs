@@ -0,0 +1,542 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bytes::{Buf, BufMut};
+use reqwest::Url;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Instant};
+use torrent::InfoHash;
+
+use crate::error::TrackerError;
+use crate::{peers_v4, peers_v6, Event, Request, Response, ScrapeFile};
+
+// UDP tracker 协议 (BEP 15): http://bittorrent.org/beps/bep_0015.html
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// A connection id may be used for one minute after it was received.
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+/// Trackers accept at most about 74 info hashes in one scrape packet.
+const MAX_SCRAPE_HASHES: usize = 74;
+/// Responses are never larger than this, even with 200 IPv6 peers.
+const MAX_PACKET_LEN: usize = 4096;
+/// The timeout stops doubling after this many retransmissions.
+const MAX_BACKOFF: u32 = 8;
+
+/// Client for `udp://` trackers.
+///
+/// Requests are retransmitted when no response arrives within `15 * 2 ^ n`
+/// seconds, `n` counting up to 8, after which the request fails with
+/// [`TrackerError::Timeout`].
+pub struct UdpTracker {
+    host: Option<String>,
+    port: Option<u16>,
+    timeout: Duration,
+    max_retransmissions: u32,
+    bind_address: Option<IpAddr>,
+    // 缓存 connection id 和获取它的时间
+    connection: Mutex<Option<(u64, Instant)>>,
+}
+
+impl UdpTracker {
+    pub fn new(url: &Url) -> Self {
+        Self {
+            host: url.host_str().map(|host| host.to_owned()),
+            port: url.port(),
+            timeout: Duration::from_secs(15),
+            max_retransmissions: 8,
+            bind_address: None,
+            connection: Mutex::new(None),
+        }
+    }
+
+    /// Sets the base timeout `n = 0` starts from, `15` seconds by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many times a request is retransmitted before giving up, `8` by default.
+    /// Retransmissions past the 8th wait `15 * 2 ^ 8` seconds each.
+    pub fn with_max_retransmissions(mut self, max_retransmissions: u32) -> Self {
+        self.max_retransmissions = max_retransmissions;
+        self
+    }
+
+    /// Sends requests from this local address when the tracker resolves to the same IP version.
+    pub fn with_bind_address(mut self, addr: IpAddr) -> Self {
+        self.bind_address = Some(addr);
+        self
+    }
+
+    pub async fn announce(&self, req: &Request) -> Result<Response, TrackerError> {
+        let socket = self.socket().await?;
+        let mut packet = Vec::with_capacity(98);
+        packet.put_slice(&req.info_hash.truncated());
+        packet.put_slice(&req.peer_id);
+        packet.put_u64(req.downloaded as u64);
+        packet.put_u64(req.left.max(0) as u64);
+        packet.put_u64(req.uploaded as u64);
+        packet.put_u32(match req.event {
+            None => 0,
+            Some(Event::Completed) => 1,
+            Some(Event::Started) => 2,
+            Some(Event::Stopped) => 3,
+        });
+        packet.put_u32(match req.ip {
+            Some(IpAddr::V4(ip)) => u32::from(ip),
+            _ => 0,
+        });
+        packet.put_u32(
+            req.key
+                .as_deref()
+                .and_then(|key| u32::from_str_radix(key, 16).ok())
+                .unwrap_or(0),
+        );
+        packet.put_i32(req.numwant.map(|n| n as i32).unwrap_or(-1));
+        packet.put_u16(req.port as u16);
+
+        let mut resp = &self.request(&socket, ACTION_ANNOUNCE, &packet).await?[..];
+        if resp.len() < 12 {
+            return Err(TrackerError::ParseResponseError(Cow::Borrowed(
+                "announce response is shorter than 12 bytes after the header",
+            )));
+        }
+        let interval = resp.get_u32();
+        let leechers = resp.get_u32();
+        let seeders = resp.get_u32();
+        // 通过 IPv6 发送的 announce, tracker 返回 18 字节一个的 IPv6 peer
+        let peers = if socket.local_addr()?.is_ipv6() {
+            peers_v6(resp)?
+        } else {
+            peers_v4(resp)?
+        };
+
+        Ok(Response {
+            warning_message: None,
+            interval: Duration::from_secs(interval as u64),
+            min_interval: None,
+            tracker_id: None,
+            complete: Some(seeders as i64),
+            incomplete: Some(leechers as i64),
+            peers,
+        })
+    }
+
+    pub async fn scrape(
+        &self,
+        info_hashes: &[InfoHash],
+    ) -> Result<HashMap<InfoHash, ScrapeFile>, TrackerError> {
+        let socket = self.socket().await?;
+        let mut files = HashMap::with_capacity(info_hashes.len());
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let mut packet = Vec::with_capacity(chunk.len() * 20);
+            for info_hash in chunk {
+                packet.put_slice(&info_hash.truncated());
+            }
+            let mut resp = &self.request(&socket, ACTION_SCRAPE, &packet).await?[..];
+            if resp.len() < chunk.len() * 12 {
+                return Err(TrackerError::ParseResponseError(Cow::Borrowed(
+                    "scrape response does not cover every info hash",
+                )));
+            }
+            for info_hash in chunk {
+                let complete = resp.get_u32() as i64;
+                let downloaded = resp.get_u32() as i64;
+                let incomplete = resp.get_u32() as i64;
+                files.insert(
+                    *info_hash,
+                    ScrapeFile {
+                        complete,
+                        downloaded,
+                        incomplete,
+                        name: None,
+                    },
+                );
+            }
+        }
+        Ok(files)
+    }
+
+    async fn socket(&self) -> Result<UdpSocket, TrackerError> {
+        let host = self
+            .host
+            .as_deref()
+            .ok_or(TrackerError::InvalidUrl(Cow::Borrowed(
+                "udp tracker url has no host",
+            )))?;
+        let port = self.port.ok_or(TrackerError::InvalidUrl(Cow::Borrowed(
+            "udp tracker url has no port",
+        )))?;
+        // Url 里的 IPv6 地址带着方括号
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addr =
+            tokio::net::lookup_host((host, port))
+                .await?
+                .next()
+                .ok_or(TrackerError::InvalidUrl(Cow::Borrowed(
+                    "udp tracker host does not resolve",
+                )))?;
+        let local: SocketAddr = match self.bind_address {
+            Some(bind) if bind.is_ipv6() == addr.is_ipv6() => (bind, 0).into(),
+            _ if addr.is_ipv6() => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            _ => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+        Ok(socket)
+    }
+
+    // 发送请求并等待响应, 超时按 15 * 2 ^ n 秒重传, 返回去掉 action 和 transaction id 的响应
+    async fn request(
+        &self,
+        socket: &UdpSocket,
+        action: u32,
+        body: &[u8],
+    ) -> Result<Vec<u8>, TrackerError> {
+        for n in 0..=self.max_retransmissions {
+            // 重传期间 connection id 可能过期, 每次都重新取
+            let connection_id = match self.connection_id() {
+                Some(id) => id,
+                None => match self.connect(socket, n).await? {
+                    Some(id) => id,
+                    None => continue,
+                },
+            };
+            let mut packet = Vec::with_capacity(16 + body.len());
+            packet.put_u64(connection_id);
+            packet.put_u32(action);
+            let transaction_id = rand::random::<u32>();
+            packet.put_u32(transaction_id);
+            packet.put_slice(body);
+            if let Some(resp) = self
+                .exchange(socket, &packet, action, transaction_id, n)
+                .await?
+            {
+                return Ok(resp);
+            }
+        }
+        Err(TrackerError::Timeout)
+    }
+
+    async fn connect(&self, socket: &UdpSocket, n: u32) -> Result<Option<u64>, TrackerError> {
+        let mut packet = Vec::with_capacity(16);
+        packet.put_u64(PROTOCOL_ID);
+        packet.put_u32(ACTION_CONNECT);
+        let transaction_id = rand::random::<u32>();
+        packet.put_u32(transaction_id);
+        match self
+            .exchange(socket, &packet, ACTION_CONNECT, transaction_id, n)
+            .await?
+        {
+            Some(resp) if resp.len() >= 8 => {
+                let connection_id = (&resp[..]).get_u64();
+                *self.connection.lock().unwrap() = Some((connection_id, Instant::now()));
+                Ok(Some(connection_id))
+            }
+            Some(_) => Err(TrackerError::ParseResponseError(Cow::Borrowed(
+                "connect response is shorter than 8 bytes after the header",
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    fn connection_id(&self) -> Option<u64> {
+        let mut connection = self.connection.lock().unwrap();
+        match *connection {
+            Some((id, at)) if at.elapsed() < CONNECTION_ID_TTL => Some(id),
+            _ => {
+                *connection = None;
+                None
+            }
+        }
+    }
+
+    fn retransmit_timeout(&self, n: u32) -> Duration {
+        self.timeout * 2u32.pow(n.min(MAX_BACKOFF))
+    }
+
+    // 发送一次, 在第 n 次的超时时间内等待 transaction id 匹配的响应, 超时返回 None
+    async fn exchange(
+        &self,
+        socket: &UdpSocket,
+        packet: &[u8],
+        action: u32,
+        transaction_id: u32,
+        n: u32,
+    ) -> Result<Option<Vec<u8>>, TrackerError> {
+        socket.send(packet).await?;
+        let deadline = Instant::now() + self.retransmit_timeout(n);
+        let mut buf = vec![0; MAX_PACKET_LEN];
+        loop {
+            let len = match time::timeout_at(deadline, socket.recv(&mut buf)).await {
+                Ok(len) => len?,
+                Err(_) => return Ok(None),
+            };
+            if len < 8 {
+                continue;
+            }
+            let mut resp = &buf[..len];
+            let resp_action = resp.get_u32();
+            if resp.get_u32() != transaction_id {
+                // 之前重传的请求迟到的响应
+                continue;
+            }
+            return match resp_action {
+                ACTION_ERROR => Err(TrackerError::Failure(
+                    String::from_utf8_lossy(resp).into_owned(),
+                )),
+                resp_action if resp_action == action => Ok(Some(resp.to_vec())),
+                _ => Err(TrackerError::InvalidResponse),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    const CONNECTION_ID: u64 = 0x1234_5678_9abc_def0;
+
+    #[derive(Default)]
+    pub(crate) struct Counters {
+        connects: AtomicUsize,
+        announces: AtomicUsize,
+    }
+
+    // 本地的 UDP tracker 替身, drop 表示丢掉前几个 announce 请求
+    pub(crate) async fn stand_in(
+        bind: &str,
+        drop: usize,
+        error: Option<&'static str>,
+    ) -> (Url, Arc<Counters>) {
+        let socket = UdpSocket::bind(bind).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let counters = Arc::new(Counters::default());
+        let server_counters = counters.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; MAX_PACKET_LEN];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let mut req = &buf[..len];
+                let connection_id = req.get_u64();
+                let action = req.get_u32();
+                let transaction_id = req.get_u32();
+                let mut resp = Vec::new();
+                match action {
+                    ACTION_CONNECT => {
+                        assert_eq!(connection_id, PROTOCOL_ID);
+                        server_counters.connects.fetch_add(1, Ordering::SeqCst);
+                        resp.put_u32(ACTION_CONNECT);
+                        resp.put_u32(transaction_id);
+                        resp.put_u64(CONNECTION_ID);
+                    }
+                    _ if error.is_some() => {
+                        resp.put_u32(ACTION_ERROR);
+                        resp.put_u32(transaction_id);
+                        resp.put_slice(error.unwrap().as_bytes());
+                    }
+                    ACTION_ANNOUNCE => {
+                        assert_eq!(connection_id, CONNECTION_ID);
+                        assert_eq!(req.len(), 82);
+                        let n = server_counters.announces.fetch_add(1, Ordering::SeqCst);
+                        if n < drop {
+                            continue;
+                        }
+                        let info_hash = &req[..20];
+                        assert_eq!(info_hash, b"abcdefghij1234567890");
+                        req.advance(40);
+                        assert_eq!(req.get_u64(), 10); // downloaded
+                        assert_eq!(req.get_u64(), 20); // left
+                        assert_eq!(req.get_u64(), 30); // uploaded
+                        assert_eq!(req.get_u32(), 2); // started
+                        req.advance(4);
+                        assert_eq!(req.get_u32(), 0xdead_beef); // key
+                        assert_eq!(req.get_i32(), -1); // numwant
+                        assert_eq!(req.get_u16(), 6881);
+
+                        resp.put_u32(ACTION_ANNOUNCE);
+                        resp.put_u32(transaction_id);
+                        resp.put_u32(1800);
+                        resp.put_u32(3);
+                        resp.put_u32(5);
+                        if from.is_ipv6() {
+                            resp.put_u128(u128::from("2001:db8::1".parse::<Ipv6Addr>().unwrap()));
+                            resp.put_u16(51413);
+                        } else {
+                            resp.put_slice(&[2, 156, 201, 254]);
+                            resp.put_u16(49123);
+                            resp.put_slice(&[10, 0, 0, 1]);
+                            resp.put_u16(6881);
+                        }
+                    }
+                    ACTION_SCRAPE => {
+                        resp.put_u32(ACTION_SCRAPE);
+                        resp.put_u32(transaction_id);
+                        for i in 0..req.len() / 20 {
+                            resp.put_u32(i as u32 + 1);
+                            resp.put_u32(i as u32 + 2);
+                            resp.put_u32(i as u32 + 3);
+                        }
+                    }
+                    _ => panic!("unexpected action {}", action),
+                }
+                socket.send_to(&resp, from).await.unwrap();
+            }
+        });
+        let url = match addr {
+            SocketAddr::V4(_) => format!("udp://{}/announce", addr),
+            SocketAddr::V6(_) => format!("udp://[{}]:{}/announce", addr.ip(), addr.port()),
+        };
+        (url.parse().unwrap(), counters)
+    }
+
+    pub(crate) fn request() -> Request {
+        let mut info_hash = [0; 20];
+        info_hash.copy_from_slice(b"abcdefghij1234567890");
+        Request {
+            info_hash: InfoHash::V1(info_hash),
+            peer_id: *b"cbt-2020-03-03-00000",
+            port: 6881,
+            uploaded: 30,
+            downloaded: 10,
+            left: 20,
+            compact: 1,
+            no_peer_id: None,
+            event: Some(Event::Started),
+            ip: None,
+            ipv4: None,
+            ipv6: None,
+            numwant: None,
+            key: Some("deadbeef".to_owned()),
+            tracker_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_announce() {
+        let (url, counters) = stand_in("127.0.0.1:0", 0, None).await;
+        let tracker = UdpTracker::new(&url);
+        let resp = tracker.announce(&request()).await.unwrap();
+        assert_eq!(resp.interval, Duration::from_secs(1800));
+        assert_eq!(resp.incomplete, Some(3));
+        assert_eq!(resp.complete, Some(5));
+        assert_eq!(
+            resp.peers,
+            vec![
+                "2.156.201.254:49123".parse::<SocketAddr>().unwrap().into(),
+                "10.0.0.1:6881".parse::<SocketAddr>().unwrap().into()
+            ]
+        );
+
+        // 第二次 announce 复用缓存的 connection id
+        tracker.announce(&request()).await.unwrap();
+        assert_eq!(counters.connects.load(Ordering::SeqCst), 1);
+        assert_eq!(counters.announces.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_find_peers() {
+        let (url, _) = stand_in("127.0.0.1:0", 0, None).await;
+        let resp = crate::Tracker::new(url)
+            .find_peers(request())
+            .await
+            .unwrap();
+        assert_eq!(resp.peers.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_expired_connection_id() {
+        let (url, counters) = stand_in("127.0.0.1:0", 0, None).await;
+        let tracker = UdpTracker::new(&url);
+        tracker.announce(&request()).await.unwrap();
+        *tracker.connection.lock().unwrap() =
+            Some((CONNECTION_ID, Instant::now() - CONNECTION_ID_TTL));
+        tracker.announce(&request()).await.unwrap();
+        assert_eq!(counters.connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retransmission() {
+        let (url, counters) = stand_in("127.0.0.1:0", 2, None).await;
+        let tracker = UdpTracker::new(&url).with_timeout(Duration::from_millis(20));
+        let resp = tracker.announce(&request()).await.unwrap();
+        assert_eq!(resp.peers.len(), 2);
+        assert_eq!(counters.announces.load(Ordering::SeqCst), 3);
+
+        let (url, _) = stand_in("127.0.0.1:0", usize::MAX, None).await;
+        let tracker = UdpTracker::new(&url)
+            .with_timeout(Duration::from_millis(5))
+            .with_max_retransmissions(2);
+        assert!(matches!(
+            tracker.announce(&request()).await,
+            Err(TrackerError::Timeout)
+        ));
+
+        let tracker = UdpTracker::new(&url).with_max_retransmissions(40);
+        assert_eq!(tracker.retransmit_timeout(0), Duration::from_secs(15));
+        assert_eq!(
+            tracker.retransmit_timeout(40),
+            Duration::from_secs(15 * 256)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_action() {
+        let (url, _) = stand_in("127.0.0.1:0", 0, Some("unregistered torrent")).await;
+        let tracker = UdpTracker::new(&url);
+        match tracker.announce(&request()).await {
+            Err(TrackerError::Failure(reason)) => assert_eq!(reason, "unregistered torrent"),
+            other => panic!("expect failure, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_announce() {
+        let (url, _) = stand_in("[::1]:0", 0, None).await;
+        let resp = UdpTracker::new(&url).announce(&request()).await.unwrap();
+        assert_eq!(
+            resp.peers,
+            vec!["[2001:db8::1]:51413".parse::<SocketAddr>().unwrap().into()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scrape() {
+        let (url, _) = stand_in("127.0.0.1:0", 0, None).await;
+        let hashes: Vec<InfoHash> = (0..100u8).map(|i| InfoHash::V1([i; 20])).collect();
+        let files = UdpTracker::new(&url).scrape(&hashes).await.unwrap();
+        assert_eq!(files.len(), 100);
+        assert_eq!(
+            files[&InfoHash::V1([1; 20])],
+            ScrapeFile {
+                complete: 2,
+                downloaded: 3,
+                incomplete: 4,
+                name: None,
+            }
+        );
+        // 第二个包里的第一个 hash
+        assert_eq!(files[&InfoHash::V1([74; 20])].complete, 1);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_url() {
+        let tracker = UdpTracker::new(&"udp://tracker.example.org/announce".parse().unwrap());
+        assert!(matches!(
+            tracker.announce(&request()).await,
+            Err(TrackerError::InvalidUrl(_))
+        ));
+    }
+}